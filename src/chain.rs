@@ -0,0 +1,213 @@
+// File: cpi_hyperv/src/chain.rs
+//! Differencing disk chains.
+//!
+//! A differencing disk records writes on top of its parent, which may itself
+//! be a differencing disk, down to a fixed or dynamic root. Linked clones
+//! build such chains; `get_volume_chain` reports them and `merge_volume`
+//! collapses part of one with `Merge-VHD`.
+//!
+//! Merging a child into an ancestor deletes the child and every link between
+//! them, and rewrites the ancestor. Any other disk whose parent is one of
+//! those files would be left broken, so the merge is planned here first.
+
+use serde_json::Value;
+
+// Walks ParentPath from a disk to the root of its chain, leaf first
+pub const VHD_CHAIN: &str = "function Get-VhdChain($path) { \
+     $links = @(); $seen = @{}; \
+     while ($path) { \
+       $d = Get-VHD -Path $path; \
+       if ($seen.ContainsKey($d.Path)) { throw ('Disk chain loops back to ' + $d.Path) }; \
+       $seen[$d.Path] = $true; \
+       $links += [PSCustomObject]@{ \
+         path = $d.Path; \
+         format = $d.VhdFormat.ToString(); \
+         type = $d.VhdType.ToString(); \
+         size_bytes = $d.Size; \
+         file_size_bytes = $d.FileSize; \
+         fragmentation_percentage = $d.FragmentationPercentage; \
+         attached = $d.Attached; \
+         parent_path = $(if ($d.ParentPath) { $d.ParentPath } else { $null }) \
+       }; \
+       $path = $d.ParentPath \
+     }; \
+     $links \
+   };";
+
+// Lists the differencing disks in the given directories and attached to any VM
+pub const VHD_CHILDREN: &str = "function Get-VhdChildren($dirs) { \
+     $paths = @($dirs | ForEach-Object { \
+       Get-ChildItem -LiteralPath $_ -File -ErrorAction SilentlyContinue | \
+         Where-Object { $_.Extension -in '.vhd', '.vhdx', '.avhd', '.avhdx' } | \
+         ForEach-Object { $_.FullName } \
+     }) + @(Get-VM | Get-VMHardDiskDrive | ForEach-Object { $_.Path }); \
+     @($paths | Where-Object { $_ } | Sort-Object -Unique | ForEach-Object { \
+       Get-VHD -Path $_ -ErrorAction SilentlyContinue \
+     } | Where-Object { $_.ParentPath } | ForEach-Object { \
+       [PSCustomObject]@{ path = $_.Path; parent_path = $_.ParentPath } \
+     }) \
+   };";
+
+/// One disk of a chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainLink {
+    pub path: String,
+    pub parent_path: Option<String>,
+    pub attached: bool,
+}
+
+/// A disk that names another as its parent
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChildDisk {
+    pub path: String,
+    pub parent_path: String,
+}
+
+/// What `merge_volume` will do
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergePlan {
+    /// Ancestor that receives the merged data
+    pub destination: String,
+    /// Disks deleted by the merge, leaf first
+    pub removed: Vec<String>,
+}
+
+/// Parse the links written by `Get-VhdChain`, leaf first
+pub fn parse_links(value: &Value) -> Result<Vec<ChainLink>, String> {
+    let links = as_list(value).iter().map(|link| {
+        let path = link["path"].as_str()
+            .ok_or_else(|| "Disk chain link has no path".to_string())?;
+        Ok(ChainLink {
+            path: path.to_string(),
+            parent_path: link["parent_path"].as_str().filter(|p| !p.is_empty()).map(|p| p.to_string()),
+            attached: link["attached"].as_bool().unwrap_or(false),
+        })
+    }).collect::<Result<Vec<_>, String>>()?;
+
+    if links.is_empty() {
+        return Err("Disk chain is empty".to_string());
+    }
+    Ok(links)
+}
+
+/// Parse the disks written by `Get-VhdChildren`
+pub fn parse_children(value: &Value) -> Vec<ChildDisk> {
+    as_list(value).iter().filter_map(|child| Some(ChildDisk {
+        path: child["path"].as_str()?.to_string(),
+        parent_path: child["parent_path"].as_str()?.to_string(),
+    })).collect()
+}
+
+/// Check a merge of the chain's leaf into `destination` (its parent when `None`)
+pub fn plan_merge(links: &[ChainLink], destination: Option<&str>, children: &[ChildDisk]) -> Result<MergePlan, String> {
+    let leaf = &links[0];
+    if links.len() < 2 {
+        return Err(format!("Disk '{}' is not a differencing disk", leaf.path));
+    }
+
+    let target = match destination {
+        Some(destination) => links[1..].iter()
+            .position(|link| same_path(&link.path, destination))
+            .map(|index| index + 1)
+            .ok_or_else(|| format!("Disk '{}' is not an ancestor of '{}'", destination, leaf.path))?,
+        None => 1,
+    };
+
+    // Hyper-V can only merge offline disks
+    if let Some(link) = links[..=target].iter().find(|link| link.attached) {
+        return Err(format!("Disk '{}' is in use; detach it or stop its VM before merging", link.path));
+    }
+
+    // Every disk from the leaf to the destination is deleted or rewritten
+    for child in children {
+        if links.iter().any(|link| same_path(&link.path, &child.path)) {
+            continue;
+        }
+        if let Some(link) = links[..=target].iter().find(|link| same_path(&link.path, &child.parent_path)) {
+            return Err(format!(
+                "Disk '{}' depends on '{}', which the merge would {}",
+                child.path, link.path,
+                if same_path(&link.path, &links[target].path) { "change" } else { "delete" }
+            ));
+        }
+    }
+
+    Ok(MergePlan {
+        destination: links[target].path.clone(),
+        removed: links[..target].iter().map(|link| link.path.clone()).collect(),
+    })
+}
+
+// ConvertTo-Json writes a lone object rather than a one-element array
+fn as_list(value: &Value) -> Vec<Value> {
+    match value {
+        Value::Array(items) => items.clone(),
+        Value::Null => Vec::new(),
+        item => vec![item.clone()],
+    }
+}
+
+// Windows paths are case-insensitive
+fn same_path(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chain() -> Vec<ChainLink> {
+        parse_links(&json!([
+            { "path": "D:\\vms\\web-3.avhdx", "parent_path": "D:\\vms\\web-2.vhdx", "attached": false },
+            { "path": "D:\\vms\\web-2.vhdx", "parent_path": "D:\\vms\\web-1.vhdx", "attached": false },
+            { "path": "D:\\vms\\web-1.vhdx", "parent_path": "D:\\base\\ubuntu.vhdx", "attached": false },
+            { "path": "D:\\base\\ubuntu.vhdx", "parent_path": null, "attached": false }
+        ])).unwrap()
+    }
+
+    fn child(path: &str, parent_path: &str) -> ChildDisk {
+        ChildDisk { path: path.to_string(), parent_path: parent_path.to_string() }
+    }
+
+    #[test]
+    fn plans_merges_into_ancestors() {
+        let links = chain();
+        assert_eq!(links[3].parent_path, None);
+
+        let plan = plan_merge(&links, None, &[]).unwrap();
+        assert_eq!(plan.destination, "D:\\vms\\web-2.vhdx");
+        assert_eq!(plan.removed, vec!["D:\\vms\\web-3.avhdx"]);
+
+        // The chain's own links and children of untouched ancestors do not block a merge
+        let children = parse_children(&json!([
+            { "path": "D:\\vms\\web-3.avhdx", "parent_path": "D:\\vms\\web-2.vhdx" },
+            { "path": "D:\\vms\\db-1.vhdx", "parent_path": "D:\\base\\ubuntu.vhdx" }
+        ]));
+        let plan = plan_merge(&links, Some("d:\\VMS\\web-1.vhdx"), &children).unwrap();
+        assert_eq!(plan.destination, "D:\\vms\\web-1.vhdx");
+        assert_eq!(plan.removed, vec!["D:\\vms\\web-3.avhdx", "D:\\vms\\web-2.vhdx"]);
+    }
+
+    #[test]
+    fn rejects_unsafe_merges() {
+        let links = chain();
+
+        // Not an ancestor, or nothing to merge into
+        assert!(plan_merge(&links, Some("D:\\vms\\web-3.avhdx"), &[]).is_err());
+        assert!(plan_merge(&links, Some("D:\\other.vhdx"), &[]).is_err());
+        assert!(plan_merge(&links[3..], None, &[]).is_err());
+
+        // Another clone depends on an intermediate link or on the destination
+        let sibling = [child("D:\\vms\\web-2b.avhdx", "D:\\vms\\web-2.vhdx")];
+        let err = plan_merge(&links, Some("D:\\vms\\web-1.vhdx"), &sibling).unwrap_err();
+        assert!(err.contains("would delete"), "{}", err);
+        let err = plan_merge(&links, None, &sibling).unwrap_err();
+        assert!(err.contains("would change"), "{}", err);
+
+        let mut attached = links.clone();
+        attached[1].attached = true;
+        assert!(plan_merge(&attached, None, &[]).is_err());
+        assert!(plan_merge(&attached[2..], None, &[]).is_ok());
+    }
+}
@@ -0,0 +1,62 @@
+// File: cpi_hyperv/src/cloud_init.rs
+//! cloud-init NoCloud seed media.
+//!
+//! cloud-init's NoCloud datasource picks up `user-data`, `meta-data` and
+//! `network-config` from any filesystem labelled `cidata`, so a worker can be
+//! configured on first boot by attaching the generated image as a DVD.
+
+use crate::iso::IsoImage;
+
+/// Contents of a NoCloud seed image
+#[derive(Debug, Clone, Default)]
+pub struct NoCloudSeed {
+    pub user_data: Option<String>,
+    pub meta_data: Option<String>,
+    pub network_config: Option<String>,
+}
+
+impl NoCloudSeed {
+    pub fn is_empty(&self) -> bool {
+        self.user_data.is_none() && self.meta_data.is_none() && self.network_config.is_none()
+    }
+
+    /// Build the `cidata` ISO. Without explicit meta-data, one is generated from
+    /// the VM ID and name so cloud-init treats every new worker as a new instance.
+    pub fn to_iso(&self, instance_id: &str, hostname: &str) -> Result<Vec<u8>, String> {
+        let meta_data = self.meta_data.clone().unwrap_or_else(|| {
+            format!("instance-id: {}\nlocal-hostname: {}\n", instance_id, hostname)
+        });
+
+        let mut iso = IsoImage::new("cidata");
+        iso.add_file("meta-data", meta_data)?;
+        // NoCloud expects a user-data file even when it is empty
+        iso.add_file("user-data", self.user_data.clone().unwrap_or_default())?;
+        if let Some(network_config) = &self.network_config {
+            iso.add_file("network-config", network_config.clone())?;
+        }
+        iso.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_meta_data_and_empty_user_data() {
+        let seed = NoCloudSeed {
+            network_config: Some("version: 2\n".to_string()),
+            ..Default::default()
+        };
+        let image = seed.to_iso("1234", "worker-1").unwrap();
+        let text = String::from_utf8_lossy(&image);
+
+        assert!(text.contains("instance-id: 1234\nlocal-hostname: worker-1\n"));
+        assert!(text.contains("version: 2\n"));
+        // The Joliet directory lists every seed file by its exact name
+        for name in ["meta-data", "network-config", "user-data"] {
+            let encoded: Vec<u8> = name.encode_utf16().flat_map(|c| c.to_be_bytes()).collect();
+            assert!(image.windows(encoded.len()).any(|w| w == encoded.as_slice()), "missing {}", name);
+        }
+    }
+}
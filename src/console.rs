@@ -0,0 +1,161 @@
+// File: cpi_hyperv/src/console.rs
+//! Persisted serial console output.
+//!
+//! Output read from a worker's COM port pipe is appended to a log that acts
+//! as a bounded ring buffer: when the active file grows past its size limit
+//! it is rotated (`com1.log` becomes `com1.log.1`, and so on) and the oldest
+//! file is dropped, so each worker keeps at most `max_files` files of output.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Default size at which the active log file is rotated
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+/// Default number of files kept, including the active one
+pub const DEFAULT_MAX_FILES: usize = 4;
+
+/// Rotating log of one serial port
+#[derive(Debug, Clone)]
+pub struct ConsoleLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl ConsoleLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        ConsoleLog {
+            path: path.into(),
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_files: DEFAULT_MAX_FILES,
+        }
+    }
+
+    pub fn with_limits(mut self, max_bytes: u64, max_files: usize) -> Self {
+        self.max_bytes = max_bytes.max(1);
+        self.max_files = max_files.max(1);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append output, rotating whenever the active file reaches its limit
+    pub fn append(&self, mut data: &[u8]) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        while !data.is_empty() {
+            let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+            if size >= self.max_bytes {
+                self.rotate()?;
+                continue;
+            }
+
+            let room = (self.max_bytes - size) as usize;
+            let (now, later) = data.split_at(room.min(data.len()));
+            OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(now)?;
+            data = later;
+        }
+        Ok(())
+    }
+
+    /// All retained output, oldest first
+    pub fn read_all(&self) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        for index in (0..self.max_files).rev() {
+            match fs::read(self.file(index)) {
+                Ok(data) => output.extend_from_slice(&data),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(output)
+    }
+
+    /// The last `lines` lines of retained output
+    pub fn tail(&self, lines: usize) -> io::Result<String> {
+        let output = self.read_all()?;
+        let text = String::from_utf8_lossy(&output);
+        let text = text.strip_suffix('\n').unwrap_or(&text);
+
+        let start = text.rmatch_indices('\n')
+            .nth(lines.saturating_sub(1))
+            .map(|(pos, _)| pos + 1)
+            .unwrap_or(0);
+        Ok(if lines == 0 { String::new() } else { text[start..].to_string() })
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        let oldest = self.file(self.max_files - 1);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for index in (0..self.max_files - 1).rev() {
+            let from = self.file(index);
+            if from.exists() {
+                fs::rename(&from, self.file(index + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    // File `index` of the ring; 0 is the active file
+    fn file(&self, index: usize) -> PathBuf {
+        if index == 0 {
+            self.path.clone()
+        } else {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", index));
+            PathBuf::from(name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cpi-console-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn rotates_and_drops_the_oldest_output() {
+        let dir = scratch("rotate");
+        let log = ConsoleLog::new(dir.join("com1.log")).with_limits(10, 3);
+
+        log.append(b"0123456789abcdefghij").unwrap();
+        log.append(b"ABCDEFGHIJ").unwrap();
+        assert_eq!(log.read_all().unwrap(), b"0123456789abcdefghijABCDEFGHIJ");
+
+        // A fourth file's worth pushes the first one out of the ring
+        log.append(b"klm").unwrap();
+        assert_eq!(log.read_all().unwrap(), b"abcdefghijABCDEFGHIJklm");
+        assert_eq!(fs::read(dir.join("com1.log")).unwrap(), b"klm");
+        assert!(!dir.join("com1.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tails_lines_across_files() {
+        let dir = scratch("tail");
+        let log = ConsoleLog::new(dir.join("com1.log")).with_limits(8, 4);
+
+        assert_eq!(log.tail(5).unwrap(), "");
+        log.append(b"boot\nkernel\ninit\nlogin: ").unwrap();
+        assert_eq!(log.tail(2).unwrap(), "init\nlogin: ");
+        assert_eq!(log.tail(10).unwrap(), "boot\nkernel\ninit\nlogin: ");
+        log.append(b"\n").unwrap();
+        assert_eq!(log.tail(1).unwrap(), "login: ");
+        assert_eq!(log.tail(0).unwrap(), "");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
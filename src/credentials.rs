@@ -0,0 +1,53 @@
+// File: cpi_hyperv/src/credentials.rs
+//! Guest credential lookup.
+//!
+//! Credentials are taken from the action parameters first, then from the
+//! extension settings and finally from the environment, so secrets do not
+//! have to be baked into the extension or passed on every call.
+
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Environment variable consulted when no username is configured
+pub const USERNAME_ENV: &str = "CPI_HYPERV_GUEST_USERNAME";
+/// Environment variable consulted when no password is configured
+pub const PASSWORD_ENV: &str = "CPI_HYPERV_GUEST_PASSWORD";
+
+/// Credentials of an account inside a guest
+#[derive(Clone)]
+pub struct GuestCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for GuestCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GuestCredentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+impl GuestCredentials {
+    /// Resolve credentials from `params`, then `settings`, then the environment
+    pub fn resolve(params: &HashMap<String, Value>, settings: &HashMap<String, Value>) -> Result<Self, String> {
+        let lookup = |key: &str, env: &str| -> Option<String> {
+            params.get(key).and_then(|v| v.as_str())
+                .or_else(|| settings.get(key).and_then(|v| v.as_str()))
+                .map(|v| v.to_string())
+                .or_else(|| std::env::var(env).ok())
+                .filter(|v| !v.is_empty())
+        };
+
+        let username = lookup("username", USERNAME_ENV)
+            .unwrap_or_else(|| "Administrator".to_string());
+        let password = lookup("password", PASSWORD_ENV)
+            .ok_or_else(|| format!(
+                "No guest password configured; set the 'password' setting or {}",
+                PASSWORD_ENV
+            ))?;
+
+        Ok(Self { username, password })
+    }
+}
@@ -0,0 +1,143 @@
+// File: cpi_hyperv/src/inventory.rs
+//! PowerShell helpers describing workers.
+//!
+//! `get_worker` and the detailed mode of `list_workers` share these
+//! definitions so both report exactly the same fields for a VM.
+
+use crate::kvp::KvpPools;
+use serde_json::{json, Map, Value};
+
+// Parses the key=value lines `set_worker_metadata` keeps in the VM notes; later lines win
+pub const NOTES_PARSER: &str = "function ConvertFrom-Notes($notes) { \
+     $m = [ordered]@{}; \
+     foreach ($line in ([string]$notes -split '\\r?\\n')) { \
+       $i = $line.IndexOf('='); \
+       if ($i -gt 0) { $m[$line.Substring(0, $i)] = $line.Substring($i + 1) } \
+     }; \
+     $m \
+   };";
+
+// Returns the raw KVP exchange items of a VM: guest intrinsic, guest and host pools
+pub const KVP_ITEMS: &str = "function Get-KvpItems($v) { \
+     $ns = 'root\\virtualization\\v2'; \
+     $cs = Get-CimInstance -Namespace $ns -ClassName Msvm_ComputerSystem -Filter \"Name='$($v.Id)'\"; \
+     $component = $cs | Get-CimAssociatedInstance -ResultClassName Msvm_KvpExchangeComponent; \
+     $settings = $cs | Get-CimAssociatedInstance -ResultClassName Msvm_VirtualSystemSettingData | \
+       Where-Object { $_.VirtualSystemType -eq 'Microsoft:Hyper-V:System:Realized' } | \
+       Get-CimAssociatedInstance -ResultClassName Msvm_KvpExchangeComponentSettingData; \
+     [PSCustomObject]@{ \
+       intrinsic = @($component.GuestIntrinsicExchangeItems | Where-Object { $_ }); \
+       guest = @($component.GuestExchangeItems | Where-Object { $_ }); \
+       host = @($settings.HostExchangeItems | Where-Object { $_ }) \
+     } \
+   };";
+
+// Lists the integration services of a VM with their state
+pub const INTEGRATION_SERVICES: &str = "function ConvertTo-IntegrationServices($v) { \
+     @(Get-VMIntegrationService -VM $v | ForEach-Object { [PSCustomObject]@{ \
+       name = $_.Name; \
+       enabled = $_.Enabled; \
+       primary_status = $_.PrimaryStatusDescription; \
+       secondary_status = $_.SecondaryStatusDescription \
+     } }) \
+   };";
+
+// Describes one VM with the fields get_worker returns
+const WORKER_DETAIL: &str = "function ConvertTo-WorkerDetail($v) { \
+     $adapters = @($v.NetworkAdapters | ForEach-Object { [PSCustomObject]@{ \
+       name = $_.Name; \
+       switch_name = $_.SwitchName; \
+       mac_address = $_.MacAddress; \
+       ip_addresses = @($_.IPAddresses) \
+     } }); \
+     $disks = @(Get-VMHardDiskDrive -VM $v | ForEach-Object { [PSCustomObject]@{ \
+       controller_type = $_.ControllerType.ToString(); \
+       controller_number = $_.ControllerNumber; \
+       controller_location = $_.ControllerLocation; \
+       path = $_.Path \
+     } }); \
+     [PSCustomObject]@{ \
+       name = $v.Name; \
+       id = $v.Id.ToString(); \
+       state = $(if ($v.State.ToString() -eq 'Off') { 'Stopped' } else { $v.State.ToString() }); \
+       memory_mb = [int64]($v.MemoryStartup / 1MB); \
+       cpu_count = $v.ProcessorCount; \
+       generation = $v.Generation; \
+       uptime_seconds = [int64]$v.Uptime.TotalSeconds; \
+       disks = $disks; \
+       network_adapters = $adapters; \
+       ip_addresses = @($adapters | ForEach-Object { $_.ip_addresses }); \
+       metadata = (ConvertFrom-Notes $v.Notes); \
+       checkpoint_count = @(Get-VMSnapshot -VM $v -ErrorAction SilentlyContinue).Count; \
+       automatic_start_action = $v.AutomaticStartAction.ToString(); \
+       automatic_start_delay = $v.AutomaticStartDelay; \
+       automatic_stop_action = $v.AutomaticStopAction.ToString(); \
+       automatic_critical_error_action = $v.AutomaticCriticalErrorAction.ToString(); \
+       integration_services = @(ConvertTo-IntegrationServices $v); \
+       integration_services_version = $(if ($v.IntegrationServicesVersion) { $v.IntegrationServicesVersion.ToString() } else { $null }); \
+       integration_services_state = $v.IntegrationServicesState; \
+       guest_kvp = $(try { Get-KvpItems $v } catch { $null }); \
+       heartbeat = $(if ($null -ne $v.Heartbeat) { $v.Heartbeat.ToString() } else { $null }) \
+     } \
+   };";
+
+/// Definition of `ConvertTo-WorkerDetail`, which also needs `NOTES_PARSER`
+pub fn worker_detail() -> String {
+    format!("{} {} {}", KVP_ITEMS, INTEGRATION_SERVICES, WORKER_DETAIL)
+}
+
+/// Replace the raw `guest_kvp` and `heartbeat` fields of a `ConvertTo-WorkerDetail`
+/// row with a `guest` object. Facts the guest does not report are null.
+pub fn finish_detail(row: &mut Map<String, Value>) {
+    let intrinsic = row.remove("guest_kvp")
+        .and_then(|raw| KvpPools::from_json(&raw).ok())
+        .map(|pools| pools.intrinsic)
+        .unwrap_or_default();
+    let heartbeat = row.remove("heartbeat")
+        .and_then(|h| h.as_str().map(|h| h.to_string()));
+
+    // OkApplicationsHealthy and OkApplicationsUnknown mean the guest answers heartbeats
+    let responsive = heartbeat.as_deref().map(|h| h.starts_with("Ok"));
+    let fact = |name: &str| intrinsic.get(name).cloned();
+
+    row.insert("guest".to_string(), json!({
+        "os_name": fact("OSName"),
+        "os_version": fact("OSVersion"),
+        "hostname": fact("FullyQualifiedDomainName"),
+        "integration_services_version": fact("IntegrationServicesVersion"),
+        "heartbeat": heartbeat,
+        "responsive": responsive
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_guest_facts_with_nulls_for_missing_data() {
+        let item = |name: &str, data: &str| crate::kvp::KvpItem {
+            name: name.to_string(),
+            data: data.to_string(),
+            source: Some(2),
+        }.to_xml();
+        let mut row = json!({
+            "name": "web-1",
+            "guest_kvp": { "intrinsic": [item("OSName", "Ubuntu 22.04.4 LTS"), item("FullyQualifiedDomainName", "web-1")], "guest": [], "host": [] },
+            "heartbeat": "OkApplicationsUnknown"
+        }).as_object().unwrap().clone();
+        finish_detail(&mut row);
+
+        assert!(!row.contains_key("guest_kvp") && !row.contains_key("heartbeat"));
+        assert_eq!(row["guest"]["os_name"], "Ubuntu 22.04.4 LTS");
+        assert_eq!(row["guest"]["hostname"], "web-1");
+        assert!(row["guest"]["os_version"].is_null());
+        assert_eq!(row["guest"]["responsive"], true);
+
+        // A stopped VM or one without integration services reports nothing
+        let mut row = json!({ "name": "web-2", "guest_kvp": null, "heartbeat": null }).as_object().unwrap().clone();
+        finish_detail(&mut row);
+        assert!(row["guest"]["os_name"].is_null());
+        assert!(row["guest"]["responsive"].is_null());
+    }
+}
@@ -0,0 +1,447 @@
+// File: cpi_hyperv/src/iso.rs
+//! Minimal ISO9660 image writer.
+//!
+//! Produces a single-directory image with a primary volume descriptor and a
+//! Joliet supplementary volume descriptor, which is enough for small seed
+//! media such as cloud-init `cidata` disks or unattended-setup answer files.
+//! The Joliet tree preserves lowercase names and characters like `-` that
+//! plain ISO9660 does not allow, and is what Linux and Windows read by default.
+
+const SECTOR_SIZE: usize = 2048;
+const SYSTEM_AREA_SECTORS: usize = 16;
+
+/// A file placed in the root directory of the image
+#[derive(Debug, Clone)]
+pub struct IsoFile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Builder for an ISO9660 image with a flat root directory
+#[derive(Debug, Clone)]
+pub struct IsoImage {
+    volume_id: String,
+    files: Vec<IsoFile>,
+    /// Recording time as seconds since the Unix epoch
+    timestamp: u64,
+}
+
+impl IsoImage {
+    pub fn new(volume_id: &str) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Self {
+            volume_id: volume_id.to_string(),
+            files: Vec::new(),
+            timestamp,
+        }
+    }
+
+    /// Use a fixed recording time instead of the current time
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn add_file(&mut self, name: &str, data: impl Into<Vec<u8>>) -> Result<(), String> {
+        if name.is_empty() || name.len() > 64 || name.contains(['/', '\\']) {
+            return Err(format!("Invalid ISO file name '{}'", name));
+        }
+        if self.files.iter().any(|f| f.name == name) {
+            return Err(format!("Duplicate ISO file name '{}'", name));
+        }
+
+        self.files.push(IsoFile { name: name.to_string(), data: data.into() });
+        Ok(())
+    }
+
+    /// Serialize the image
+    pub fn build(&self) -> Result<Vec<u8>, String> {
+        // Layout: system area, PVD, Joliet SVD, terminator, four path tables,
+        // the two root directories and then the file extents
+        let pvd_sector = SYSTEM_AREA_SECTORS;
+        let svd_sector = pvd_sector + 1;
+        let terminator_sector = svd_sector + 1;
+        let path_table_sectors = [terminator_sector + 1, terminator_sector + 2, terminator_sector + 3, terminator_sector + 4];
+        let primary_root_sector = terminator_sector + 5;
+        let joliet_root_sector = primary_root_sector + 1;
+
+        let mut files: Vec<&IsoFile> = self.files.iter().collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut next_sector = joliet_root_sector + 1;
+        let mut extents = Vec::new();
+        for file in &files {
+            extents.push(next_sector);
+            next_sector += sectors_for(file.data.len()).max(1);
+        }
+        let total_sectors = next_sector;
+
+        let date = RecordingDate::from_unix(self.timestamp);
+        let primary_names: Vec<Vec<u8>> = files.iter().map(|f| primary_name(&f.name)).collect();
+        let joliet_names: Vec<Vec<u8>> = files.iter().map(|f| ucs2(&f.name)).collect();
+
+        let primary_root = directory(primary_root_sector, &files, &extents, &primary_names, &date)?;
+        let joliet_root = directory(joliet_root_sector, &files, &extents, &joliet_names, &date)?;
+
+        let mut image = vec![0u8; total_sectors * SECTOR_SIZE];
+
+        let path_table_size = 10;
+        let descriptor = |joliet: bool, root_sector: usize, l_table: usize, m_table: usize| {
+            volume_descriptor(VolumeDescriptor {
+                joliet,
+                volume_id: &self.volume_id,
+                total_sectors,
+                path_table_size,
+                l_path_table: l_table,
+                m_path_table: m_table,
+                root_sector,
+                date: &date,
+                timestamp: self.timestamp,
+            })
+        };
+
+        write_sector(&mut image, pvd_sector, &descriptor(false, primary_root_sector, path_table_sectors[0], path_table_sectors[1]));
+        write_sector(&mut image, svd_sector, &descriptor(true, joliet_root_sector, path_table_sectors[2], path_table_sectors[3]));
+
+        let mut terminator = vec![0u8; SECTOR_SIZE];
+        terminator[0] = 255;
+        terminator[1..6].copy_from_slice(b"CD001");
+        terminator[6] = 1;
+        write_sector(&mut image, terminator_sector, &terminator);
+
+        write_sector(&mut image, path_table_sectors[0], &path_table(primary_root_sector, false));
+        write_sector(&mut image, path_table_sectors[1], &path_table(primary_root_sector, true));
+        write_sector(&mut image, path_table_sectors[2], &path_table(joliet_root_sector, false));
+        write_sector(&mut image, path_table_sectors[3], &path_table(joliet_root_sector, true));
+
+        write_sector(&mut image, primary_root_sector, &primary_root);
+        write_sector(&mut image, joliet_root_sector, &joliet_root);
+
+        for (file, extent) in files.iter().zip(&extents) {
+            let offset = extent * SECTOR_SIZE;
+            image[offset..offset + file.data.len()].copy_from_slice(&file.data);
+        }
+
+        Ok(image)
+    }
+}
+
+fn sectors_for(len: usize) -> usize {
+    len.div_ceil(SECTOR_SIZE)
+}
+
+fn write_sector(image: &mut [u8], sector: usize, data: &[u8]) {
+    let offset = sector * SECTOR_SIZE;
+    image[offset..offset + data.len()].copy_from_slice(data);
+}
+
+fn both_u16(value: u16) -> [u8; 4] {
+    let le = value.to_le_bytes();
+    let be = value.to_be_bytes();
+    [le[0], le[1], be[0], be[1]]
+}
+
+fn both_u32(value: u32) -> [u8; 8] {
+    let le = value.to_le_bytes();
+    let be = value.to_be_bytes();
+    [le[0], le[1], le[2], le[3], be[0], be[1], be[2], be[3]]
+}
+
+fn ucs2(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(|c| c.to_be_bytes()).collect()
+}
+
+/// Map a file name onto ISO9660 d-characters, e.g. `user-data` becomes `USER_DATA.;1`
+fn primary_name(name: &str) -> Vec<u8> {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, extension),
+        _ => (name, ""),
+    };
+    let clean = |part: &str, max: usize| -> String {
+        part.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .take(max)
+            .collect()
+    };
+    format!("{}.{};1", clean(stem, 30 - extension.len().min(8)), clean(extension, 8)).into_bytes()
+}
+
+/// Fill a text field, padding with spaces in the field's encoding
+fn text_field(field: &mut [u8], text: &str, joliet: bool) {
+    if joliet {
+        for pair in field.chunks_mut(2) {
+            pair.copy_from_slice(&[0x00, 0x20][..pair.len()]);
+        }
+        let encoded = ucs2(text);
+        let len = encoded.len().min(field.len() & !1);
+        field[..len].copy_from_slice(&encoded[..len]);
+    } else {
+        field.fill(b' ');
+        let len = text.len().min(field.len());
+        field[..len].copy_from_slice(&text.as_bytes()[..len]);
+    }
+}
+
+/// Seven-byte directory record date
+struct RecordingDate([u8; 7]);
+
+impl RecordingDate {
+    fn from_unix(timestamp: u64) -> Self {
+        let (year, month, day, hour, minute, second) = civil_from_unix(timestamp);
+        RecordingDate([
+            (year - 1900).clamp(0, 255) as u8,
+            month as u8,
+            day as u8,
+            hour as u8,
+            minute as u8,
+            second as u8,
+            0,
+        ])
+    }
+}
+
+/// Seventeen-byte volume descriptor date, e.g. `2024010112000000` plus a UTC offset
+fn descriptor_date(timestamp: u64) -> [u8; 17] {
+    let (year, month, day, hour, minute, second) = civil_from_unix(timestamp);
+    let text = format!("{:04}{:02}{:02}{:02}{:02}{:02}00", year, month, day, hour, minute, second);
+    let mut date = [0u8; 17];
+    date[..16].copy_from_slice(text.as_bytes());
+    date
+}
+
+/// Convert seconds since the Unix epoch into a UTC calendar date and time
+fn civil_from_unix(timestamp: u64) -> (i64, i64, i64, i64, i64, i64) {
+    let days = (timestamp / 86_400) as i64;
+    let seconds = (timestamp % 86_400) as i64;
+
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, seconds / 3_600, (seconds % 3_600) / 60, seconds % 60)
+}
+
+fn directory_record(extent: usize, size: usize, is_directory: bool, name: &[u8], date: &RecordingDate) -> Vec<u8> {
+    let name_len = name.len();
+    let mut len = 33 + name_len;
+    if len % 2 == 1 {
+        len += 1;
+    }
+
+    let mut record = vec![0u8; len];
+    record[0] = len as u8;
+    record[2..10].copy_from_slice(&both_u32(extent as u32));
+    record[10..18].copy_from_slice(&both_u32(size as u32));
+    record[18..25].copy_from_slice(&date.0);
+    record[25] = if is_directory { 2 } else { 0 };
+    record[28..32].copy_from_slice(&both_u16(1));
+    record[32] = name_len as u8;
+    record[33..33 + name_len].copy_from_slice(name);
+    record
+}
+
+fn directory(
+    sector: usize,
+    files: &[&IsoFile],
+    extents: &[usize],
+    names: &[Vec<u8>],
+    date: &RecordingDate,
+) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    data.extend(directory_record(sector, SECTOR_SIZE, true, &[0], date));
+    data.extend(directory_record(sector, SECTOR_SIZE, true, &[1], date));
+
+    // Records must be ordered by identifier within each tree
+    let mut order: Vec<usize> = (0..files.len()).collect();
+    order.sort_by(|a, b| names[*a].cmp(&names[*b]));
+
+    for index in order {
+        data.extend(directory_record(extents[index], files[index].data.len(), false, &names[index], date));
+    }
+
+    if data.len() > SECTOR_SIZE {
+        return Err("Too many files for a single-sector ISO root directory".to_string());
+    }
+    Ok(data)
+}
+
+fn path_table(root_sector: usize, big_endian: bool) -> Vec<u8> {
+    let mut table = vec![0u8; 10];
+    table[0] = 1;
+    if big_endian {
+        table[2..6].copy_from_slice(&(root_sector as u32).to_be_bytes());
+        table[6..8].copy_from_slice(&1u16.to_be_bytes());
+    } else {
+        table[2..6].copy_from_slice(&(root_sector as u32).to_le_bytes());
+        table[6..8].copy_from_slice(&1u16.to_le_bytes());
+    }
+    table
+}
+
+struct VolumeDescriptor<'a> {
+    joliet: bool,
+    volume_id: &'a str,
+    total_sectors: usize,
+    path_table_size: u32,
+    l_path_table: usize,
+    m_path_table: usize,
+    root_sector: usize,
+    date: &'a RecordingDate,
+    timestamp: u64,
+}
+
+fn volume_descriptor(vd: VolumeDescriptor) -> Vec<u8> {
+    let mut sector = vec![0u8; SECTOR_SIZE];
+    sector[0] = if vd.joliet { 2 } else { 1 };
+    sector[1..6].copy_from_slice(b"CD001");
+    sector[6] = 1;
+    text_field(&mut sector[8..40], "", vd.joliet);
+    text_field(&mut sector[40..72], vd.volume_id, vd.joliet);
+    sector[80..88].copy_from_slice(&both_u32(vd.total_sectors as u32));
+    if vd.joliet {
+        // UCS-2 level 3 escape sequence
+        sector[88..91].copy_from_slice(b"%/E");
+    }
+    sector[120..124].copy_from_slice(&both_u16(1));
+    sector[124..128].copy_from_slice(&both_u16(1));
+    sector[128..132].copy_from_slice(&both_u16(SECTOR_SIZE as u16));
+    sector[132..140].copy_from_slice(&both_u32(vd.path_table_size));
+    sector[140..144].copy_from_slice(&(vd.l_path_table as u32).to_le_bytes());
+    sector[148..152].copy_from_slice(&(vd.m_path_table as u32).to_be_bytes());
+    sector[156..190].copy_from_slice(&directory_record(vd.root_sector, SECTOR_SIZE, true, &[0], vd.date));
+    for range in [190..318, 318..446, 446..574, 574..702, 702..739, 739..776, 776..813] {
+        text_field(&mut sector[range], "", vd.joliet);
+    }
+    let date = descriptor_date(vd.timestamp);
+    sector[813..830].copy_from_slice(&date);
+    sector[830..847].copy_from_slice(&date);
+    // Expiration and effective dates are left unspecified
+    sector[847..863].fill(b'0');
+    sector[864..880].fill(b'0');
+    sector[881] = 1;
+    sector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(image: &[u8], offset: usize) -> usize {
+        u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    /// Read the (name, data) pairs of the root directory referenced by a volume descriptor
+    fn read_root(image: &[u8], descriptor_sector: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let vd = descriptor_sector * SECTOR_SIZE;
+        let root_extent = u32_at(image, vd + 156 + 2);
+        let root_size = u32_at(image, vd + 156 + 10);
+
+        let mut entries = Vec::new();
+        let mut offset = root_extent * SECTOR_SIZE;
+        let end = offset + root_size;
+        while offset < end && image[offset] != 0 {
+            let len = image[offset] as usize;
+            let extent = u32_at(image, offset + 2);
+            let size = u32_at(image, offset + 10);
+            let name_len = image[offset + 32] as usize;
+            let name = image[offset + 33..offset + 33 + name_len].to_vec();
+            if name != [0] && name != [1] {
+                let data = image[extent * SECTOR_SIZE..extent * SECTOR_SIZE + size].to_vec();
+                entries.push((name, data));
+            }
+            offset += len;
+        }
+        entries
+    }
+
+    fn sample_image() -> Vec<u8> {
+        let mut iso = IsoImage::new("cidata").with_timestamp(1_700_000_000);
+        iso.add_file("user-data", "#cloud-config\nhostname: worker-1\n").unwrap();
+        iso.add_file("meta-data", "instance-id: abc\n").unwrap();
+        iso.build().unwrap()
+    }
+
+    #[test]
+    fn descriptors_are_in_place() {
+        let image = sample_image();
+        assert_eq!(image.len() % SECTOR_SIZE, 0);
+
+        let pvd = &image[16 * SECTOR_SIZE..17 * SECTOR_SIZE];
+        assert_eq!(pvd[0], 1);
+        assert_eq!(&pvd[1..6], b"CD001");
+        assert_eq!(&pvd[40..46], b"cidata");
+        assert_eq!(pvd[46], b' ');
+        assert_eq!(u32_at(pvd, 80) * SECTOR_SIZE, image.len());
+
+        let svd = &image[17 * SECTOR_SIZE..18 * SECTOR_SIZE];
+        assert_eq!(svd[0], 2);
+        assert_eq!(&svd[88..91], b"%/E");
+        assert_eq!(&svd[40..52], &ucs2("cidata")[..]);
+
+        let terminator = &image[18 * SECTOR_SIZE..19 * SECTOR_SIZE];
+        assert_eq!(terminator[0], 255);
+        assert_eq!(&terminator[1..6], b"CD001");
+    }
+
+    #[test]
+    fn joliet_tree_keeps_original_names() {
+        let image = sample_image();
+        let entries = read_root(&image, 17);
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, ucs2("meta-data"));
+        assert_eq!(entries[0].1, b"instance-id: abc\n");
+        assert_eq!(entries[1].0, ucs2("user-data"));
+        assert_eq!(entries[1].1, b"#cloud-config\nhostname: worker-1\n");
+    }
+
+    #[test]
+    fn primary_tree_uses_d_characters() {
+        let image = sample_image();
+        let names: Vec<Vec<u8>> = read_root(&image, 16).into_iter().map(|(name, _)| name).collect();
+
+        assert_eq!(names, vec![b"META_DATA.;1".to_vec(), b"USER_DATA.;1".to_vec()]);
+        assert_eq!(primary_name("unattend.xml"), b"UNATTEND.XML;1");
+    }
+
+    #[test]
+    fn large_files_span_multiple_sectors() {
+        let payload: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let mut iso = IsoImage::new("data").with_timestamp(0);
+        iso.add_file("blob", payload.clone()).unwrap();
+        iso.add_file("empty", Vec::new()).unwrap();
+        let image = iso.build().unwrap();
+
+        let entries = read_root(&image, 17);
+        assert_eq!(entries[0].1, payload);
+        assert!(entries[1].1.is_empty());
+    }
+
+    #[test]
+    fn rejects_duplicate_and_invalid_names() {
+        let mut iso = IsoImage::new("cidata");
+        iso.add_file("meta-data", "").unwrap();
+        assert!(iso.add_file("meta-data", "").is_err());
+        assert!(iso.add_file("dir/file", "").is_err());
+        assert!(iso.add_file("", "").is_err());
+    }
+
+    #[test]
+    fn converts_unix_time_to_calendar_dates() {
+        assert_eq!(civil_from_unix(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil_from_unix(1_700_000_000), (2023, 11, 14, 22, 13, 20));
+        assert_eq!(civil_from_unix(951_782_400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(&descriptor_date(0)[..16], b"1970010100000000");
+    }
+}
@@ -0,0 +1,286 @@
+// File: cpi_hyperv/src/kvp.rs
+//! Hyper-V Key-Value Pair (KVP) exchange items.
+//!
+//! WMI exposes KVP items as embedded `Msvm_KvpExchangeDataItem` instances in
+//! CIM-XML, e.g.
+//!
+//! ```text
+//! <INSTANCE CLASSNAME="Msvm_KvpExchangeDataItem">
+//!   <PROPERTY NAME="Data" TYPE="string"><VALUE>Windows Server 2022</VALUE></PROPERTY>
+//!   <PROPERTY NAME="Name" TYPE="string"><VALUE>OSName</VALUE></PROPERTY>
+//!   <PROPERTY NAME="Source" TYPE="uint16"><VALUE>2</VALUE></PROPERTY>
+//! </INSTANCE>
+//! ```
+//!
+//! The same form is passed to `AddKvpItems`, `ModifyKvpItems` and
+//! `RemoveKvpItems` when writing to the host pool.
+
+use crate::unattend::xml_escape;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Longest key Hyper-V accepts, in UTF-16 code units (512 bytes)
+const MAX_NAME_UNITS: usize = 256;
+/// Longest value Hyper-V accepts, in UTF-16 code units (2048 bytes)
+const MAX_DATA_UNITS: usize = 1024;
+
+/// One KVP exchange item
+#[derive(Debug, Clone, PartialEq)]
+pub struct KvpItem {
+    pub name: String,
+    pub data: String,
+    /// Pool the item came from: 0 host, 1 guest, 2 guest intrinsic, 3 host-only
+    pub source: Option<u16>,
+}
+
+impl KvpItem {
+    /// Parse an embedded `Msvm_KvpExchangeDataItem` instance
+    pub fn parse(xml: &str) -> Result<Self, String> {
+        if !xml.contains("Msvm_KvpExchangeDataItem") {
+            return Err("Not a Msvm_KvpExchangeDataItem instance".to_string());
+        }
+        let properties = properties(xml)?;
+        let property = |name: &str| properties.iter().find(|(n, _)| n == name).and_then(|(_, v)| v.clone());
+
+        let name = property("Name").ok_or_else(|| "KVP item has no Name".to_string())?;
+        let source = match property("Source") {
+            Some(source) => Some(source.trim().parse::<u16>()
+                .map_err(|_| format!("Invalid KVP item Source '{}'", source))?),
+            None => None,
+        };
+
+        Ok(KvpItem {
+            name,
+            data: property("Data").unwrap_or_default(),
+            source,
+        })
+    }
+
+    /// Render the item as an embedded instance for the host pool methods
+    pub fn to_xml(&self) -> String {
+        format!(
+            "<INSTANCE CLASSNAME=\"Msvm_KvpExchangeDataItem\">\
+             <PROPERTY NAME=\"Data\" TYPE=\"string\"><VALUE>{}</VALUE></PROPERTY>\
+             <PROPERTY NAME=\"Name\" TYPE=\"string\"><VALUE>{}</VALUE></PROPERTY>\
+             <PROPERTY NAME=\"Source\" TYPE=\"uint16\"><VALUE>{}</VALUE></PROPERTY>\
+             </INSTANCE>",
+            xml_escape(&self.data),
+            xml_escape(&self.name),
+            self.source.unwrap_or(0)
+        )
+    }
+}
+
+/// The KVP pools of a VM, each as a name to data map
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KvpPools {
+    /// Facts the integration services publish about the guest (OS, FQDN, addresses)
+    pub intrinsic: BTreeMap<String, String>,
+    /// Items written by software in the guest
+    pub guest: BTreeMap<String, String>,
+    /// Items the host pushes to the guest
+    pub host: BTreeMap<String, String>,
+}
+
+impl KvpPools {
+    /// Parse the `intrinsic`, `guest` and `host` item lists of `Get-KvpItems`
+    pub fn from_json(raw: &Value) -> Result<Self, String> {
+        let pool = |name: &str| -> Result<BTreeMap<String, String>, String> {
+            let items: Vec<String> = match &raw[name] {
+                Value::Null => Vec::new(),
+                value => serde_json::from_value(value.clone())
+                    .map_err(|_| format!("KVP pool '{}' must be a list of items", name))?,
+            };
+            parse_items(&items)
+        };
+
+        Ok(KvpPools {
+            intrinsic: pool("intrinsic")?,
+            guest: pool("guest")?,
+            host: pool("host")?,
+        })
+    }
+}
+
+/// Parse a list of embedded instances into a name to data map
+pub fn parse_items(items: &[String]) -> Result<BTreeMap<String, String>, String> {
+    items.iter()
+        .map(|xml| KvpItem::parse(xml).map(|item| (item.name, item.data)))
+        .collect()
+}
+
+/// Check that a key and value fit the limits of the exchange
+pub fn validate(name: &str, data: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("KVP key must not be empty".to_string());
+    }
+    if name.encode_utf16().count() > MAX_NAME_UNITS {
+        return Err(format!("KVP key '{}' is longer than {} characters", name, MAX_NAME_UNITS));
+    }
+    if data.encode_utf16().count() > MAX_DATA_UNITS {
+        return Err(format!("KVP value for '{}' is longer than {} characters", name, MAX_DATA_UNITS));
+    }
+    if name.contains('\0') || data.contains('\0') {
+        return Err(format!("KVP key or value for '{}' contains a NUL character", name));
+    }
+    Ok(())
+}
+
+// Collect the NAME and VALUE of every PROPERTY element; VALUE is None when absent
+fn properties(xml: &str) -> Result<Vec<(String, Option<String>)>, String> {
+    let mut properties = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find("<PROPERTY") {
+        rest = &rest[start + "<PROPERTY".len()..];
+        let tag_end = rest.find('>').ok_or_else(|| "Unterminated PROPERTY tag".to_string())?;
+        let attributes = &rest[..tag_end];
+        let name = attribute(attributes, "NAME")
+            .ok_or_else(|| "PROPERTY without NAME".to_string())?;
+
+        if attributes.trim_end().ends_with('/') {
+            rest = &rest[tag_end + 1..];
+            properties.push((name, None));
+            continue;
+        }
+
+        let body_end = rest.find("</PROPERTY>").ok_or_else(|| format!("Unterminated PROPERTY '{}'", name))?;
+        let body = &rest[tag_end + 1..body_end];
+        let value = match (body.find("<VALUE>"), body.find("</VALUE>")) {
+            (Some(open), Some(close)) if open < close => Some(unescape(&body[open + "<VALUE>".len()..close])?),
+            _ => None,
+        };
+        properties.push((name, value));
+        rest = &rest[body_end + "</PROPERTY>".len()..];
+    }
+
+    Ok(properties)
+}
+
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let pattern = format!("{}=\"", name);
+    let mut search = attributes;
+    while let Some(pos) = search.find(&pattern) {
+        // Skip matches that are the tail of a longer attribute name
+        let preceded_by_space = pos == 0 || search[..pos].ends_with(char::is_whitespace);
+        let value_start = pos + pattern.len();
+        if preceded_by_space {
+            let value_end = search[value_start..].find('"')?;
+            return unescape(&search[value_start..value_start + value_end]).ok();
+        }
+        search = &search[value_start..];
+    }
+    None
+}
+
+// Replace the predefined XML entities and numeric character references
+fn unescape(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let semi = rest.find(';').ok_or_else(|| format!("Unterminated entity in '{}'", text))?;
+        let entity = &rest[1..semi];
+        let decoded = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if let Some(hex) = entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(dec) = entity.strip_prefix('#') {
+                    dec.parse::<u32>().ok()
+                } else {
+                    None
+                };
+                code.and_then(char::from_u32).ok_or_else(|| format!("Unknown entity '&{};'", entity))?
+            },
+        };
+        out.push(decoded);
+        rest = &rest[semi + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // GuestIntrinsicExchangeItems captured from a Windows Server 2022 guest
+    const INTRINSIC_SAMPLE: &[&str] = &[
+        "<INSTANCE CLASSNAME=\"Msvm_KvpExchangeDataItem\"><PROPERTY NAME=\"Caption\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"Data\" TYPE=\"string\"><VALUE>build-07.corp.example.com</VALUE></PROPERTY><PROPERTY NAME=\"Description\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"ElementName\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"InstanceID\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"Name\" TYPE=\"string\"><VALUE>FullyQualifiedDomainName</VALUE></PROPERTY><PROPERTY NAME=\"Source\" TYPE=\"uint16\"><VALUE>2</VALUE></PROPERTY></INSTANCE>",
+        "<INSTANCE CLASSNAME=\"Msvm_KvpExchangeDataItem\"><PROPERTY NAME=\"Caption\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"Data\" TYPE=\"string\"><VALUE>Windows Server 2022 Datacenter</VALUE></PROPERTY><PROPERTY NAME=\"Description\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"ElementName\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"InstanceID\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"Name\" TYPE=\"string\"><VALUE>OSName</VALUE></PROPERTY><PROPERTY NAME=\"Source\" TYPE=\"uint16\"><VALUE>2</VALUE></PROPERTY></INSTANCE>",
+        "<INSTANCE CLASSNAME=\"Msvm_KvpExchangeDataItem\"><PROPERTY NAME=\"Caption\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"Data\" TYPE=\"string\"><VALUE>10.0.20348</VALUE></PROPERTY><PROPERTY NAME=\"Description\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"ElementName\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"InstanceID\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"Name\" TYPE=\"string\"><VALUE>OSVersion</VALUE></PROPERTY><PROPERTY NAME=\"Source\" TYPE=\"uint16\"><VALUE>2</VALUE></PROPERTY></INSTANCE>",
+        "<INSTANCE CLASSNAME=\"Msvm_KvpExchangeDataItem\"><PROPERTY NAME=\"Caption\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"Data\" TYPE=\"string\"><VALUE>10.0.20348.1</VALUE></PROPERTY><PROPERTY NAME=\"Description\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"ElementName\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"InstanceID\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"Name\" TYPE=\"string\"><VALUE>IntegrationServicesVersion</VALUE></PROPERTY><PROPERTY NAME=\"Source\" TYPE=\"uint16\"><VALUE>2</VALUE></PROPERTY></INSTANCE>",
+        "<INSTANCE CLASSNAME=\"Msvm_KvpExchangeDataItem\"><PROPERTY NAME=\"Caption\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"Data\" TYPE=\"string\"><VALUE>172.20.14.9;fe80::5d1b:2c4e:9a0f:1b7e%5</VALUE></PROPERTY><PROPERTY NAME=\"Description\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"ElementName\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"InstanceID\" PROPAGATED=\"true\" TYPE=\"string\"></PROPERTY><PROPERTY NAME=\"Name\" TYPE=\"string\"><VALUE>NetworkAddressIPv4</VALUE></PROPERTY><PROPERTY NAME=\"Source\" TYPE=\"uint16\"><VALUE>2</VALUE></PROPERTY></INSTANCE>",
+    ];
+
+    // GuestExchangeItems written by a Linux guest's hv_kvp_daemon, pretty-printed by Get-CimInstance
+    const GUEST_SAMPLE: &str = "<INSTANCE CLASSNAME=\"Msvm_KvpExchangeDataItem\">\r\n\
+        <PROPERTY NAME=\"Caption\" PROPAGATED=\"true\" TYPE=\"string\"/>\r\n\
+        <PROPERTY NAME=\"Data\" TYPE=\"string\"><VALUE>ready &amp; &lt;healthy&gt; &#x2713;</VALUE></PROPERTY>\r\n\
+        <PROPERTY NAME=\"Name\" TYPE=\"string\"><VALUE>provisioning/status</VALUE></PROPERTY>\r\n\
+        <PROPERTY NAME=\"Source\" TYPE=\"uint16\"><VALUE>1</VALUE></PROPERTY>\r\n\
+        </INSTANCE>";
+
+    #[test]
+    fn parses_intrinsic_items() {
+        let items: Vec<String> = INTRINSIC_SAMPLE.iter().map(|s| s.to_string()).collect();
+        let parsed = parse_items(&items).unwrap();
+
+        assert_eq!(parsed.len(), 5);
+        assert_eq!(parsed["OSName"], "Windows Server 2022 Datacenter");
+        assert_eq!(parsed["OSVersion"], "10.0.20348");
+        assert_eq!(parsed["FullyQualifiedDomainName"], "build-07.corp.example.com");
+        assert_eq!(parsed["NetworkAddressIPv4"], "172.20.14.9;fe80::5d1b:2c4e:9a0f:1b7e%5");
+
+        let item = KvpItem::parse(INTRINSIC_SAMPLE[3]).unwrap();
+        assert_eq!(item.source, Some(2));
+    }
+
+    #[test]
+    fn parses_pools_from_json() {
+        let raw = serde_json::json!({
+            "intrinsic": [INTRINSIC_SAMPLE[1]],
+            "guest": [GUEST_SAMPLE],
+            "host": []
+        });
+        let pools = KvpPools::from_json(&raw).unwrap();
+
+        assert_eq!(pools.intrinsic["OSName"], "Windows Server 2022 Datacenter");
+        assert_eq!(pools.guest["provisioning/status"], "ready & <healthy> \u{2713}");
+        assert!(pools.host.is_empty());
+        assert!(KvpPools::from_json(&serde_json::json!({ "host": "x" })).is_err());
+    }
+
+    #[test]
+    fn parses_entities_and_self_closing_properties() {
+        let item = KvpItem::parse(GUEST_SAMPLE).unwrap();
+
+        assert_eq!(item.name, "provisioning/status");
+        assert_eq!(item.data, "ready & <healthy> \u{2713}");
+        assert_eq!(item.source, Some(1));
+        assert!(KvpItem::parse("<INSTANCE CLASSNAME=\"Msvm_Other\"></INSTANCE>").is_err());
+    }
+
+    #[test]
+    fn rendered_items_parse_back() {
+        let item = KvpItem {
+            name: "role".to_string(),
+            data: "web <\"blue\"> & 'green'".to_string(),
+            source: None,
+        };
+        let parsed = KvpItem::parse(&item.to_xml()).unwrap();
+
+        assert_eq!(parsed.name, item.name);
+        assert_eq!(parsed.data, item.data);
+        assert_eq!(parsed.source, Some(0));
+        assert!(validate("", "x").is_err());
+        assert!(validate("key", &"x".repeat(1025)).is_err());
+        assert!(validate("key", "value").is_ok());
+    }
+}
@@ -1,14 +1,40 @@
 // File: cpi_hyperv/src/lib.rs
 use lib_cpi::{
-    ActionParameter, ActionDefinition, ActionResult, CpiExtension, ParamType,
-    action, param, validation
+    ActionDefinition, ActionResult, CpiExtension, ParamType,
+    param, validation
 };
-use serde_json::{json, Value};
-use std::collections::HashMap;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
 use std::process::Command;
-use std::sync::Once;
+use std::sync::{Mutex, Once};
+
+mod base64;
+mod chain;
+mod cloud_init;
+mod console;
+mod credentials;
+mod inventory;
+pub mod iso;
+mod kvp;
+mod png;
+mod query;
+mod templates;
+mod unattend;
+mod volume;
+pub mod vhdx;
+
+use cloud_init::NoCloudSeed;
+use console::ConsoleLog;
+use credentials::GuestCredentials;
+use iso::IsoImage;
+use kvp::{KvpItem, KvpPools};
+use query::WorkerQuery;
+use templates::WorkerTemplate;
+use unattend::{UnattendDelivery, UnattendSpec};
+use volume::{VolumeConversion, VolumeSpec};
 
 #[unsafe(no_mangle)]
+#[allow(improper_ctypes_definitions)]
 pub extern "C" fn get_extension() -> *mut dyn CpiExtension {
     Box::into_raw(Box::new(HyperVExtension::new()))
 }
@@ -18,11 +44,408 @@ pub struct HyperVExtension {
     name: String,
     provider_type: String,
     default_settings: HashMap<String, Value>,
+    /// Templates registered at runtime through `register_template`
+    templates: Mutex<HashMap<String, WorkerTemplate>>,
 }
 
 // Static initialization to warm up PowerShell on first use
 static INIT: Once = Once::new();
 
+/// How the OS disk of a new worker is derived from its base image
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiskMode {
+    /// A differencing child disk backed by the base image
+    Differencing,
+    /// A full, independent copy of the base image
+    Copy,
+}
+
+impl DiskMode {
+    fn parse(mode: &str) -> Result<Self, String> {
+        match mode.to_lowercase().as_str() {
+            "differencing" | "linked" => Ok(DiskMode::Differencing),
+            "copy" | "full" => Ok(DiskMode::Copy),
+            other => Err(format!("Unknown disk mode '{}' (expected 'differencing' or 'copy')", other)),
+        }
+    }
+}
+
+/// OS disk to create and attach when provisioning a worker
+#[derive(Debug, Clone)]
+struct OsDiskSpec {
+    /// Parent VHD/VHDX; when absent a blank dynamic disk is created
+    base_image: Option<String>,
+    /// Virtual size of the disk; the base image size is kept when absent
+    size_mb: Option<i64>,
+    mode: DiskMode,
+}
+
+/// Fully resolved settings for a new worker
+#[derive(Debug, Clone)]
+struct WorkerSpec {
+    memory_mb: i64,
+    cpu_count: i64,
+    generation: i64,
+    switch_name: String,
+    vlan_id: Option<i64>,
+    secure_boot: Option<bool>,
+    secure_boot_template: Option<String>,
+    integration_services: Vec<(String, bool)>,
+    /// Directory under which a per-worker folder holds generated disks and media
+    storage_root: String,
+    os_disk: Option<OsDiskSpec>,
+    cloud_init: NoCloudSeed,
+    unattend: Option<WindowsSetup>,
+    automatic_actions: AutomaticActions,
+}
+
+/// Windows answer file to deliver to a new worker
+#[derive(Debug, Clone)]
+struct WindowsSetup {
+    spec: UnattendSpec,
+    delivery: UnattendDelivery,
+    /// Source of the local Administrator password
+    credentials: GuestCredentials,
+}
+
+/// What a worker does when the host starts, shuts down or hits a critical storage error
+#[derive(Debug, Clone, Default, PartialEq)]
+struct AutomaticActions {
+    /// Nothing, StartIfRunning or Start
+    start_action: Option<&'static str>,
+    /// Seconds to wait after host start-up before starting the worker
+    start_delay: Option<i64>,
+    /// Save, TurnOff or ShutDown
+    stop_action: Option<&'static str>,
+    /// Pause or None
+    critical_error_action: Option<&'static str>,
+}
+
+impl AutomaticActions {
+    fn from_params(params: &HashMap<String, Value>) -> Result<Self, String> {
+        let start_delay = validation::extract_int_opt(params, "automatic_start_delay")?;
+        if let Some(delay) = start_delay
+            && delay < 0 {
+            return Err(format!("automatic_start_delay must not be negative, got {}", delay));
+        }
+        
+        Ok(AutomaticActions {
+            start_action: extract_choice(params, "automatic_start_action", &["Nothing", "StartIfRunning", "Start"])?,
+            start_delay,
+            stop_action: extract_choice(params, "automatic_stop_action", &["Save", "TurnOff", "ShutDown"])?,
+            critical_error_action: extract_choice(params, "automatic_critical_error_action", &["Pause", "None"])?,
+        })
+    }
+    
+    fn is_empty(&self) -> bool {
+        *self == AutomaticActions::default()
+    }
+    
+    // Set-VM arguments applying the configured actions
+    fn set_vm_args(&self) -> String {
+        let mut args = String::new();
+        if let Some(action) = self.start_action {
+            args.push_str(&format!(" -AutomaticStartAction {}", action));
+        }
+        if let Some(delay) = self.start_delay {
+            args.push_str(&format!(" -AutomaticStartDelay {}", delay));
+        }
+        if let Some(action) = self.stop_action {
+            args.push_str(&format!(" -AutomaticStopAction {}", action));
+        }
+        if let Some(action) = self.critical_error_action {
+            args.push_str(&format!(" -AutomaticCriticalErrorAction {}", action));
+        }
+        args
+    }
+}
+
+/// How an action identifies the VM it operates on
+#[derive(Debug, Clone, PartialEq)]
+enum WorkerRef {
+    /// The VM ID (GUID), from `worker_id`
+    Id(String),
+    /// The VM name, from `worker_name`; it must match exactly one VM
+    Name(String),
+}
+
+impl WorkerRef {
+    // Prefer `worker_id` and fall back to `worker_name`
+    fn from_params(params: &HashMap<String, Value>) -> Result<Self, String> {
+        if let Some(id) = validation::extract_string_opt(params, "worker_id")? {
+            if !is_guid(&id) {
+                return Err(format!("Parameter 'worker_id' must be a VM ID (GUID), got '{}'", id));
+            }
+            return Ok(WorkerRef::Id(id));
+        }
+        
+        match validation::extract_string_opt(params, "worker_name")? {
+            Some(name) => Ok(WorkerRef::Name(name)),
+            None => Err("Either 'worker_id' or 'worker_name' must be provided".to_string()),
+        }
+    }
+    
+    // PowerShell statement binding the VM to $vm. Hyper-V allows duplicate names,
+    // so a name matching several VMs fails with AmbiguousName instead of picking one.
+    fn select(&self) -> String {
+        match self {
+            WorkerRef::Id(id) => format!("$vm = Get-VM -Id {} -ErrorAction Stop;", ps_quote(id)),
+            // The name is only expanded from $vmName, never parsed as part of the script
+            WorkerRef::Name(name) => format!(
+                "$vmName = {}; \
+                 $vm = @(Get-VM -Name $vmName -ErrorAction Stop); \
+                 if ($vm.Count -gt 1) {{ throw \"AmbiguousName: VM name '$vmName' matches $($vm.Count) VMs ($($vm.Id -join ', ')); use worker_id instead\" }}; \
+                 $vm = $vm[0];",
+                ps_quote(name)
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for WorkerRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorkerRef::Id(id) => write!(f, "{}", id),
+            WorkerRef::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+// Check for the 8-4-4-4-12 hex digit form of a GUID
+fn is_guid(value: &str) -> bool {
+    let groups: Vec<&str> = value.split('-').collect();
+    groups.len() == 5
+        && groups.iter().zip([8, 4, 4, 4, 12]).all(|(group, len)| {
+            group.len() == len && group.chars().all(|c| c.is_ascii_hexdigit())
+        })
+}
+
+/// How `import_worker` brings an exported VM onto the host
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImportMode {
+    /// Register the VM in place, keeping its ID
+    Register,
+    /// Copy the VM's files, keeping its ID
+    Restore,
+    /// Copy the VM's files, optionally with a new ID
+    Copy,
+}
+
+impl ImportMode {
+    fn parse(mode: &str) -> Result<Self, String> {
+        match mode.to_lowercase().as_str() {
+            "register" => Ok(ImportMode::Register),
+            "restore" => Ok(ImportMode::Restore),
+            "copy" => Ok(ImportMode::Copy),
+            other => Err(format!("Unknown import mode '{}' (expected 'register', 'restore' or 'copy')", other)),
+        }
+    }
+    
+    fn as_str(&self) -> &'static str {
+        match self {
+            ImportMode::Register => "register",
+            ImportMode::Restore => "restore",
+            ImportMode::Copy => "copy",
+        }
+    }
+}
+
+/// Parameters of an `import_worker` call
+#[derive(Debug, Clone)]
+struct ImportOptions {
+    path: String,
+    mode: ImportMode,
+    generate_new_id: bool,
+    new_name: Option<String>,
+    destination: Option<String>,
+    storage_root: String,
+    switch_name: Option<String>,
+    check_only: bool,
+}
+
+/// A boot device as expressed through the CPI API, independent of VM generation
+#[derive(Debug, Clone, PartialEq)]
+enum BootDevice {
+    /// A hard disk, optionally identified by the path of its attached VHD
+    Disk(Option<String>),
+    /// A DVD drive, optionally identified by the path of its mounted ISO
+    Dvd(Option<String>),
+    /// A network adapter (PXE), optionally identified by adapter name
+    Network(Option<String>),
+    /// A floppy drive (Generation 1 only)
+    Floppy,
+}
+
+impl BootDevice {
+    // Parse a device descriptor such as {"type": "disk", "path": "C:\\vm.vhdx"}
+    // or the shorthand string form "dvd"
+    fn from_value(value: &Value) -> Result<Self, String> {
+        let (kind, path, name) = match value {
+            Value::String(kind) => (kind.as_str(), None, None),
+            Value::Object(obj) => (
+                obj.get("type").and_then(|t| t.as_str())
+                    .ok_or_else(|| "Boot device is missing a 'type' field".to_string())?,
+                obj.get("path").and_then(|p| p.as_str()).map(|p| p.to_string()),
+                obj.get("name").and_then(|n| n.as_str()).map(|n| n.to_string()),
+            ),
+            _ => return Err("Boot device must be a string or an object".to_string()),
+        };
+        
+        match kind.to_lowercase().as_str() {
+            "disk" | "hdd" | "ide" => Ok(BootDevice::Disk(path)),
+            "dvd" | "cd" => Ok(BootDevice::Dvd(path)),
+            "network" | "net" | "pxe" => Ok(BootDevice::Network(name)),
+            "floppy" => Ok(BootDevice::Floppy),
+            other => Err(format!("Unknown boot device type '{}'", other)),
+        }
+    }
+    
+    // Name of the Gen1 BIOS startup device class
+    fn bios_name(&self) -> &'static str {
+        match self {
+            BootDevice::Disk(_) => "IDE",
+            BootDevice::Dvd(_) => "CD",
+            BootDevice::Network(_) => "LegacyNetworkAdapter",
+            BootDevice::Floppy => "Floppy",
+        }
+    }
+    
+    // PowerShell expression that resolves to the Gen2 firmware boot device of $vm
+    fn firmware_lookup(&self) -> Result<String, String> {
+        match self {
+            BootDevice::Disk(Some(path)) => Ok(format!(
+                "Get-VMHardDiskDrive -VM $vm | Where-Object {{ $_.Path -eq {} }} | Select-Object -First 1",
+                ps_quote(path)
+            )),
+            BootDevice::Disk(None) => Ok("Get-VMHardDiskDrive -VM $vm | Select-Object -First 1".to_string()),
+            BootDevice::Dvd(Some(path)) => Ok(format!(
+                "Get-VMDvdDrive -VM $vm | Where-Object {{ $_.Path -eq {} }} | Select-Object -First 1",
+                ps_quote(path)
+            )),
+            BootDevice::Dvd(None) => Ok("Get-VMDvdDrive -VM $vm | Select-Object -First 1".to_string()),
+            BootDevice::Network(Some(name)) => Ok(format!(
+                "Get-VMNetworkAdapter -VM $vm -Name {} | Select-Object -First 1",
+                ps_quote(name)
+            )),
+            BootDevice::Network(None) => Ok("Get-VMNetworkAdapter -VM $vm | Select-Object -First 1".to_string()),
+            BootDevice::Floppy => Err("Floppy boot is only supported on Generation 1 VMs".to_string()),
+        }
+    }
+    
+    fn to_json(&self) -> Value {
+        match self {
+            BootDevice::Disk(path) => json!({ "type": "disk", "path": path }),
+            BootDevice::Dvd(path) => json!({ "type": "dvd", "path": path }),
+            BootDevice::Network(name) => json!({ "type": "network", "name": name }),
+            BootDevice::Floppy => json!({ "type": "floppy" }),
+        }
+    }
+}
+
+// Optional string parameter restricted to `choices`, matched case-insensitively
+fn extract_choice(params: &HashMap<String, Value>, name: &str, choices: &[&'static str]) -> Result<Option<&'static str>, String> {
+    match validation::extract_string_opt(params, name)? {
+        Some(value) => choices.iter()
+            .find(|choice| choice.eq_ignore_ascii_case(&value))
+            .map(|choice| Some(*choice))
+            .ok_or_else(|| format!("Parameter '{}' must be one of {}, got '{}'", name, choices.join(", "), value)),
+        None => Ok(None),
+    }
+}
+
+// COM port number from the `port` parameter, 1 unless given
+fn extract_com_port(params: &HashMap<String, Value>) -> Result<i64, String> {
+    let port = validation::extract_int_opt(params, "port")?.unwrap_or(1);
+    if port != 1 && port != 2 {
+        return Err(format!("port must be 1 or 2, got {}", port));
+    }
+    Ok(port)
+}
+
+// Optional boolean parameter; lib_cpi only provides the required variant
+fn extract_bool_opt(params: &HashMap<String, Value>, name: &str) -> Result<Option<bool>, String> {
+    match params.get(name) {
+        Some(Value::Bool(b)) => Ok(Some(*b)),
+        Some(_) => Err(format!("Parameter '{}' must be a boolean", name)),
+        None => Ok(None),
+    }
+}
+
+// Name get_volumes has always reported for a disk type
+fn legacy_type_name(volume_type: volume::VolumeType) -> &'static str {
+    match volume_type {
+        volume::VolumeType::Fixed => "FixedSize",
+        volume::VolumeType::Dynamic => "DynamicExpanding",
+        volume::VolumeType::Differencing => "Differencing",
+    }
+}
+
+// Describe a disk read with the native parser
+fn volume_json(path: &str, info: &vhdx::DiskInfo) -> Value {
+    json!({
+        "id": path,
+        "path": path,
+        "size_mb": info.virtual_size / (1024 * 1024),
+        "format": legacy_type_name(info.volume_type),
+        "vhd_format": info.format.extension(),
+        "type": info.volume_type.as_str(),
+        "block_size_bytes": info.block_size,
+        "logical_sector_size": info.logical_sector_size,
+        "physical_sector_size": info.physical_sector_size,
+        "disk_id": info.disk_id.to_string(),
+        "parent_path": info.parent.as_ref().and_then(|p| p.path())
+    })
+}
+
+// Settings for writing a new disk without New-VHD: a dynamic or differencing VHDX
+// in a local directory, whose parent (if any) can be read here
+fn native_vhdx(spec: &VolumeSpec) -> Result<Option<vhdx::NewVhdx>, String> {
+    const MB: u64 = 1024 * 1024;
+    
+    let local = std::path::Path::new(&spec.path).parent().is_some_and(|dir| dir.is_dir());
+    if spec.format != volume::VolumeFormat::Vhdx || spec.source_disk.is_some() || !local {
+        return Ok(None);
+    }
+    
+    let mut disk = match spec.volume_type {
+        volume::VolumeType::Fixed => return Ok(None),
+        volume::VolumeType::Dynamic => {
+            let mut disk = vhdx::NewVhdx::dynamic(spec.size_mb.unwrap_or(0) as u64 * MB);
+            if let Some(size) = spec.logical_sector_size {
+                disk.logical_sector_size = size as u32;
+            }
+            if let Some(size) = spec.physical_sector_size {
+                disk.physical_sector_size = size as u32;
+            }
+            disk
+        },
+        volume::VolumeType::Differencing => {
+            let parent_path = spec.parent_path.as_deref().unwrap_or_default();
+            // An in-use or remote parent is left to New-VHD
+            let Ok(parent) = vhdx::inspect(parent_path) else {
+                return Ok(None);
+            };
+            let mut disk = vhdx::NewVhdx::differencing(&spec.path, parent_path, &parent)?;
+            if let Some(size_mb) = spec.size_mb {
+                if (size_mb as u64 * MB) < parent.virtual_size {
+                    return Err("A differencing disk cannot be smaller than its parent".to_string());
+                }
+                disk.virtual_size = size_mb as u64 * MB;
+            }
+            disk
+        },
+    };
+    if let Some(block_size_mb) = spec.block_size_mb {
+        disk.block_size = (block_size_mb as u64 * MB) as u32;
+    }
+    Ok(Some(disk))
+}
+
+// Quote text as a PowerShell single-quoted string literal
+fn ps_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
 impl HyperVExtension {
     pub fn new() -> Self {
         // Initialize PowerShell once for faster subsequent calls
@@ -38,7 +461,7 @@ impl HyperVExtension {
                 ])
                 .output();
                 
-            if let Ok(_) = warmup {
+            if warmup.is_ok() {
                 println!("PowerShell session warmed up");
             }
         });
@@ -49,17 +472,35 @@ impl HyperVExtension {
         default_settings.insert("switch_name".to_string(), json!("Default Switch"));
         default_settings.insert("generation".to_string(), json!(2));
         default_settings.insert("username".to_string(), json!("Administrator"));
-        default_settings.insert("password".to_string(), json!("password"));
+        default_settings.insert("templates".to_string(), templates::builtin_templates());
+        default_settings.insert("storage_root".to_string(), json!("C:\\ProgramData\\Microsoft\\Windows\\Virtual Hard Disks"));
+        default_settings.insert("console_log_dir".to_string(), json!("C:\\ProgramData\\cpi_hyperv\\console"));
+        default_settings.insert("console_log_max_bytes".to_string(), json!(console::DEFAULT_MAX_BYTES));
+        default_settings.insert("console_log_files".to_string(), json!(console::DEFAULT_MAX_FILES));
 
         Self {
             name: "hyperv".to_string(),
             provider_type: "command".to_string(),
             default_settings,
+            templates: Mutex::new(HashMap::new()),
         }
     }
     
+    // Look up a string-valued default setting
+    fn default_string(&self, key: &str) -> Option<String> {
+        self.default_settings.get(key)
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+    }
+    
     // Helper method to run PowerShell commands - optimized version
     fn run_powershell(&self, script: &str) -> Result<String, String> {
+        self.run_powershell_with_env(script, &[])
+    }
+    
+    // Secrets are handed to the script through environment variables so they
+    // never appear in the (logged) script text
+    fn run_powershell_with_env(&self, script: &str, env: &[(&str, &str)]) -> Result<String, String> {
         println!("Running PowerShell script: {}", script);
         
         // Use PowerShell Core (pwsh) if available, as it has faster startup time
@@ -93,6 +534,7 @@ impl HyperVExtension {
         
         let output = Command::new(command)
             .args(&args)
+            .envs(env.iter().copied())
             .output()
             .map_err(|e| format!("Failed to execute PowerShell command: {}", e))?;
             
@@ -101,6 +543,13 @@ impl HyperVExtension {
             Ok(stdout)
         } else {
             let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+            
+            // Report ambiguous worker names without PowerShell's error decoration
+            if let Some(start) = stderr.find("AmbiguousName:") {
+                let message = stderr[start..].lines().next().unwrap_or_default();
+                return Err(message.trim().to_string());
+            }
+            
             Err(format!("PowerShell command failed: {}", stderr))
         }
     }
@@ -114,294 +563,780 @@ impl HyperVExtension {
         let output = self.run_powershell(script)?;
         
         // Parse the output
-        if let Some(version_line) = output.lines().next() {
-            if version_line.contains("Major") {
-                return Ok(json!({
-                    "success": true,
-                    "version": version_line.trim()
-                }));
-            }
+        if let Some(version_line) = output.lines().next()
+            && version_line.contains("Major") {
+            return Ok(json!({
+                "success": true,
+                "version": version_line.trim()
+            }));
         }
         
         Err("Could not determine PowerShell version".to_string())
     }
     
-    fn list_workers(&self) -> ActionResult {
-        // Use a more optimized PowerShell script with faster output format
-        // Use CSV format which parses faster than JSON
-        let script = "Get-VM | Select-Object Name, Id, State | ForEach-Object { \
-                      $state = switch($_.State) { \
-                        2 {'Running'} \
-                        3 {'Stopped'} \
-                        default {'Unknown'} \
-                      }; \
-                      [PSCustomObject]@{ \
-                        Name=$_.Name; \
-                        Id=$_.Id; \
-                        State=$state \
-                      } \
-                    } | ConvertTo-Csv -NoTypeInformation";
-                      
-        let output = self.run_powershell(script)?;
-        
-        // Parse CSV output which is faster than JSON parsing
-        let mut workers = Vec::new();
-        let mut lines = output.lines();
-        
-        // Skip the header line
-        if let Some(_header) = lines.next() {
-            for line in lines {
-                if line.trim().is_empty() {
-                    continue;
-                }
-                
-                // Parse CSV line
-                let parts: Vec<&str> = line.split(',').collect();
-                if parts.len() >= 3 {
-                    // Trim quotes from CSV values
-                    let name = parts[0].trim_matches('"').to_string();
-                    let id = parts[1].trim_matches('"').to_string();
-                    let state = parts[2].trim_matches('"').to_string();
-                    
-                    workers.push(json!({
-                        "name": name,
-                        "id": id,
-                        "state": state
-                    }));
-                }
-            }
-        }
-        
-        Ok(json!({
-            "workers": workers
-        }))
+    fn list_workers(&self, query: WorkerQuery) -> ActionResult {
+        // Filtering, sorting and paging run inside the PowerShell pipeline so
+        // only the requested page is serialized
+        let output = self.run_powershell(&query.script())?;
+        query.paginate(&output)
     }
     
-    fn create_worker(&self, worker_name: String, memory_mb: i64, cpu_count: i64, generation: i64, switch_name: String) -> ActionResult {
+    fn create_worker(&self, worker_name: String, spec: WorkerSpec) -> ActionResult {
         // First, check if VM already exists
         let check_script = format!("Get-VM -Name \"{}\" -ErrorAction SilentlyContinue", worker_name);
         let check_output = self.run_powershell(&check_script);
         
-        if let Ok(output) = check_output {
-            if !output.trim().is_empty() {
-                return Err(format!("VM '{}' already exists", worker_name));
+        if let Ok(output) = check_output
+            && !output.trim().is_empty() {
+            return Err(format!("VM '{}' already exists", worker_name));
+        }
+        
+        // Validate the base image before creating anything so there is nothing to roll back
+        if let Some(base_image) = spec.os_disk.as_ref().and_then(|d| d.base_image.as_ref()) {
+            let script = format!("Test-Path -Path \"{}\" -PathType Leaf", base_image);
+            let output = self.run_powershell(&script)?;
+            if output.trim().to_lowercase() != "true" {
+                return Err(format!("Base image '{}' does not exist", base_image));
             }
         }
         
         // Create VM
         let create_script = format!(
-            "New-VM -Name \"{}\" -MemoryStartupBytes {}MB -Generation {} -SwitchName \"{}\" | Out-Null; \
-             Set-VM -Name \"{}\" -ProcessorCount {}; \
-             Get-VM -Name \"{}\" | Select-Object Name, Id, State | ConvertTo-Json",
-            worker_name, memory_mb, generation, switch_name, worker_name, cpu_count, worker_name
+            "$name = {}; \
+             New-VM -Name $name -MemoryStartupBytes {}MB -Generation {} -SwitchName {} | Out-Null; \
+             Set-VM -Name $name -ProcessorCount {}; \
+             Get-VM -Name $name | Select-Object Name, Id, State | ConvertTo-Json",
+            ps_quote(&worker_name), spec.memory_mb, spec.generation, ps_quote(&spec.switch_name), spec.cpu_count
         );
         
-        let output = self.run_powershell(&create_script)?;
+        let output = match self.run_powershell(&create_script) {
+            Ok(output) => output,
+            Err(e) => {
+                // New-VM may have succeeded before a later statement failed
+                self.cleanup_worker(&WorkerRef::Name(worker_name.clone()), &[]);
+                return Err(e);
+            }
+        };
         
         // Parse the output JSON
-        let vm_info: Value = serde_json::from_str(&output)
-            .map_err(|e| format!("Failed to parse VM info: {}", e))?;
-        
-        let id = vm_info["Id"].as_str().unwrap_or("unknown").to_string();
+        let id = match serde_json::from_str::<Value>(&output).ok()
+            .and_then(|vm_info| vm_info["Id"].as_str().map(|id| id.to_string())) {
+            Some(id) => id,
+            None => {
+                self.cleanup_worker(&WorkerRef::Name(worker_name.clone()), &[]);
+                return Err(format!("Failed to read the ID of new VM '{}' (rolled back)", worker_name));
+            }
+        };
         
-        Ok(json!({
+        let mut result = json!({
             "success": true,
             "id": id,
             "name": worker_name
-        }))
-    }
-    
-    fn delete_worker(&self, worker_name: String) -> ActionResult {
-        // Stop VM if running
-        let stop_script = format!(
-            "Stop-VM -Name \"{}\" -TurnOff -Force -ErrorAction SilentlyContinue",
-            worker_name
-        );
-        let _ = self.run_powershell(&stop_script);
-        
-        // Delete VM
-        let delete_script = format!(
-            "Remove-VM -Name \"{}\" -Force",
-            worker_name
-        );
+        });
         
-        self.run_powershell(&delete_script)?;
+        // From here on, address the new VM by ID
+        let worker = WorkerRef::Id(id.clone());
         
-        Ok(json!({
-            "success": true
-        }))
-    }
-    
-    fn get_worker(&self, worker_name: String) -> ActionResult {
-        let script = format!(
-            "$vm = Get-VM -Name \"{}\" -ErrorAction Stop; \
-             $vmInfo = $vm | Select-Object Name, Id, State, @{{Name='memory_mb';Expression={{$_.MemoryStartup / 1MB}}}}, \
-             @{{Name='cpu_count';Expression={{$_.ProcessorCount}}}}, \
-             @{{Name='generation';Expression={{$_.Generation}}}}; \
-             $vmInfo | ConvertTo-Json",
-            worker_name
-        );
+        if let Err(e) = self.configure_new_worker(&worker, &spec) {
+            self.cleanup_worker(&worker, &[]);
+            return Err(format!("Failed to configure VM '{}' (rolled back): {}", worker_name, e));
+        }
         
-        let output = self.run_powershell(&script)?;
+        // Files created for this worker, removed again if a later step fails. A path is
+        // only recorded once this call has created it, so existing files are never removed
+        let mut created_files = Vec::new();
         
-        // Parse the output JSON
-        let vm_info: Value = serde_json::from_str(&output)
-            .map_err(|e| format!("Failed to parse VM info: {}", e))?;
+        if let Some(os_disk) = &spec.os_disk {
+            let disk_path = Self::os_disk_path(&worker_name, &spec.storage_root, os_disk);
+            
+            if let Err(e) = self.provision_os_disk(&worker, spec.generation, &disk_path, os_disk, &mut created_files) {
+                self.cleanup_worker(&worker, &created_files);
+                return Err(format!("Failed to provision OS disk for VM '{}' (rolled back): {}", worker_name, e));
+            }
+            
+            result["disk_path"] = json!(disk_path);
+        }
         
-        let state = vm_info["State"].as_i64().map(|s| match s {
-            2 => "Running",
-            3 => "Stopped",
-            _ => "Unknown"
-        }).unwrap_or("Unknown").to_string();
+        if !spec.cloud_init.is_empty() {
+            let seed_path = Self::worker_file(&spec.storage_root, &worker_name, "cidata.iso");
+            
+            let attached = spec.cloud_init.to_iso(&id, &worker_name)
+                .and_then(|image| self.attach_seed_iso(&worker, &seed_path, &image, &mut created_files));
+            if let Err(e) = attached {
+                self.cleanup_worker(&worker, &created_files);
+                return Err(format!("Failed to attach cloud-init seed to VM '{}' (rolled back): {}", worker_name, e));
+            }
+            
+            result["seed_path"] = json!(seed_path);
+        }
         
-        let result = json!({
-            "success": true,
-            "vm": {
-                "name": vm_info["Name"].as_str().unwrap_or("unknown"),
-                "id": vm_info["Id"].as_str().unwrap_or("unknown"),
-                "state": state,
-                "memory_mb": vm_info["memory_mb"].as_i64().unwrap_or(0),
-                "cpu_count": vm_info["cpu_count"].as_i64().unwrap_or(0),
-                "generation": vm_info["generation"].as_i64().unwrap_or(0)
+        if let Some(setup) = &spec.unattend {
+            let xml = setup.spec.render(&setup.credentials.password);
+            
+            let delivered = match setup.delivery {
+                UnattendDelivery::Iso => {
+                    let iso_path = Self::worker_file(&spec.storage_root, &worker_name, "unattend.iso");
+                    
+                    let mut iso = IsoImage::new("UNATTEND");
+                    iso.add_file("unattend.xml", xml.as_bytes())
+                        .and_then(|_| iso.build())
+                        .and_then(|image| self.attach_seed_iso(&worker, &iso_path, &image, &mut created_files))
+                        .map(|_| iso_path)
+                },
+                UnattendDelivery::Inject => {
+                    let disk_path = result["disk_path"].as_str().unwrap_or_default().to_string();
+                    self.inject_unattend(&spec.storage_root, &worker_name, &disk_path, &xml)
+                        .map(|_| disk_path)
+                },
+            };
+            
+            match delivered {
+                Ok(path) => result["unattend_path"] = json!(path),
+                Err(e) => {
+                    self.cleanup_worker(&worker, &created_files);
+                    return Err(format!("Failed to deliver unattend.xml to VM '{}' (rolled back): {}", worker_name, e));
+                }
             }
-        });
+        }
         
         Ok(result)
     }
     
-    fn has_worker(&self, worker_name: String) -> ActionResult {
+    // Mount the OS disk offline and place the answer file in Windows\Panther
+    fn inject_unattend(&self, storage_root: &str, worker_name: &str, disk_path: &str, xml: &str) -> Result<(), String> {
+        // Stage the file on the host so its contents never pass through the script text
+        let staged_path = Self::worker_file(storage_root, worker_name, "unattend.xml");
+        let mut staged = std::fs::OpenOptions::new().write(true).create_new(true).open(&staged_path)
+            .map_err(|e| format!("Failed to create '{}': {}", staged_path, e))?;
+        let written = std::io::Write::write_all(&mut staged, xml.as_bytes());
+        drop(staged);
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&staged_path);
+            return Err(format!("Failed to write '{}': {}", staged_path, e));
+        }
+        
         let script = format!(
-            "Get-VM -Name \"{}\" -ErrorAction SilentlyContinue | Measure-Object | Select-Object -ExpandProperty Count",
-            worker_name
+            "$ErrorActionPreference = 'Stop'; \
+             $disk = Mount-VHD -Path {} -Passthru | Get-Disk; \
+             try {{ \
+               $partition = $disk | Get-Partition | Where-Object {{ $_.DriveLetter -and (Test-Path \"$($_.DriveLetter):\\Windows\\System32\") }} | Select-Object -First 1; \
+               if (-not $partition) {{ throw \"No Windows installation found on the OS disk\" }}; \
+               $panther = \"$($partition.DriveLetter):\\Windows\\Panther\"; \
+               New-Item -ItemType Directory -Path $panther -Force | Out-Null; \
+               Copy-Item -LiteralPath {} -Destination \"$panther\\unattend.xml\" -Force \
+             }} finally {{ \
+               Dismount-VHD -Path {} \
+             }}",
+            ps_quote(disk_path), ps_quote(&staged_path), ps_quote(disk_path)
         );
         
-        let output = self.run_powershell(&script)?;
-        
-        let count = output.trim().parse::<i32>().unwrap_or(0);
-        
-        Ok(json!({
-            "success": true,
-            "exists": count > 0
-        }))
+        let result = self.run_powershell(&script);
+        let _ = std::fs::remove_file(&staged_path);
+        result.map(|_| ())
     }
     
-    fn start_worker(&self, worker_name: String) -> ActionResult {
+    // Write a generated ISO next to the worker's disks and insert it as a DVD. An existing
+    // file is never overwritten; the ISO is added to `created_files` once written
+    fn attach_seed_iso(&self, worker: &WorkerRef, iso_path: &str, image: &[u8], created_files: &mut Vec<String>) -> Result<(), String> {
+        use std::io::Write;
+        
+        if let Some(parent) = std::path::Path::new(iso_path).parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create directory for '{}': {}", iso_path, e))?;
+        }
+        let mut file = std::fs::OpenOptions::new().write(true).create_new(true).open(iso_path)
+            .map_err(|e| format!("Failed to create '{}': {}", iso_path, e))?;
+        created_files.push(iso_path.to_string());
+        file.write_all(image)
+            .map_err(|e| format!("Failed to write '{}': {}", iso_path, e))?;
+        
         let script = format!(
-            "Start-VM -Name \"{}\"",
-            worker_name
+            "{} Add-VMDvdDrive -VM $vm -Path {} -ErrorAction Stop",
+            worker.select(), ps_quote(iso_path)
         );
+        self.run_powershell(&script)?;
+        
+        Ok(())
+    }
+    
+    // Apply firmware, network and integration service settings to a freshly created VM
+    fn configure_new_worker(&self, worker: &WorkerRef, spec: &WorkerSpec) -> Result<(), String> {
+        let mut steps = Vec::new();
+        
+        if spec.generation == 2 && (spec.secure_boot.is_some() || spec.secure_boot_template.is_some()) {
+            let mut step = String::from("Set-VMFirmware -VM $vm");
+            if let Some(secure_boot) = spec.secure_boot {
+                step.push_str(if secure_boot { " -EnableSecureBoot On" } else { " -EnableSecureBoot Off" });
+            }
+            if let Some(template) = &spec.secure_boot_template {
+                step.push_str(&format!(" -SecureBootTemplate {}", ps_quote(template)));
+            }
+            steps.push(step);
+        }
+        
+        if let Some(vlan_id) = spec.vlan_id {
+            steps.push(format!(
+                "Set-VMNetworkAdapterVlan -VM $vm -Access -VlanId {}",
+                vlan_id
+            ));
+        }
+        
+        for (service, enabled) in &spec.integration_services {
+            steps.push(format!(
+                "{}-VMIntegrationService -VM $vm -Name {}",
+                if *enabled { "Enable" } else { "Disable" }, ps_quote(service)
+            ));
+        }
+        
+        if !spec.automatic_actions.is_empty() {
+            steps.push(format!("Set-VM -VM $vm{}", spec.automatic_actions.set_vm_args()));
+        }
+        
+        if steps.is_empty() {
+            return Ok(());
+        }
         
+        let script = format!("$ErrorActionPreference = 'Stop'; {} {}", worker.select(), steps.join("; "));
         self.run_powershell(&script)?;
         
-        Ok(json!({
-            "success": true,
-            "started": worker_name
-        }))
+        Ok(())
     }
     
-    fn get_volumes(&self) -> ActionResult {
-        let script = "$vhds = Get-VHD; $vhds | Select-Object Path, VhdType, Size, @{Name='SizeGB';Expression={$_.Size / 1GB}} | ConvertTo-Json";
+    // Layer extension defaults, the named template and per-call parameters into a worker spec
+    fn resolve_worker_spec(&self, params: &HashMap<String, Value>) -> Result<WorkerSpec, String> {
+        let defaults = WorkerTemplate {
+            memory_mb: self.default_settings.get("memory_mb").and_then(|v| v.as_i64()),
+            cpu_count: self.default_settings.get("cpu_count").and_then(|v| v.as_i64()),
+            generation: self.default_settings.get("generation").and_then(|v| v.as_i64()),
+            switch_name: self.default_string("switch_name"),
+            ..Default::default()
+        };
         
-        let output = self.run_powershell(script)?;
+        let mut resolved = defaults;
+        if let Some(template_name) = validation::extract_string_opt(params, "template")? {
+            let template = self.find_template(&template_name, params)?
+                .ok_or_else(|| format!("Template '{}' not found", template_name))?;
+            resolved = resolved.overlay(&template);
+        }
+        resolved = resolved.overlay(&WorkerTemplate::from_params(params)?);
         
-        // Parse the output
-        let mut volumes = Vec::new();
+        let generation = resolved.generation.unwrap_or(2);
+        if generation == 1 && (resolved.secure_boot == Some(true) || resolved.secure_boot_template.is_some()) {
+            return Err("Secure boot is only available on Generation 2 VMs".to_string());
+        }
         
-        // Handle single disk case
-        if output.trim().starts_with('{') {
-            let disk_json: Result<Value, _> = serde_json::from_str(&output);
-            if let Ok(disk) = disk_json {
-                let path = disk["Path"].as_str().unwrap_or("unknown").to_string();
-                let size_bytes = disk["Size"].as_i64().unwrap_or(0);
-                let size_mb = size_bytes / (1024 * 1024);
-                let vhd_type = disk["VhdType"].as_i64().map(|t| match t {
-                    1 => "FixedSize",
-                    2 => "DynamicExpanding",
-                    3 => "Differencing",
-                    _ => "Unknown"
-                }).unwrap_or("Unknown").to_string();
+        let os_disk = if resolved.base_image.is_some() || resolved.disk_size_mb.is_some() {
+            let mode = resolved.disk_mode.as_deref()
+                .map(DiskMode::parse)
+                .transpose()?
+                .unwrap_or(DiskMode::Differencing);
+            
+            Some(OsDiskSpec {
+                base_image: resolved.base_image.clone(),
+                size_mb: resolved.disk_size_mb,
+                mode,
+            })
+        } else {
+            None
+        };
+        
+        let storage_root = validation::extract_string_opt(params, "storage_root")?
+            .or_else(|| self.default_string("storage_root"))
+            .ok_or_else(|| "No storage root configured for worker disks".to_string())?;
+        
+        let unattend = match params.get("unattend") {
+            Some(value) => {
+                let mut spec = UnattendSpec::from_value(value)?;
+                if spec.computer_name.is_none() {
+                    // Default to the worker name when it is a valid computer name
+                    let worker_name = validation::extract_string(params, "worker_name")?;
+                    unattend::validate_computer_name(&worker_name)
+                        .map_err(|e| format!("{}; set unattend.computer_name explicitly", e))?;
+                    spec.computer_name = Some(worker_name);
+                }
                 
-                volumes.push(json!({
-                    "id": path.clone(),
-                    "path": path,
-                    "size_mb": size_mb,
-                    "format": vhd_type
-                }));
-            }
-        } else if output.trim().starts_with('[') {
-            let disks_json: Result<Vec<Value>, _> = serde_json::from_str(&output);
-            if let Ok(disks) = disks_json {
-                for disk in disks {
-                    let path = disk["Path"].as_str().unwrap_or("unknown").to_string();
-                    let size_bytes = disk["Size"].as_i64().unwrap_or(0);
-                    let size_mb = size_bytes / (1024 * 1024);
-                    let vhd_type = disk["VhdType"].as_i64().map(|t| match t {
-                        1 => "FixedSize",
-                        2 => "DynamicExpanding",
-                        3 => "Differencing",
-                        _ => "Unknown"
-                    }).unwrap_or("Unknown").to_string();
-                    
-                    volumes.push(json!({
-                        "id": path.clone(),
-                        "path": path,
-                        "size_mb": size_mb,
-                        "format": vhd_type
-                    }));
+                let delivery = validation::extract_string_opt(params, "unattend_delivery")?
+                    .map(|d| UnattendDelivery::parse(&d))
+                    .transpose()?
+                    .unwrap_or(UnattendDelivery::Iso);
+                if delivery == UnattendDelivery::Inject && os_disk.is_none() {
+                    return Err("Injecting unattend.xml requires an OS disk (base_image)".to_string());
                 }
+                
+                let credentials = GuestCredentials::resolve(params, &self.default_settings)?;
+                spec.username = Some(credentials.username.clone());
+                Some(WindowsSetup { spec, delivery, credentials })
+            },
+            None => None,
+        };
+        
+        let cloud_init = NoCloudSeed {
+            user_data: validation::extract_string_opt(params, "user_data")?,
+            meta_data: validation::extract_string_opt(params, "meta_data")?,
+            network_config: validation::extract_string_opt(params, "network_config")?,
+        };
+        
+        Ok(WorkerSpec {
+            memory_mb: resolved.memory_mb.unwrap_or(2048),
+            cpu_count: resolved.cpu_count.unwrap_or(2),
+            generation,
+            switch_name: resolved.switch_name.unwrap_or_else(|| "Default Switch".to_string()),
+            vlan_id: resolved.vlan_id,
+            secure_boot: resolved.secure_boot,
+            secure_boot_template: resolved.secure_boot_template,
+            integration_services: resolved.integration_services.unwrap_or_default().into_iter().collect(),
+            storage_root,
+            os_disk,
+            cloud_init,
+            unattend,
+            automatic_actions: AutomaticActions::from_params(params)?,
+        })
+    }
+    
+    // Built-in templates, overlaid by those in the `templates` setting of the host configuration
+    fn configured_templates(&self, params: &HashMap<String, Value>) -> Result<HashMap<String, WorkerTemplate>, String> {
+        let mut configured = HashMap::new();
+        
+        for source in [self.default_settings.get("templates"), params.get("templates")] {
+            let entries = match source {
+                Some(Value::Object(entries)) => entries,
+                Some(Value::Null) | None => continue,
+                Some(_) => return Err("Setting 'templates' must be an object of named templates".to_string()),
+            };
+            for (name, value) in entries {
+                let template = WorkerTemplate::from_value(value)
+                    .map_err(|e| format!("Configured template '{}': {}", name, e))?;
+                configured.insert(name.clone(), template);
             }
         }
         
-        Ok(json!({
-            "success": true,
-            "volumes": volumes
-        }))
+        Ok(configured)
     }
     
-    fn has_volume(&self, disk_path: String) -> ActionResult {
-        let script = format!(
-            "Test-Path -Path \"{}\" -PathType Leaf",
-            disk_path
-        );
+    // Registered templates take precedence over configured ones of the same name
+    fn find_template(&self, name: &str, params: &HashMap<String, Value>) -> Result<Option<WorkerTemplate>, String> {
+        let registered = self.templates.lock()
+            .map_err(|_| "Template registry is poisoned".to_string())?;
         
-        let output = self.run_powershell(&script)?;
+        if let Some(template) = registered.get(name) {
+            return Ok(Some(template.clone()));
+        }
         
-        let exists = output.trim().to_lowercase() == "true";
+        Ok(self.configured_templates(params)?.remove(name))
+    }
+    
+    fn register_template(&self, name: String, template: Value) -> ActionResult {
+        templates::validate_template_name(&name)?;
+        let template = WorkerTemplate::from_value(&template)?;
+        
+        let mut registered = self.templates.lock()
+            .map_err(|_| "Template registry is poisoned".to_string())?;
+        let replaced = registered.insert(name.clone(), template).is_some();
         
         Ok(json!({
             "success": true,
-            "exists": exists
+            "name": name,
+            "replaced": replaced
         }))
     }
     
-    fn create_volume(&self, disk_path: String, size_mb: i64) -> ActionResult {
-        let script = format!(
-            "New-VHD -Path \"{}\" -SizeBytes {}MB -Dynamic; \
-             Get-VHD -Path \"{}\" | Select-Object Path | ConvertTo-Json",
-            disk_path, size_mb, disk_path
-        );
-        
-        let output = self.run_powershell(&script)?;
+    fn list_templates(&self, params: &HashMap<String, Value>) -> ActionResult {
+        let mut templates: Vec<Value> = Vec::new();
+        
+        let registered = self.templates.lock()
+            .map_err(|_| "Template registry is poisoned".to_string())?;
+        
+        let mut configured: Vec<_> = self.configured_templates(params)?.into_iter().collect();
+        configured.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, template) in configured {
+            if !registered.contains_key(&name) {
+                templates.push(json!({
+                    "name": name,
+                    "source": "configuration",
+                    "template": template
+                }));
+            }
+        }
         
-        // Parse output to get the path
-        let disk_json: Result<Value, _> = serde_json::from_str(&output.trim());
+        let mut registered: Vec<_> = registered.iter().collect();
+        registered.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, template) in registered {
+            templates.push(json!({
+                "name": name,
+                "source": "registered",
+                "template": template
+            }));
+        }
         
-        match disk_json {
-            Ok(disk) => {
-                let path = disk["Path"].as_str().unwrap_or(&disk_path).to_string();
+        Ok(json!({
+            "success": true,
+            "templates": templates
+        }))
+    }
+    
+    // Location of a file in a worker's folder: <storage_root>\<worker_name>\<file_name>
+    fn worker_file(storage_root: &str, worker_name: &str, file_name: &str) -> String {
+        format!(
+            "{}\\{}\\{}",
+            storage_root.trim_end_matches(['\\', '/']),
+            worker_name, file_name
+        )
+    }
+    
+    // Location of a worker's OS disk: <storage_root>\<worker_name>\<worker_name>.<ext>
+    fn os_disk_path(worker_name: &str, storage_root: &str, os_disk: &OsDiskSpec) -> String {
+        let extension = os_disk.base_image.as_ref()
+            .and_then(|base| base.rsplit('.').next())
+            .filter(|ext| ext.eq_ignore_ascii_case("vhd") || ext.eq_ignore_ascii_case("vhdx"))
+            .unwrap_or("vhdx")
+            .to_lowercase();
+        
+        Self::worker_file(storage_root, worker_name, &format!("{}.{}", worker_name, extension))
+    }
+    
+    // Create the OS disk, attach it and make it the first boot device. The disk is
+    // added to `created_files` once it has been created
+    fn provision_os_disk(
+        &self,
+        worker: &WorkerRef,
+        generation: i64,
+        disk_path: &str,
+        os_disk: &OsDiskSpec,
+        created_files: &mut Vec<String>,
+    ) -> Result<(), String> {
+        let create_disk = match (&os_disk.base_image, os_disk.mode) {
+            (Some(base_image), DiskMode::Differencing) => format!(
+                "New-VHD -Path {} -ParentPath {} -Differencing | Out-Null",
+                ps_quote(disk_path), ps_quote(base_image)
+            ),
+            // File.Copy with overwrite off fails rather than replacing an existing file
+            (Some(base_image), DiskMode::Copy) => format!(
+                "[System.IO.File]::Copy({}, {}, $false)",
+                ps_quote(base_image), ps_quote(disk_path)
+            ),
+            (None, _) => format!(
+                "New-VHD -Path {} -SizeBytes {}MB -Dynamic | Out-Null",
+                ps_quote(disk_path),
+                os_disk.size_mb.ok_or_else(|| "Either 'base_image' or 'disk_size_mb' is required for an OS disk".to_string())?
+            ),
+        };
+        
+        // Refuse to touch a file that is already there, and remove a partial copy if
+        // creating the disk fails
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             $path = {}; \
+             if (Test-Path -LiteralPath $path) {{ throw \"OS disk '$path' already exists\" }}; \
+             New-Item -ItemType Directory -Path (Split-Path -Parent $path) -Force | Out-Null; \
+             try {{ {} }} catch {{ \
+               if (Test-Path -LiteralPath $path) {{ Remove-Item -LiteralPath $path -Force }}; \
+               throw \
+             }}",
+            ps_quote(disk_path), create_disk
+        );
+        self.run_powershell(&script)?;
+        created_files.push(disk_path.to_string());
+        
+        // Only grow the disk; Resize-VHD cannot shrink below the base image's partitions
+        let resize_disk = match os_disk.size_mb {
+            Some(size_mb) if os_disk.base_image.is_some() => format!(
+                "$vhd = Get-VHD -Path {}; \
+                 if ($vhd.Size -lt {}MB) {{ Resize-VHD -Path {} -SizeBytes {}MB }} \
+                 elseif ($vhd.Size -gt {}MB) {{ throw \"Requested disk size is smaller than the base image\" }}",
+                ps_quote(disk_path), size_mb, ps_quote(disk_path), size_mb, size_mb
+            ),
+            _ => String::new(),
+        };
+        
+        let controller = if generation == 1 { "IDE" } else { "SCSI" };
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             {} \
+             {}; \
+             Add-VMHardDiskDrive -VM $vm -Path {} -ControllerType {}",
+            worker.select(), resize_disk, ps_quote(disk_path), controller
+        );
+        
+        self.run_powershell(&script)?;
+        
+        self.set_boot_order(
+            worker.clone(),
+            json!([{ "type": "disk", "path": disk_path }])
+        )?;
+        
+        Ok(())
+    }
+    
+    // Best-effort removal of a partially created worker and the files created for it
+    fn cleanup_worker(&self, worker: &WorkerRef, files: &[String]) {
+        let mut script = format!(
+            "try {{ {} Stop-VM -VM $vm -TurnOff -Force -ErrorAction SilentlyContinue; \
+             Remove-VM -VM $vm -Force -ErrorAction SilentlyContinue }} catch {{ }}",
+            worker.select()
+        );
+        
+        for file in files {
+            script.push_str(&format!(
+                "; Remove-Item -LiteralPath {} -Force -ErrorAction SilentlyContinue; \
+                 $dir = Split-Path -Parent {}; \
+                 if ((Test-Path -LiteralPath $dir) -and -not (Get-ChildItem -LiteralPath $dir)) {{ Remove-Item -LiteralPath $dir -Force }}",
+                ps_quote(file), ps_quote(file)
+            ));
+        }
+        
+        let _ = self.run_powershell(&script);
+    }
+    
+    fn delete_worker(&self, worker: WorkerRef) -> ActionResult {
+        // Stop VM if running
+        let stop_script = format!(
+            "{} Stop-VM -VM $vm -TurnOff -Force -ErrorAction SilentlyContinue",
+            worker.select()
+        );
+        let _ = self.run_powershell(&stop_script);
+        
+        // Delete VM
+        let delete_script = format!(
+            "{} Remove-VM -VM $vm -Force",
+            worker.select()
+        );
+        
+        self.run_powershell(&delete_script)?;
+        
+        Ok(json!({
+            "success": true
+        }))
+    }
+    
+    fn get_worker(&self, worker: WorkerRef) -> ActionResult {
+        let script = format!(
+            "{} {} {} ConvertTo-WorkerDetail $vm | ConvertTo-Json -Depth 4 -Compress",
+            worker.select(), inventory::NOTES_PARSER, inventory::worker_detail()
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        // Parse the output JSON
+        let mut vm_info: Map<String, Value> = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse VM info: {}", e))?;
+        inventory::finish_detail(&mut vm_info);
+        
+        Ok(json!({
+            "success": true,
+            "vm": vm_info
+        }))
+    }
+    
+    fn has_worker(&self, worker: WorkerRef) -> ActionResult {
+        let script = match &worker {
+            WorkerRef::Id(id) => format!(
+                "Get-VM -Id \"{}\" -ErrorAction SilentlyContinue | Measure-Object | Select-Object -ExpandProperty Count",
+                id
+            ),
+            WorkerRef::Name(name) => format!(
+                "Get-VM -Name \"{}\" -ErrorAction SilentlyContinue | Measure-Object | Select-Object -ExpandProperty Count",
+                name
+            ),
+        };
+        
+        let output = self.run_powershell(&script)?;
+        
+        let count = output.trim().parse::<i32>().unwrap_or(0);
+        
+        Ok(json!({
+            "success": true,
+            "exists": count > 0,
+            // More than one when several VMs share the name
+            "count": count
+        }))
+    }
+    
+    fn start_worker(&self, worker: WorkerRef) -> ActionResult {
+        let script = format!(
+            "{} Start-VM -VM $vm",
+            worker.select()
+        );
+        
+        self.run_powershell(&script)?;
+        
+        Ok(json!({
+            "success": true,
+            "started": worker.to_string()
+        }))
+    }
+    
+    fn get_volumes(&self, directory: String, include_attached: bool) -> ActionResult {
+        // Disks attached to any VM live wherever their VM put them, and listing them
+        // takes a PowerShell round trip, so they are only included on request
+        let attached: Vec<String> = if include_attached {
+            let output = self.run_powershell(
+                "ConvertTo-Json -InputObject @(Get-VM | Get-VMHardDiskDrive | Where-Object { $_.Path } | ForEach-Object { $_.Path })"
+            )?;
+            serde_json::from_str(output.trim())
+                .map_err(|e| format!("Failed to parse attached disks: {}", e))?
+        } else {
+            Vec::new()
+        };
+        
+        // Disks readable from here are parsed directly; Get-VHD is only asked about
+        // the rest, such as VHDX files held open by a running VM
+        let (mut paths, scan) = match vhdx::find_disks(&directory) {
+            Ok(found) => (found.iter().map(|path| path.to_string_lossy().to_string()).collect(), None),
+            Err(_) => (Vec::new(), Some(format!(
+                "@(Get-ChildItem -LiteralPath {} -Recurse -File -Include *.vhd, *.vhdx, *.avhd, *.avhdx | ForEach-Object {{ $_.FullName }})",
+                ps_quote(&directory)
+            ))),
+        };
+        for path in attached {
+            if !paths.iter().any(|known: &String| known.eq_ignore_ascii_case(&path)) {
+                paths.push(path);
+            }
+        }
+        
+        let mut volumes = Vec::new();
+        let mut unread = Vec::new();
+        for path in paths {
+            match vhdx::inspect(&path) {
+                Ok(info) => volumes.push(volume_json(&path, &info)),
+                Err(_) => unread.push(ps_quote(&path)),
+            }
+        }
+        let pending = match (unread.is_empty(), scan) {
+            (true, None) => None,
+            (true, Some(scan)) => Some(scan),
+            (false, None) => Some(format!("@({})", unread.join(", "))),
+            (false, Some(scan)) => Some(format!("@({}) + {}", unread.join(", "), scan)),
+        };
+        
+        if let Some(paths) = pending {
+            let script = format!(
+                "$ErrorActionPreference = 'Stop'; \
+                 ConvertTo-Json -InputObject @(({}) | Sort-Object -Unique | ForEach-Object {{ Get-VHD -Path $_ -ErrorAction SilentlyContinue }} | ForEach-Object {{ [PSCustomObject]@{{ \
+                   Path = $_.Path; VhdFormat = $_.VhdFormat.ToString(); VhdType = $_.VhdType.ToString(); Size = $_.Size; \
+                   BlockSize = $_.BlockSize; LogicalSectorSize = $_.LogicalSectorSize; PhysicalSectorSize = $_.PhysicalSectorSize; \
+                   DiskIdentifier = $_.DiskIdentifier; ParentPath = $_.ParentPath \
+                 }} }})",
+                paths
+            );
+            
+            let output = self.run_powershell(&script)?;
+            
+            let disks: Vec<Value> = serde_json::from_str(output.trim())
+                .map_err(|e| format!("Failed to parse disks: {}", e))?;
+            for disk in disks {
+                let path = disk["Path"].as_str().unwrap_or("unknown").to_string();
+                let volume_type = disk["VhdType"].as_str().and_then(|t| volume::VolumeType::parse(t).ok());
+                
+                volumes.push(json!({
+                    "id": path.clone(),
+                    "path": path,
+                    "size_mb": disk["Size"].as_i64().unwrap_or(0) / (1024 * 1024),
+                    "format": volume_type.map(legacy_type_name).unwrap_or("Unknown"),
+                    "vhd_format": disk["VhdFormat"].as_str().map(|f| f.to_lowercase()),
+                    "type": volume_type.map(|t| t.as_str()),
+                    "block_size_bytes": disk["BlockSize"],
+                    "logical_sector_size": disk["LogicalSectorSize"],
+                    "physical_sector_size": disk["PhysicalSectorSize"],
+                    "disk_id": disk["DiskIdentifier"],
+                    "parent_path": disk["ParentPath"].as_str().filter(|p| !p.is_empty())
+                }));
+            }
+        }
+        
+        // An attached disk may also turn up in a directory only PowerShell could scan
+        let path_key = |volume: &Value| volume["path"].as_str().unwrap_or("").to_lowercase();
+        volumes.sort_by_key(path_key);
+        volumes.dedup_by(|a, b| path_key(a) == path_key(b));
+        
+        Ok(json!({
+            "success": true,
+            "volumes": volumes
+        }))
+    }
+    
+    fn has_volume(&self, disk_path: String) -> ActionResult {
+        // A locally readable disk answers without starting PowerShell
+        match vhdx::inspect(&disk_path) {
+            Ok(info) => {
+                return Ok(json!({
+                    "success": true,
+                    "exists": true,
+                    "volume": volume_json(&disk_path, &info)
+                }));
+            },
+            Err(_) if std::path::Path::new(&disk_path).is_file() => {
+                return Ok(json!({
+                    "success": true,
+                    "exists": true
+                }));
+            },
+            Err(_) => {},
+        }
+        
+        let script = format!(
+            "Test-Path -LiteralPath {} -PathType Leaf",
+            ps_quote(&disk_path)
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let exists = output.trim().to_lowercase() == "true";
+        
+        Ok(json!({
+            "success": true,
+            "exists": exists
+        }))
+    }
+    
+    fn create_volume(&self, spec: VolumeSpec) -> ActionResult {
+        // New-VHD is the slowest step of provisioning; blank dynamic and differencing
+        // VHDX files are written directly when their directory is reachable from here
+        if let Some(disk) = native_vhdx(&spec)? {
+            disk.create(&spec.path)?;
+            
+            return Ok(json!({
+                "success": true,
+                "id": spec.path.clone(),
+                "path": spec.path,
+                "format": spec.format.extension(),
+                "type": spec.volume_type.as_str(),
+                "size_mb": disk.virtual_size / (1024 * 1024)
+            }));
+        }
+        
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             {} | Out-Null; \
+             Get-VHD -Path {} | Select-Object Path, VhdFormat, VhdType, Size | ConvertTo-Json",
+            spec.new_vhd_command(), ps_quote(&spec.path)
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        // Parse output to get the path
+        let disk_json: Result<Value, _> = serde_json::from_str(output.trim());
+        
+        match disk_json {
+            Ok(disk) => {
+                let path = disk["Path"].as_str().unwrap_or(&spec.path).to_string();
                 
                 Ok(json!({
                     "success": true,
                     "id": path.clone(),
-                    "path": path
+                    "path": path,
+                    "format": spec.format.extension(),
+                    "type": spec.volume_type.as_str(),
+                    "size_mb": disk["Size"].as_i64().map(|size| size / (1024 * 1024))
                 }))
             },
             Err(_) => {
                 // Fallback if we can't parse the JSON
                 Ok(json!({
                     "success": true,
-                    "id": disk_path.clone(),
-                    "path": disk_path
+                    "id": spec.path.clone(),
+                    "path": spec.path,
+                    "format": spec.format.extension(),
+                    "type": spec.volume_type.as_str()
                 }))
             }
         }
@@ -420,184 +1355,1285 @@ impl HyperVExtension {
         }))
     }
     
-    fn attach_volume(&self, worker_name: String, controller_type: String, disk_path: String) -> ActionResult {
+    fn attach_volume(&self, worker: WorkerRef, controller_type: String, disk_path: String) -> ActionResult {
         // Determine controller type - supports IDE, SCSI, or DVD
         let controller_script = match controller_type.to_lowercase().as_str() {
             "ide" => format!(
-                "Add-VMHardDiskDrive -VMName \"{}\" -Path \"{}\" -ControllerType IDE",
-                worker_name, disk_path
+                "{} Add-VMHardDiskDrive -VM $vm -Path \"{}\" -ControllerType IDE",
+                worker.select(), disk_path
+            ),
+            "dvd" => format!(
+                "{} Add-VMDvdDrive -VM $vm -Path \"{}\"",
+                worker.select(), disk_path
+            ),
+            _ => format!(
+                "{} Add-VMHardDiskDrive -VM $vm -Path \"{}\" -ControllerType SCSI",
+                worker.select(), disk_path
             ),
+        };
+        
+        self.run_powershell(&controller_script)?;
+        
+        Ok(json!({
+            "success": true
+        }))
+    }
+    
+    fn detach_volume(&self, worker: WorkerRef, controller_type: String, disk_path: String) -> ActionResult {
+        // Find the disk to remove
+        let script = match controller_type.to_lowercase().as_str() {
             "dvd" => format!(
-                "Add-VMDvdDrive -VMName \"{}\" -Path \"{}\"",
-                worker_name, disk_path
+                "{} $drive = Get-VMDvdDrive -VM $vm | Where-Object {{ $_.Path -eq \"{}\" }}; \
+                 if ($drive) {{ Remove-VMDvdDrive -VMDvdDrive $drive }}",
+                worker.select(), disk_path
             ),
             _ => format!(
-                "Add-VMHardDiskDrive -VMName \"{}\" -Path \"{}\" -ControllerType SCSI",
-                worker_name, disk_path
+                "{} $drive = Get-VMHardDiskDrive -VM $vm | Where-Object {{ $_.Path -eq \"{}\" }}; \
+                 if ($drive) {{ Remove-VMHardDiskDrive -VMHardDiskDrive $drive }}",
+                worker.select(), disk_path
             ),
         };
         
-        self.run_powershell(&controller_script)?;
+        self.run_powershell(&script)?;
+        
+        Ok(json!({
+            "success": true
+        }))
+    }
+    
+    fn create_snapshot(&self, worker: WorkerRef, snapshot_name: String) -> ActionResult {
+        let script = format!(
+            "{} Checkpoint-VM -VM $vm -SnapshotName \"{}\" -Passthru | Select-Object Id | ConvertTo-Json",
+            worker.select(), snapshot_name
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        // Parse the checkpoint ID
+        let snapshot_json: Result<Value, _> = serde_json::from_str(output.trim());
+        
+        match snapshot_json {
+            Ok(snapshot) => {
+                let id = snapshot["Id"].as_str().unwrap_or("unknown").to_string();
+                
+                Ok(json!({
+                    "success": true,
+                    "id": id
+                }))
+            },
+            Err(_) => {
+                // Fallback if we can't parse the JSON
+                Ok(json!({
+                    "success": true,
+                    "id": format!("{}-{}", worker, snapshot_name)
+                }))
+            }
+        }
+    }
+    
+    fn delete_snapshot(&self, worker: WorkerRef, snapshot_name: String) -> ActionResult {
+        let script = format!(
+            "{} Get-VMSnapshot -VM $vm -Name \"{}\" -ErrorAction Stop | Remove-VMSnapshot -IncludeAllChildSnapshots",
+            worker.select(), snapshot_name
+        );
+        
+        self.run_powershell(&script)?;
+        
+        Ok(json!({
+            "success": true
+        }))
+    }
+    
+    fn has_snapshot(&self, worker: WorkerRef, snapshot_name: String) -> ActionResult {
+        let script = format!(
+            "{} Get-VMSnapshot -VM $vm -Name \"{}\" -ErrorAction SilentlyContinue | Measure-Object | Select-Object -ExpandProperty Count",
+            worker.select(), snapshot_name
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let count = output.trim().parse::<i32>().unwrap_or(0);
+        
+        Ok(json!({
+            "success": true,
+            "exists": count > 0
+        }))
+    }
+    
+    fn reboot_worker(&self, worker: WorkerRef) -> ActionResult {
+        let script = format!(
+            "{} Restart-VM -VM $vm -Force",
+            worker.select()
+        );
+        
+        self.run_powershell(&script)?;
+        
+        Ok(json!({
+            "success": true
+        }))
+    }
+    
+    fn configure_networks(&self, worker: WorkerRef, switch_name: String) -> ActionResult {
+        let script = format!(
+            "{} Get-VMNetworkAdapter -VM $vm | Connect-VMNetworkAdapter -SwitchName \"{}\"",
+            worker.select(), switch_name
+        );
+        
+        self.run_powershell(&script)?;
+        
+        Ok(json!({
+            "success": true
+        }))
+    }
+    
+    fn set_worker_metadata(&self, worker: WorkerRef, key: String, value: String) -> ActionResult {
+        // Hyper-V doesn't have a native metadata system, so we'll use Notes
+        let script = format!(
+            "{} \
+             $currentNotes = $vm.Notes; \
+             $newNotes = if ($currentNotes) {{ \"$currentNotes`n{}={}\"; }} else {{ \"{}={}\"; }}; \
+             Set-VM -VM $vm -Notes $newNotes",
+            worker.select(), key, value, key, value
+        );
+        
+        self.run_powershell(&script)?;
+        
+        Ok(json!({
+            "success": true
+        }))
+    }
+    
+    fn snapshot_volume(&self, source_volume_path: String, target_volume_path: String, mode: DiskMode) -> ActionResult {
+        // A differencing child records writes on top of the source, which must then
+        // stay unchanged; a copy is independent of it. Convert-VHD reads through the
+        // source's chain, so a differencing source is flattened into a dynamic copy
+        let create = match mode {
+            DiskMode::Differencing => format!(
+                "New-VHD -Path {} -ParentPath {} -Differencing | Out-Null",
+                ps_quote(&target_volume_path), ps_quote(&source_volume_path)
+            ),
+            DiskMode::Copy => format!(
+                "$type = if ($source.VhdType -eq 'Differencing') {{ 'Dynamic' }} else {{ $source.VhdType }}; \
+                 Convert-VHD -Path {} -DestinationPath {} -VHDType $type",
+                ps_quote(&source_volume_path), ps_quote(&target_volume_path)
+            ),
+        };
+        
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             $source = Get-VHD -Path {}; \
+             if ($source.Attached) {{ throw 'Source disk is in use; detach it or stop its VM first' }}; \
+             if (Test-Path -LiteralPath {}) {{ throw 'Target disk already exists' }}; \
+             {}; \
+             Get-VHD -Path {} | Select-Object Path, VhdType, ParentPath | ConvertTo-Json",
+            ps_quote(&source_volume_path), ps_quote(&target_volume_path), create, ps_quote(&target_volume_path)
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let disk_json: Result<Value, _> = serde_json::from_str(output.trim());
+        let mode = match mode {
+            DiskMode::Differencing => "differencing",
+            DiskMode::Copy => "copy",
+        };
+        
+        match disk_json {
+            Ok(disk) => {
+                let path = disk["Path"].as_str().unwrap_or(&target_volume_path).to_string();
+                
+                Ok(json!({
+                    "success": true,
+                    "id": path.clone(),
+                    "path": path,
+                    "mode": mode,
+                    "parent_path": disk["ParentPath"].as_str().filter(|p| !p.is_empty())
+                }))
+            },
+            Err(_) => {
+                // Fallback if we can't parse the JSON
+                Ok(json!({
+                    "success": true,
+                    "id": target_volume_path.clone(),
+                    "path": target_volume_path,
+                    "mode": mode
+                }))
+            }
+        }
+    }
+    
+    fn convert_volume(&self, conversion: VolumeConversion) -> ActionResult {
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             $source = Get-VHD -Path {}; \
+             if ($source.Attached) {{ throw 'Source disk is in use; detach it or stop its VM first' }}; \
+             if ($source.VhdType -eq 'Differencing' -and -not {}) {{ throw 'Differencing disks must be given a type to convert to' }}; \
+             {}; \
+             $disk = Get-VHD -Path {}; \
+             [PSCustomObject]@{{ Path = $disk.Path; Format = $disk.VhdFormat.ToString(); Type = $disk.VhdType.ToString(); \
+               FileSizeBefore = $source.FileSize; FileSizeAfter = $disk.FileSize }} | ConvertTo-Json",
+            ps_quote(&conversion.source_path),
+            if conversion.volume_type.is_some() { "$true" } else { "$false" },
+            conversion.convert_vhd_command(),
+            ps_quote(&conversion.destination_path)
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let disk: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse converted disk: {}", e))?;
+        let path = disk["Path"].as_str().unwrap_or(&conversion.destination_path).to_string();
+        
+        Ok(json!({
+            "success": true,
+            "id": path.clone(),
+            "path": path,
+            "format": disk["Format"],
+            "type": disk["Type"],
+            "file_size_bytes_before": disk["FileSizeBefore"],
+            "file_size_bytes_after": disk["FileSizeAfter"],
+            "source_deleted": conversion.delete_source
+        }))
+    }
+    
+    fn get_generation(&self, worker: &WorkerRef) -> Result<i64, String> {
+        let script = format!(
+            "{} $vm.Generation",
+            worker.select()
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        output.trim().parse::<i64>()
+            .map_err(|_| format!("Could not determine generation of VM '{}'", worker))
+    }
+    
+    fn set_boot_order(&self, worker: WorkerRef, devices: Value) -> ActionResult {
+        let devices = match devices {
+            Value::Array(items) => items.iter()
+                .map(BootDevice::from_value)
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err("Parameter 'devices' must be an array of boot devices".to_string()),
+        };
+        
+        if devices.is_empty() {
+            return Err("At least one boot device must be specified".to_string());
+        }
+        
+        let generation = self.get_generation(&worker)?;
+        
+        let script = if generation == 1 {
+            // BIOS boots by device class, so every class may only appear once
+            let mut order: Vec<&str> = Vec::new();
+            for device in &devices {
+                let class = device.bios_name();
+                if order.contains(&class) {
+                    return Err(format!(
+                        "Generation 1 VMs boot by device class; '{}' is listed more than once",
+                        class
+                    ));
+                }
+                order.push(class);
+            }
+            
+            // The BIOS startup order must contain all four device classes
+            for class in ["CD", "IDE", "LegacyNetworkAdapter", "Floppy"] {
+                if !order.contains(&class) {
+                    order.push(class);
+                }
+            }
+            
+            format!(
+                "{} Set-VMBios -VM $vm -StartupOrder @({})",
+                worker.select(),
+                order.iter().map(|c| format!("\"{}\"", c)).collect::<Vec<_>>().join(", ")
+            )
+        } else {
+            let mut script = format!("{} $order = @(); ", worker.select());
+            for (index, device) in devices.iter().enumerate() {
+                script.push_str(&format!(
+                    "$dev = {}; \
+                     if (-not $dev) {{ throw \"Boot device {} was not found on the VM\" }}; \
+                     $order += $dev; ",
+                    device.firmware_lookup()?, index
+                ));
+            }
+            script.push_str("Set-VMFirmware -VM $vm -BootOrder $order");
+            script
+        };
+        
+        self.run_powershell(&script)?;
+        
+        Ok(json!({
+            "success": true,
+            "generation": generation,
+            "devices": devices.iter().map(|d| d.to_json()).collect::<Vec<_>>()
+        }))
+    }
+    
+    fn get_boot_order(&self, worker: WorkerRef) -> ActionResult {
+        let script = format!(
+            "{} \
+             if ($vm.Generation -eq 1) {{ \
+               $order = (Get-VMBios -VM $vm).StartupOrder | ForEach-Object {{ \
+                 [PSCustomObject]@{{ Type=$_.ToString(); Path=$null; Name=$null }} \
+               }} \
+             }} else {{ \
+               $order = (Get-VMFirmware -VM $vm).BootOrder | ForEach-Object {{ \
+                 $type = switch($_.BootType.ToString()) {{ \
+                   'Network' {{'network'}} \
+                   'File' {{'file'}} \
+                   default {{ if ($_.Device -is [Microsoft.HyperV.PowerShell.DvdDrive]) {{'dvd'}} else {{'disk'}} }} \
+                 }}; \
+                 [PSCustomObject]@{{ \
+                   Type=$type; \
+                   Path=if ($type -eq 'file') {{ $_.FirmwarePath }} else {{ $_.Device.Path }}; \
+                   Name=if ($type -eq 'network') {{ $_.Device.Name }} else {{ $null }} \
+                 }} \
+               }} \
+             }}; \
+             [PSCustomObject]@{{ Generation=$vm.Generation; Devices=@($order) }} | ConvertTo-Json -Depth 4",
+            worker.select()
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let info: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse boot order: {}", e))?;
+        
+        let generation = info["Generation"].as_i64().unwrap_or(0);
+        let devices: Vec<Value> = info["Devices"].as_array().cloned().unwrap_or_default()
+            .iter()
+            .map(|device| {
+                let path = device["Path"].as_str().map(|p| p.to_string());
+                let name = device["Name"].as_str().map(|n| n.to_string());
+                match device["Type"].as_str().unwrap_or("") {
+                    "IDE" | "disk" => BootDevice::Disk(path).to_json(),
+                    "CD" | "dvd" => BootDevice::Dvd(path).to_json(),
+                    "LegacyNetworkAdapter" | "network" => BootDevice::Network(name).to_json(),
+                    "Floppy" => BootDevice::Floppy.to_json(),
+                    other => json!({ "type": other.to_lowercase(), "path": path }),
+                }
+            })
+            .collect();
+        
+        Ok(json!({
+            "success": true,
+            "generation": generation,
+            "devices": devices
+        }))
+    }
+    
+    fn clone_worker(&self, source: WorkerRef, clone_name: String, snapshot_name: Option<String>, copy_metadata: bool, storage_root: String) -> ActionResult {
+        let check_script = format!("Get-VM -Name {} -ErrorAction SilentlyContinue", ps_quote(&clone_name));
+        let check_output = self.run_powershell(&check_script)?;
+        if !check_output.trim().is_empty() {
+            return Err(format!("VM '{}' already exists", clone_name));
+        }
+        
+        let export = match &snapshot_name {
+            Some(snapshot_name) => format!(
+                "Get-VMSnapshot -VM $vm -Name {} -ErrorAction Stop | Export-VMSnapshot -Path $staging",
+                ps_quote(snapshot_name)
+            ),
+            // Exporting a running VM would capture an inconsistent disk state
+            None => String::from(
+                "if ($vm.State -ne 'Off') { throw \"VM '$($vm.Name)' must be stopped to be cloned; clone a checkpoint instead\" }; \
+                 Export-VM -VM $vm -Path $staging"
+            ),
+        };
+        
+        let destination = Self::worker_file(&storage_root, &clone_name, "");
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             {} \
+             $staging = Join-Path $env:TEMP (\"cpi-clone-\" + [guid]::NewGuid()); \
+             $destination = {}; \
+             $clone = $null; \
+             try {{ \
+               {}; \
+               $config = Get-ChildItem -Path $staging -Recurse -Include *.vmcx | Select-Object -First 1; \
+               if (-not $config) {{ throw \"Export did not produce a VM configuration\" }}; \
+               $clone = Import-VM -Path $config.FullName -Copy -GenerateNewId \
+                 -VirtualMachinePath $destination -SnapshotFilePath $destination -SmartPagingFilePath $destination \
+                 -VhdDestinationPath (Join-Path $destination 'Virtual Hard Disks'); \
+               Rename-VM -VM $clone -NewName {}; \
+               {} \
+               Get-VMNetworkAdapter -VM $clone | Set-VMNetworkAdapter -DynamicMacAddress; \
+               $clone = Get-VM -Id $clone.Id; \
+               [PSCustomObject]@{{ \
+                 Id=$clone.Id.ToString(); \
+                 Name=$clone.Name; \
+                 MacAddresses=@(Get-VMNetworkAdapter -VM $clone | Where-Object {{ $_.MacAddress -ne '000000000000' }} | ForEach-Object {{ $_.MacAddress }}) \
+               }} | ConvertTo-Json \
+             }} catch {{ \
+               if ($clone) {{ Remove-VM -VM $clone -Force -ErrorAction SilentlyContinue; Remove-Item -LiteralPath $destination -Recurse -Force -ErrorAction SilentlyContinue }}; \
+               throw \
+             }} finally {{ \
+               Remove-Item -LiteralPath $staging -Recurse -Force -ErrorAction SilentlyContinue \
+             }}",
+            source.select(), ps_quote(destination.trim_end_matches('\\')), export, ps_quote(&clone_name),
+            if copy_metadata { "" } else { "Set-VM -VM $clone -Notes '';" }
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let clone_info: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse clone info: {}", e))?;
+        
+        // Dynamic addresses are only assigned once the clone starts
+        let mac_addresses = match &clone_info["MacAddresses"] {
+            Value::Array(macs) => macs.clone(),
+            Value::String(mac) => vec![json!(mac)],
+            _ => Vec::new(),
+        };
+        
+        Ok(json!({
+            "success": true,
+            "id": clone_info["Id"].as_str().unwrap_or("unknown"),
+            "name": clone_name,
+            "source": source.to_string(),
+            "snapshot": snapshot_name,
+            "mac_addresses": mac_addresses
+        }))
+    }
+    
+    fn export_worker(&self, worker: WorkerRef, export_path: String, snapshot_name: Option<String>) -> ActionResult {
+        let export = match &snapshot_name {
+            Some(snapshot_name) => format!(
+                "Get-VMSnapshot -VM $vm -Name {} -ErrorAction Stop | Export-VMSnapshot -Path $exportPath",
+                ps_quote(snapshot_name)
+            ),
+            None => String::from("Export-VM -VM $vm -Path $exportPath"),
+        };
+        
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             {} \
+             $exportPath = {}; \
+             $target = Join-Path $exportPath $vm.Name; \
+             if (Test-Path -LiteralPath $target) {{ throw \"Export target '$target' already exists\" }}; \
+             {}; \
+             $config = Get-ChildItem -Path $target -Recurse -Include *.vmcx | Select-Object -First 1; \
+             [PSCustomObject]@{{ Id=$vm.Id.ToString(); Name=$vm.Name; Path=$target; Config=$config.FullName }} | ConvertTo-Json",
+            worker.select(), ps_quote(&export_path), export
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let export_info: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse export info: {}", e))?;
+        
+        Ok(json!({
+            "success": true,
+            "id": export_info["Id"],
+            "name": export_info["Name"],
+            "snapshot": snapshot_name,
+            "path": export_info["Path"],
+            "config_path": export_info["Config"]
+        }))
+    }
+    
+    fn import_worker(&self, import: ImportOptions) -> ActionResult {
+        if let Some(new_name) = &import.new_name {
+            let check_script = format!("Get-VM -Name {} -ErrorAction SilentlyContinue", ps_quote(new_name));
+            if !self.run_powershell(&check_script)?.trim().is_empty() {
+                return Err(format!("VM '{}' already exists", new_name));
+            }
+        }
+        
+        let copy_args = match import.mode {
+            ImportMode::Register => String::from("-Register"),
+            ImportMode::Restore | ImportMode::Copy => format!(
+                "-Copy {} -VirtualMachinePath $destination -SnapshotFilePath $destination -SmartPagingFilePath $destination \
+                 -VhdDestinationPath (Join-Path $destination 'Virtual Hard Disks')",
+                if import.generate_new_id { "-GenerateNewId" } else { "" }
+            ),
+        };
+        
+        // Network adapters whose switch does not exist on this host are reconnected when a switch is given
+        let remap = match &import.switch_name {
+            Some(switch_name) => format!(
+                "$report.Incompatibilities | Where-Object {{ $_.Source -is [Microsoft.HyperV.PowerShell.VMNetworkAdapter] }} | \
+                   ForEach-Object {{ Connect-VMNetworkAdapter -VMNetworkAdapter $_.Source -SwitchName {} }}; \
+                 $report = Compare-VM -CompatibilityReport $report; ",
+                ps_quote(switch_name)
+            ),
+            None => String::new(),
+        };
+        
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             $path = {}; \
+             $newName = {}; \
+             if (Test-Path -LiteralPath $path -PathType Container) {{ \
+               $config = Get-ChildItem -Path $path -Recurse -Include *.vmcx | Select-Object -First 1; \
+               if (-not $config) {{ throw \"No VM configuration found under '$path'\" }}; \
+               $path = $config.FullName \
+             }}; \
+             $destination = {}; \
+             if (-not $destination -and '{}' -ne 'register') {{ \
+               $sourceName = (Compare-VM -Path $path -Register).VM.Name; \
+               $destination = Join-Path {} $(if ($newName) {{ $newName }} else {{ $sourceName }}) \
+             }}; \
+             $report = Compare-VM -Path $path {}; \
+             {}\
+             $incompatibilities = @($report.Incompatibilities | ForEach-Object {{ \
+               [PSCustomObject]@{{ MessageId=$_.MessageId; Message=$_.Message; Source=$_.Source.GetType().Name }} \
+             }}); \
+             if ($incompatibilities.Count -gt 0 -or {}) {{ \
+               [PSCustomObject]@{{ Imported=$false; Path=$path; Incompatibilities=$incompatibilities }} | ConvertTo-Json -Depth 4; \
+               return \
+             }}; \
+             $vm = Import-VM -CompatibilityReport $report; \
+             {} \
+             [PSCustomObject]@{{ Imported=$true; Path=$path; Id=$vm.Id.ToString(); Name=$vm.Name; Incompatibilities=@() }} | ConvertTo-Json -Depth 4",
+            ps_quote(&import.path),
+            ps_quote(import.new_name.as_deref().unwrap_or("")),
+            ps_quote(import.destination.as_deref().unwrap_or("")),
+            import.mode.as_str(),
+            ps_quote(import.storage_root.trim_end_matches(['\\', '/'])),
+            copy_args,
+            remap,
+            if import.check_only { "$true" } else { "$false" },
+            match &import.new_name {
+                Some(_) => String::from("Rename-VM -VM $vm -NewName $newName; $vm = Get-VM -Id $vm.Id;"),
+                None => String::new(),
+            }
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let import_info: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse import info: {}", e))?;
+        
+        let incompatibilities: Vec<Value> = match &import_info["Incompatibilities"] {
+            Value::Array(items) => items.clone(),
+            Value::Object(_) => vec![import_info["Incompatibilities"].clone()],
+            _ => Vec::new(),
+        }
+        .iter()
+        .map(|item| json!({
+            "message_id": item["MessageId"],
+            "message": item["Message"],
+            "source": item["Source"]
+        }))
+        .collect();
+        
+        let imported = import_info["Imported"].as_bool().unwrap_or(false);
+        
+        Ok(json!({
+            // A compatibility check that finds no problems counts as a success
+            "success": imported || (import.check_only && incompatibilities.is_empty()),
+            "imported": imported,
+            "mode": import.mode.as_str(),
+            "config_path": import_info["Path"],
+            "id": import_info["Id"],
+            "name": import_info["Name"],
+            "incompatibilities": incompatibilities
+        }))
+    }
+    
+    fn rename_worker(&self, worker: WorkerRef, new_name: String, rename_files: bool) -> ActionResult {
+        if worker == WorkerRef::Name(new_name.clone()) {
+            return Err("The new name must differ from the current name".to_string());
+        }
+        
+        let check_script = format!("Get-VM -Name {} -ErrorAction SilentlyContinue", ps_quote(&new_name));
+        if !self.run_powershell(&check_script)?.trim().is_empty() {
+            return Err(format!("VM '{}' already exists", new_name));
+        }
+        
+        // Disk files named after the worker, and a folder named after it (the layout
+        // create_worker uses), are renamed before the VM. A differencing disk stores its
+        // parent's path, so renaming a child is safe but renaming a parent would break it
+        let rename_files_script = if rename_files {
+            [chain::VHD_CHILDREN, " \
+                 if ($vm.State -ne 'Off') { throw \"VM '$oldName' must be stopped to rename its files\" }; \
+                 if (@(Get-VMSnapshot -VM $vm).Count) { throw \"VM '$oldName' has checkpoints; remove them before renaming its files\" }; \
+                 $disks = @(Get-VMHardDiskDrive -VM $vm | Where-Object { $_.Path }); \
+                 $renamedFiles = @($disks | Where-Object { [IO.Path]::GetFileNameWithoutExtension($_.Path) -eq $oldName } | ForEach-Object { $_.Path }); \
+                 $renamedFolders = @(@($disks) + @(Get-VMDvdDrive -VM $vm | Where-Object { $_.Path }) | ForEach-Object { Split-Path -Parent $_.Path } | \
+                   Where-Object { (Split-Path -Leaf $_) -eq $oldName -and -not ($vm.ConfigurationLocation -like \"$_*\") } | Sort-Object -Unique); \
+                 foreach ($child in @(Get-VhdChildren @($disks | ForEach-Object { Split-Path -Parent $_.Path } | Sort-Object -Unique))) { \
+                   $parent = $child.parent_path; \
+                   if ($renamedFiles -contains $parent -or @($renamedFolders | Where-Object { $parent -like \"$_\\*\" }).Count) { \
+                     throw \"'$($child.path)' is a differencing disk whose parent '$parent' would be renamed\" \
+                   } \
+                 }; \
+                 foreach ($drive in $disks) { \
+                   $extension = [IO.Path]::GetExtension($drive.Path); \
+                   if ([IO.Path]::GetFileNameWithoutExtension($drive.Path) -eq $oldName) { \
+                     $newPath = Join-Path (Split-Path -Parent $drive.Path) ($newName + $extension); \
+                     if (Test-Path -LiteralPath $newPath) { throw \"'$newPath' already exists\" }; \
+                     Rename-Item -LiteralPath $drive.Path -NewName ($newName + $extension); \
+                     $renamed += [PSCustomObject]@{ Old=$drive.Path; New=$newPath }; \
+                     $repointed += [PSCustomObject]@{ Drive=$drive; Old=$drive.Path }; \
+                     Set-VMHardDiskDrive -VMHardDiskDrive $drive -Path $newPath \
+                   } \
+                 }; \
+                 $drives = @(Get-VMHardDiskDrive -VM $vm) + @(Get-VMDvdDrive -VM $vm | Where-Object { $_.Path }); \
+                 $folders = @($drives | ForEach-Object { Split-Path -Parent $_.Path } | Where-Object { (Split-Path -Leaf $_) -eq $oldName } | Sort-Object -Unique); \
+                 foreach ($folder in $folders) { \
+                   if ($vm.ConfigurationLocation -like \"$folder*\") { $skipped += $folder; continue }; \
+                   $newFolder = Join-Path (Split-Path -Parent $folder) $newName; \
+                   if (Test-Path -LiteralPath $newFolder) { throw \"'$newFolder' already exists\" }; \
+                   Rename-Item -LiteralPath $folder -NewName $newName; \
+                   $renamed += [PSCustomObject]@{ Old=$folder; New=$newFolder }; \
+                   foreach ($drive in $drives) { \
+                     if ($drive.Path -like \"$folder\\*\") { \
+                       $newPath = $newFolder + $drive.Path.Substring($folder.Length); \
+                       $repointed += [PSCustomObject]@{ Drive=$drive; Old=$drive.Path }; \
+                       if ($drive -is [Microsoft.HyperV.PowerShell.DvdDrive]) { Set-VMDvdDrive -VMDvdDrive $drive -Path $newPath } \
+                       else { Set-VMHardDiskDrive -VMHardDiskDrive $drive -Path $newPath } \
+                     } \
+                   } \
+                 }; \
+                 $notes = $vm.Notes; \
+                 foreach ($entry in $renamed) { if ($notes) { $notes = $notes.Replace($entry.Old, $entry.New) } }; \
+                 if ($notes -ne $vm.Notes) { Set-VM -VM $vm -Notes $notes }; "].concat()
+        } else {
+            String::new()
+        };
+        
+        // On failure, files and folders are renamed back (newest first) before the
+        // drives are pointed at their old paths again
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             {} \
+             $oldName = $vm.Name; \
+             $oldNotes = $vm.Notes; \
+             $newName = {}; \
+             $renamed = @(); \
+             $repointed = @(); \
+             $skipped = @(); \
+             try {{ \
+               {}\
+               Rename-VM -VM $vm -NewName $newName \
+             }} catch {{ \
+               $failure = $_; \
+               for ($i = $renamed.Count - 1; $i -ge 0; $i--) {{ \
+                 Rename-Item -LiteralPath $renamed[$i].New -NewName (Split-Path -Leaf $renamed[$i].Old) -ErrorAction Continue \
+               }}; \
+               for ($i = $repointed.Count - 1; $i -ge 0; $i--) {{ \
+                 $entry = $repointed[$i]; \
+                 if ($entry.Drive -is [Microsoft.HyperV.PowerShell.DvdDrive]) {{ Set-VMDvdDrive -VMDvdDrive $entry.Drive -Path $entry.Old -ErrorAction Continue }} \
+                 else {{ Set-VMHardDiskDrive -VMHardDiskDrive $entry.Drive -Path $entry.Old -ErrorAction Continue }} \
+               }}; \
+               if ((Get-VM -Id $vm.Id).Notes -ne $oldNotes) {{ Set-VM -VM $vm -Notes $oldNotes -ErrorAction Continue }}; \
+               throw $failure \
+             }}; \
+             [PSCustomObject]@{{ Id=$vm.Id.ToString(); OldName=$oldName; Renamed=@($renamed); Skipped=@($skipped) }} | ConvertTo-Json -Depth 3",
+            worker.select(), ps_quote(&new_name), rename_files_script
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let info: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse rename result: {}", e))?;
+        
+        let renamed: Vec<Value> = info["Renamed"].as_array().cloned().unwrap_or_default()
+            .iter()
+            .map(|entry| json!({ "old_path": entry["Old"], "new_path": entry["New"] }))
+            .collect();
+        
+        Ok(json!({
+            "success": true,
+            "id": info["Id"].as_str().unwrap_or("unknown"),
+            "old_name": info["OldName"],
+            "name": new_name,
+            "renamed_files": renamed,
+            // Folders that also hold the VM configuration cannot be renamed in place
+            "skipped_folders": info["Skipped"]
+        }))
+    }
+    
+    fn set_automatic_actions(&self, worker: WorkerRef, actions: AutomaticActions) -> ActionResult {
+        if actions.is_empty() {
+            return Err("At least one automatic action setting must be provided".to_string());
+        }
+        
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             {} \
+             Set-VM -VM $vm{}; \
+             $vm = Get-VM -Id $vm.Id; \
+             [PSCustomObject]@{{ \
+               automatic_start_action = $vm.AutomaticStartAction.ToString(); \
+               automatic_start_delay = $vm.AutomaticStartDelay; \
+               automatic_stop_action = $vm.AutomaticStopAction.ToString(); \
+               automatic_critical_error_action = $vm.AutomaticCriticalErrorAction.ToString() \
+             }} | ConvertTo-Json",
+            worker.select(), actions.set_vm_args()
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let settings: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse automatic actions: {}", e))?;
+        
+        Ok(json!({
+            "success": true,
+            "automatic_start_action": settings["automatic_start_action"],
+            "automatic_start_delay": settings["automatic_start_delay"],
+            "automatic_stop_action": settings["automatic_stop_action"],
+            "automatic_critical_error_action": settings["automatic_critical_error_action"]
+        }))
+    }
+    
+    fn get_integration_services(&self, worker: WorkerRef) -> ActionResult {
+        let script = format!(
+            "{} {} \
+             [PSCustomObject]@{{ \
+               services = @(ConvertTo-IntegrationServices $vm); \
+               version = $(if ($vm.IntegrationServicesVersion) {{ $vm.IntegrationServicesVersion.ToString() }} else {{ $null }}); \
+               state = $vm.IntegrationServicesState \
+             }} | ConvertTo-Json -Depth 3",
+            worker.select(), inventory::INTEGRATION_SERVICES
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let status: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse integration services: {}", e))?;
+        
+        Ok(json!({
+            "success": true,
+            "services": status["services"],
+            "version": status["version"],
+            "state": status["state"]
+        }))
+    }
+    
+    fn set_integration_services(&self, worker: WorkerRef, services: Vec<(String, bool)>) -> ActionResult {
+        if services.is_empty() {
+            return Err("No integration services given".to_string());
+        }
+        
+        // Every name is checked against the VM's services before any is changed;
+        // matching by equality keeps wildcard characters in a name literal
+        let names: Vec<String> = services.iter().map(|(service, _)| ps_quote(service)).collect();
+        let steps: Vec<String> = services.iter()
+            .map(|(service, enabled)| format!(
+                "$services | Where-Object {{ $_.Name -eq {} }} | {}-VMIntegrationService",
+                ps_quote(service), if *enabled { "Enable" } else { "Disable" }
+            ))
+            .collect();
+        
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             {} {} \
+             $services = @(Get-VMIntegrationService -VM $vm); \
+             $known = @($services | ForEach-Object {{ $_.Name }}); \
+             foreach ($name in @({})) {{ \
+               if ($known -notcontains $name) {{ throw (\"VM '$($vm.Name)' has no integration service '$name'; available: \" + ($known -join ', ')) }} \
+             }}; \
+             {}; \
+             ConvertTo-Json -InputObject @(ConvertTo-IntegrationServices $vm)",
+            worker.select(), inventory::INTEGRATION_SERVICES, names.join(", "), steps.join("; ")
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let services: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse integration services: {}", e))?;
+        
+        Ok(json!({
+            "success": true,
+            "services": services
+        }))
+    }
+    
+    fn run_in_guest(&self, worker: WorkerRef, script: String, timeout_seconds: i64, credentials: GuestCredentials) -> ActionResult {
+        // Runs in the guest: writes the script to a temporary file and runs it in a
+        // separate PowerShell process so its streams and exit code can be captured
+        const GUEST_RUNNER: &str = "param($encoded, $timeoutMs); \
+             $path = Join-Path $env:TEMP ('cpi-' + [Guid]::NewGuid().ToString() + '.ps1'); \
+             [IO.File]::WriteAllBytes($path, [Convert]::FromBase64String($encoded)); \
+             try { \
+               $psi = New-Object System.Diagnostics.ProcessStartInfo; \
+               $psi.FileName = 'powershell.exe'; \
+               $psi.Arguments = '-NoLogo -NoProfile -NonInteractive -ExecutionPolicy Bypass -File \"' + $path + '\"'; \
+               $psi.UseShellExecute = $false; \
+               $psi.CreateNoWindow = $true; \
+               $psi.RedirectStandardOutput = $true; \
+               $psi.RedirectStandardError = $true; \
+               $process = [System.Diagnostics.Process]::Start($psi); \
+               $stdout = $process.StandardOutput.ReadToEndAsync(); \
+               $stderr = $process.StandardError.ReadToEndAsync(); \
+               $timedOut = -not $process.WaitForExit($timeoutMs); \
+               if ($timedOut) { $process.Kill(); $process.WaitForExit() }; \
+               [PSCustomObject]@{ \
+                 stdout = $stdout.Result; \
+                 stderr = $stderr.Result; \
+                 exit_code = $(if ($timedOut) { $null } else { $process.ExitCode }); \
+                 timed_out = $timedOut \
+               } \
+             } finally { \
+               Remove-Item -Path $path -Force -ErrorAction SilentlyContinue \
+             }";
+        
+        // The script travels base64-encoded (with a BOM so Windows PowerShell reads
+        // it as UTF-8) and the password through the environment
+        let encoded = base64::encode(format!("\u{feff}{}", script).as_bytes());
+        
+        // Allow for PowerShell Direct session setup on top of the script's own timeout
+        let host_timeout = timeout_seconds + 60;
+        
+        let host_script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             {} \
+             if ($vm.State -ne 'Running') {{ throw \"VM '$($vm.Name)' must be running to run commands in it\" }}; \
+             $secret = ConvertTo-SecureString $env:CPI_HYPERV_GUEST_SECRET -AsPlainText -Force; \
+             Remove-Item Env:\\CPI_HYPERV_GUEST_SECRET; \
+             $cred = New-Object System.Management.Automation.PSCredential({}, $secret); \
+             $job = Invoke-Command -VMId $vm.Id -Credential $cred -AsJob -ArgumentList '{}', {} -ScriptBlock {{ {} }}; \
+             if (-not (Wait-Job -Job $job -Timeout {})) {{ \
+               Stop-Job -Job $job; \
+               Remove-Job -Job $job -Force; \
+               [PSCustomObject]@{{ stdout = ''; stderr = ''; exit_code = $null; timed_out = $true }} | ConvertTo-Json; \
+               return \
+             }}; \
+             $result = Receive-Job -Job $job; \
+             Remove-Job -Job $job; \
+             [PSCustomObject]@{{ stdout = $result.stdout; stderr = $result.stderr; exit_code = $result.exit_code; timed_out = $result.timed_out }} | ConvertTo-Json",
+            worker.select(), ps_quote(&credentials.username), encoded, timeout_seconds * 1000, GUEST_RUNNER, host_timeout
+        );
+        
+        let output = self.run_powershell_with_env(&host_script, &[("CPI_HYPERV_GUEST_SECRET", &credentials.password)])?;
+        
+        let result: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse guest command result: {}", e))?;
+        
+        Ok(json!({
+            "success": true,
+            "exit_code": result["exit_code"],
+            "stdout": result["stdout"].as_str().unwrap_or_default(),
+            "stderr": result["stderr"].as_str().unwrap_or_default(),
+            "timed_out": result["timed_out"].as_bool().unwrap_or(false)
+        }))
+    }
+    
+    fn copy_to_guest(&self, worker: WorkerRef, source_path: String, destination_path: String, create_full_path: bool, overwrite: bool) -> ActionResult {
+        // Copy-VMFile goes through the Guest Service Interface, which is disabled by
+        // default; enable it and give the guest a moment to report the service
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             {} \
+             if (-not (Test-Path -LiteralPath {} -PathType Leaf)) {{ throw ('Source file ' + {} + ' does not exist') }}; \
+             $service = Get-VMIntegrationService -VM $vm -Name 'Guest Service Interface'; \
+             $enabled = $false; \
+             if (-not $service.Enabled) {{ \
+               Enable-VMIntegrationService -VM $vm -Name 'Guest Service Interface'; \
+               $enabled = $true; \
+               for ($i = 0; $i -lt 30; $i++) {{ \
+                 if ((Get-VMIntegrationService -VM $vm -Name 'Guest Service Interface').PrimaryStatusDescription -eq 'OK') {{ break }}; \
+                 Start-Sleep -Seconds 1 \
+               }} \
+             }}; \
+             Copy-VMFile -VM $vm -SourcePath {} -DestinationPath {} -FileSource Host{}{}; \
+             [PSCustomObject]@{{ Enabled=$enabled; Size=(Get-Item -LiteralPath {}).Length }} | ConvertTo-Json",
+            worker.select(),
+            ps_quote(&source_path), ps_quote(&source_path),
+            ps_quote(&source_path), ps_quote(&destination_path),
+            if create_full_path { " -CreateFullPath" } else { "" },
+            if overwrite { " -Force" } else { "" },
+            ps_quote(&source_path)
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let result: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse copy result: {}", e))?;
+        
+        Ok(json!({
+            "success": true,
+            "source_path": source_path,
+            "destination_path": destination_path,
+            "size_bytes": result["Size"].as_i64().unwrap_or(0),
+            "guest_service_interface_enabled": result["Enabled"].as_bool().unwrap_or(false)
+        }))
+    }
+    
+    fn guest_kvp_pools(&self, worker: &WorkerRef) -> Result<KvpPools, String> {
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; {} {} Get-KvpItems $vm | ConvertTo-Json",
+            worker.select(), inventory::KVP_ITEMS
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let raw: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse KVP items: {}", e))?;
+        KvpPools::from_json(&raw)
+    }
+    
+    fn get_guest_kvp(&self, worker: WorkerRef) -> ActionResult {
+        let pools = self.guest_kvp_pools(&worker)?;
         
         Ok(json!({
-            "success": true
+            "success": true,
+            "intrinsic": pools.intrinsic,
+            "guest": pools.guest,
+            "host": pools.host
         }))
     }
     
-    fn detach_volume(&self, worker_name: String, controller_type: String, disk_path: String) -> ActionResult {
-        // Find the disk to remove
-        let script = match controller_type.to_lowercase().as_str() {
-            "dvd" => format!(
-                "$drive = Get-VMDvdDrive -VMName \"{}\" | Where-Object {{ $_.Path -eq \"{}\" }}; \
-                 if ($drive) {{ Remove-VMDvdDrive -VMDvdDrive $drive }}",
-                worker_name, disk_path
-            ),
-            _ => format!(
-                "$drive = Get-VMHardDiskDrive -VMName \"{}\" | Where-Object {{ $_.Path -eq \"{}\" }}; \
-                 if ($drive) {{ Remove-VMHardDiskDrive -VMHardDiskDrive $drive }}",
-                worker_name, disk_path
-            ),
-        };
+    fn set_guest_kvp(&self, worker: WorkerRef, items: Vec<(String, Option<String>)>) -> ActionResult {
+        if items.is_empty() {
+            return Err("No KVP items given".to_string());
+        }
         
-        self.run_powershell(&script)?;
+        // Existing host items have to be modified rather than added
+        let host = self.guest_kvp_pools(&worker)?.host;
+        
+        let mut added = Vec::new();
+        let mut modified = Vec::new();
+        let mut removed = Vec::new();
+        let mut calls: Vec<(&str, Vec<String>)> = vec![("AddKvpItems", vec![]), ("ModifyKvpItems", vec![]), ("RemoveKvpItems", vec![])];
+        for (name, data) in items {
+            let exists = host.contains_key(&name);
+            let item = KvpItem { name: name.clone(), data: data.clone().unwrap_or_default(), source: Some(0) };
+            match data {
+                Some(_) if exists => {
+                    calls[1].1.push(item.to_xml());
+                    modified.push(name);
+                },
+                Some(_) => {
+                    calls[0].1.push(item.to_xml());
+                    added.push(name);
+                },
+                None if exists => {
+                    calls[2].1.push(item.to_xml());
+                    removed.push(name);
+                },
+                None => {},
+            }
+        }
+        
+        let invocations: Vec<String> = calls.iter()
+            .filter(|(_, items)| !items.is_empty())
+            .map(|(method, items)| format!(
+                "Invoke-KvpMethod '{}' @({})",
+                method,
+                items.iter().map(|xml| ps_quote(xml)).collect::<Vec<_>>().join(", ")
+            ))
+            .collect();
+        
+        if !invocations.is_empty() {
+            // The host pool methods may complete asynchronously through a job
+            let script = format!(
+                "$ErrorActionPreference = 'Stop'; \
+                 {} \
+                 $ns = 'root\\virtualization\\v2'; \
+                 $cs = Get-CimInstance -Namespace $ns -ClassName Msvm_ComputerSystem -Filter \"Name='$($vm.Id)'\"; \
+                 $vmms = Get-CimInstance -Namespace $ns -ClassName Msvm_VirtualSystemManagementService; \
+                 function Invoke-KvpMethod($method, [string[]]$items) {{ \
+                   $r = Invoke-CimMethod -InputObject $vmms -MethodName $method -Arguments @{{ TargetSystem = $cs; DataItems = $items }}; \
+                   if ($r.ReturnValue -eq 4096) {{ \
+                     $job = $r.Job; \
+                     while ($job.JobState -in 3, 4) {{ Start-Sleep -Milliseconds 200; $job = Get-CimInstance -InputObject $job }}; \
+                     if ($job.JobState -ne 7) {{ throw \"$method failed: $($job.ErrorDescription)\" }} \
+                   }} elseif ($r.ReturnValue -ne 0) {{ throw \"$method failed with return code $($r.ReturnValue)\" }} \
+                 }}; \
+                 {}",
+                worker.select(), invocations.join("; ")
+            );
+            
+            self.run_powershell(&script)?;
+        }
         
         Ok(json!({
-            "success": true
+            "success": true,
+            "added": added,
+            "modified": modified,
+            "removed": removed
         }))
     }
     
-    fn create_snapshot(&self, worker_name: String, snapshot_name: String) -> ActionResult {
+    fn get_worker_screenshot(&self, worker: WorkerRef, width: u32, height: u32, output_path: Option<String>) -> ActionResult {
         let script = format!(
-            "Checkpoint-VM -Name \"{}\" -SnapshotName \"{}\" | Select-Object Id | ConvertTo-Json",
-            worker_name, snapshot_name
+            "$ErrorActionPreference = 'Stop'; \
+             {} \
+             if ($vm.State -ne 'Running') {{ throw \"VM '$($vm.Name)' must be running to capture its console\" }}; \
+             $ns = 'root\\virtualization\\v2'; \
+             $vmms = Get-CimInstance -Namespace $ns -ClassName Msvm_VirtualSystemManagementService; \
+             $settings = Get-CimInstance -Namespace $ns -ClassName Msvm_VirtualSystemSettingData \
+               -Filter \"VirtualSystemIdentifier='$($vm.Id)' AND VirtualSystemType='Microsoft:Hyper-V:System:Realized'\"; \
+             $r = Invoke-CimMethod -InputObject $vmms -MethodName GetVirtualSystemThumbnailImage \
+               -Arguments @{{ TargetSystem = $settings; WidthPixels = [uint16]{}; HeightPixels = [uint16]{} }}; \
+             if ($r.ReturnValue -ne 0) {{ throw \"GetVirtualSystemThumbnailImage failed with return code $($r.ReturnValue)\" }}; \
+             [Convert]::ToBase64String([byte[]]$r.ImageData)",
+            worker.select(), width, height
         );
         
         let output = self.run_powershell(&script)?;
         
-        // Parse the checkpoint ID
-        let snapshot_json: Result<Value, _> = serde_json::from_str(&output.trim());
+        // The framebuffer comes back as raw RGB565 pixels
+        let pixels = base64::decode(output.trim())
+            .map_err(|e| format!("Failed to decode thumbnail data: {}", e))?;
+        let image = png::encode_rgb565(width, height, &pixels)?;
         
-        match snapshot_json {
-            Ok(snapshot) => {
-                let id = snapshot["Id"].as_str().unwrap_or("unknown").to_string();
-                
-                Ok(json!({
-                    "success": true,
-                    "id": id
-                }))
+        let mut result = json!({
+            "success": true,
+            "width": width,
+            "height": height,
+            "format": "png"
+        });
+        match output_path {
+            Some(path) => {
+                std::fs::write(&path, &image)
+                    .map_err(|e| format!("Failed to write screenshot to '{}': {}", path, e))?;
+                result["path"] = json!(path);
             },
-            Err(_) => {
-                // Fallback if we can't parse the JSON
-                Ok(json!({
-                    "success": true,
-                    "id": format!("{}-{}", worker_name, snapshot_name)
-                }))
-            }
+            None => result["image"] = json!(base64::encode(&image)),
         }
+        
+        Ok(result)
     }
     
-    fn delete_snapshot(&self, worker_name: String, snapshot_name: String) -> ActionResult {
+    fn configure_serial_port(&self, worker: WorkerRef, port: i64, pipe_name: Option<String>, enabled: bool) -> ActionResult {
+        // Hyper-V creates the pipe server while the VM runs; a client such as
+        // read_serial_console connects to it
+        let path = if enabled {
+            match pipe_name {
+                Some(name) => format!("\\\\.\\pipe\\{}", name),
+                None => String::new(),
+            }
+        } else {
+            String::new()
+        };
+        
         let script = format!(
-            "Remove-VMSnapshot -VMName \"{}\" -Name \"{}\" -IncludeAllChildSnapshots",
-            worker_name, snapshot_name
+            "$ErrorActionPreference = 'Stop'; \
+             {} \
+             $path = {}; \
+             if ({} -and -not $path) {{ $path = '\\\\.\\pipe\\cpi-hyperv-' + $vm.Id.ToString() + '-com{}' }}; \
+             Set-VMComPort -VM $vm -Number {} -Path $path; \
+             [PSCustomObject]@{{ Path = (Get-VMComPort -VM $vm -Number {}).Path }} | ConvertTo-Json",
+            worker.select(), ps_quote(&path), if enabled { "$true" } else { "$false" }, port, port, port
         );
         
-        self.run_powershell(&script)?;
+        let output = self.run_powershell(&script)?;
+        
+        let result: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse COM port: {}", e))?;
         
         Ok(json!({
-            "success": true
+            "success": true,
+            "port": port,
+            "path": result["Path"].as_str().filter(|p| !p.is_empty())
         }))
     }
     
-    fn has_snapshot(&self, worker_name: String, snapshot_name: String) -> ActionResult {
+    fn read_serial_console(&self, worker: WorkerRef, port: i64, duration_seconds: i64, tail_lines: usize) -> ActionResult {
+        // Upper bound on output collected by one call
+        const MAX_READ_BYTES: usize = 4 * 1024 * 1024;
+        
         let script = format!(
-            "Get-VMSnapshot -VMName \"{}\" -Name \"{}\" -ErrorAction SilentlyContinue | Measure-Object | Select-Object -ExpandProperty Count",
-            worker_name, snapshot_name
+            "$ErrorActionPreference = 'Stop'; \
+             {} \
+             $port = Get-VMComPort -VM $vm -Number {}; \
+             if (-not $port.Path) {{ throw \"COM{} of VM '$($vm.Name)' is not connected to a named pipe; use configure_serial_port\" }}; \
+             if ($vm.State -ne 'Running') {{ throw \"VM '$($vm.Name)' must be running to read its serial console\" }}; \
+             $parts = $port.Path.Split('\\', 5); \
+             $pipe = New-Object System.IO.Pipes.NamedPipeClientStream($parts[2], $parts[4], [System.IO.Pipes.PipeDirection]::InOut, [System.IO.Pipes.PipeOptions]::Asynchronous); \
+             $pipe.Connect(5000); \
+             $data = New-Object System.IO.MemoryStream; \
+             $buffer = New-Object byte[] 65536; \
+             $deadline = [DateTime]::UtcNow.AddSeconds({}); \
+             try {{ \
+               while ($data.Length -lt {}) {{ \
+                 $remaining = [int]($deadline - [DateTime]::UtcNow).TotalMilliseconds; \
+                 if ($remaining -le 0) {{ break }}; \
+                 $read = $pipe.ReadAsync($buffer, 0, $buffer.Length); \
+                 if (-not $read.Wait($remaining)) {{ break }}; \
+                 if ($read.Result -eq 0) {{ break }}; \
+                 $data.Write($buffer, 0, $read.Result) \
+               }} \
+             }} finally {{ $pipe.Dispose() }}; \
+             [PSCustomObject]@{{ Id = $vm.Id.ToString(); Data = [Convert]::ToBase64String($data.ToArray()) }} | ConvertTo-Json",
+            worker.select(), port, port, duration_seconds, MAX_READ_BYTES
         );
         
         let output = self.run_powershell(&script)?;
         
-        let count = output.trim().parse::<i32>().unwrap_or(0);
+        let result: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse serial console output: {}", e))?;
+        let data = base64::decode(result["Data"].as_str().unwrap_or_default())?;
+        let vm_id = result["Id"].as_str().unwrap_or_default();
+        
+        // Output is kept per VM ID so it survives renames
+        let log_dir = self.default_string("console_log_dir")
+            .ok_or_else(|| "No console log directory configured".to_string())?;
+        let max_bytes = self.default_settings.get("console_log_max_bytes").and_then(|v| v.as_u64())
+            .unwrap_or(console::DEFAULT_MAX_BYTES);
+        let max_files = self.default_settings.get("console_log_files").and_then(|v| v.as_u64())
+            .map(|v| v as usize)
+            .unwrap_or(console::DEFAULT_MAX_FILES);
+        let log = ConsoleLog::new(std::path::Path::new(&log_dir).join(vm_id).join(format!("com{}.log", port)))
+            .with_limits(max_bytes, max_files);
+        log.append(&data)
+            .map_err(|e| format!("Failed to write console log '{}': {}", log.path().display(), e))?;
+        let tail = log.tail(tail_lines)
+            .map_err(|e| format!("Failed to read console log '{}': {}", log.path().display(), e))?;
         
         Ok(json!({
             "success": true,
-            "exists": count > 0
+            "port": port,
+            "bytes_read": data.len(),
+            "log_path": log.path().display().to_string(),
+            "output": tail
         }))
     }
     
-    fn reboot_worker(&self, worker_name: String) -> ActionResult {
+    fn resize_volume(&self, disk_path: String, size_mb: Option<i64>) -> ActionResult {
+        // Disks on the IDE controller of a running Generation 1 VM cannot be resized
+        // online; only SCSI-attached VHDX files can
+        let resize = match size_mb {
+            Some(size_mb) => format!("Resize-VHD -Path $path -SizeBytes {}MB", size_mb),
+            None => "Resize-VHD -Path $path -ToMinimumSize".to_string(),
+        };
+        
         let script = format!(
-            "Restart-VM -Name \"{}\" -Force",
-            worker_name
+            "$ErrorActionPreference = 'Stop'; \
+             $path = {}; \
+             $inUse = @(Get-VM | Where-Object {{ $_.State -eq 'Running' }} | Get-VMHardDiskDrive | Where-Object {{ $_.Path -eq $path }}); \
+             foreach ($drive in $inUse) {{ \
+               if ($drive.ControllerType -eq 'IDE') {{ throw \"Disk is attached to the IDE controller of running VM '$($drive.VMName)'; stop the VM before resizing\" }} \
+             }}; \
+             $before = Get-VHD -Path $path; \
+             {}; \
+             $after = Get-VHD -Path $path; \
+             [PSCustomObject]@{{ \
+               SizeBefore = $before.Size; SizeAfter = $after.Size; \
+               FileSizeBefore = $before.FileSize; FileSizeAfter = $after.FileSize; \
+               MinimumSize = $after.MinimumSize \
+             }} | ConvertTo-Json",
+            ps_quote(&disk_path), resize
         );
         
-        self.run_powershell(&script)?;
+        let output = self.run_powershell(&script)?;
+        
+        let sizes: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse disk sizes: {}", e))?;
         
         Ok(json!({
-            "success": true
+            "success": true,
+            "path": disk_path,
+            "size_bytes_before": sizes["SizeBefore"],
+            "size_bytes_after": sizes["SizeAfter"],
+            "file_size_bytes_before": sizes["FileSizeBefore"],
+            "file_size_bytes_after": sizes["FileSizeAfter"],
+            "minimum_size_bytes": sizes["MinimumSize"]
         }))
     }
     
-    fn configure_networks(&self, worker_name: String, switch_name: String) -> ActionResult {
+    fn compact_volume(&self, disk_path: String, mode: &str) -> ActionResult {
+        // Full, Quick and Retrim read the guest file system, so the disk is mounted
+        // read-only while it is optimized
+        let mount = matches!(mode, "Full" | "Quick" | "Retrim");
+        
         let script = format!(
-            "Get-VMNetworkAdapter -VMName \"{}\" | Connect-VMNetworkAdapter -SwitchName \"{}\"",
-            worker_name, switch_name
+            "$ErrorActionPreference = 'Stop'; \
+             $path = {}; \
+             $before = Get-VHD -Path $path; \
+             if ($before.VhdType -eq 'Fixed') {{ throw 'Fixed size disks cannot be compacted' }}; \
+             if ($before.Attached) {{ throw 'Disk is in use; detach it or stop its VM before compacting' }}; \
+             $mounted = $false; \
+             try {{ \
+               if (${}) {{ Mount-VHD -Path $path -ReadOnly -NoDriveLetter; $mounted = $true }}; \
+               Optimize-VHD -Path $path -Mode {} \
+             }} finally {{ \
+               if ($mounted) {{ Dismount-VHD -Path $path }} \
+             }}; \
+             $after = Get-VHD -Path $path; \
+             [PSCustomObject]@{{ FileSizeBefore = $before.FileSize; FileSizeAfter = $after.FileSize }} | ConvertTo-Json",
+            ps_quote(&disk_path), mount, mode
         );
         
-        self.run_powershell(&script)?;
+        let output = self.run_powershell(&script)?;
+        
+        let sizes: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse disk sizes: {}", e))?;
+        let before = sizes["FileSizeBefore"].as_i64().unwrap_or(0);
+        let after = sizes["FileSizeAfter"].as_i64().unwrap_or(0);
         
         Ok(json!({
-            "success": true
+            "success": true,
+            "path": disk_path,
+            "mode": mode,
+            "file_size_bytes_before": before,
+            "file_size_bytes_after": after,
+            "reclaimed_bytes": (before - after).max(0)
         }))
     }
     
-    fn set_worker_metadata(&self, worker_name: String, key: String, value: String) -> ActionResult {
-        // Hyper-V doesn't have a native metadata system, so we'll use Notes
+    fn get_volume_chain(&self, disk_path: String) -> ActionResult {
         let script = format!(
-            "$vm = Get-VM -Name \"{}\"; \
-             $currentNotes = $vm.Notes; \
-             $newNotes = if ($currentNotes) {{ \"$currentNotes`n{}={}\"; }} else {{ \"{}={}\"; }}; \
-             Set-VM -Name \"{}\" -Notes $newNotes",
-            worker_name, key, value, key, value, worker_name
+            "$ErrorActionPreference = 'Stop'; {} \
+             ConvertTo-Json -Depth 3 -InputObject @(Get-VhdChain {})",
+            chain::VHD_CHAIN, ps_quote(&disk_path)
         );
         
-        self.run_powershell(&script)?;
+        let output = self.run_powershell(&script)?;
+        
+        let links: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse disk chain: {}", e))?;
+        let depth = chain::parse_links(&links)?.len();
+        let root = links[depth - 1]["path"].clone();
         
         Ok(json!({
-            "success": true
+            "success": true,
+            "path": disk_path,
+            "root_path": root,
+            "depth": depth,
+            "chain": links
         }))
     }
     
-    fn snapshot_volume(&self, source_volume_path: String, target_volume_path: String) -> ActionResult {
+    fn merge_volume(&self, disk_path: String, destination_path: Option<String>) -> ActionResult {
+        // Look at the chain and every disk that might depend on it before merging
         let script = format!(
-            "Convert-VHD -Path \"{}\" -DestinationPath \"{}\" -VHDType Differencing; \
-             Get-VHD -Path \"{}\" | Select-Object Path | ConvertTo-Json",
-            source_volume_path, target_volume_path, target_volume_path
+            "$ErrorActionPreference = 'Stop'; {} {} \
+             $chain = @(Get-VhdChain {}); \
+             $dirs = @($chain | ForEach-Object {{ Split-Path -Parent $_.path }} | Sort-Object -Unique); \
+             [PSCustomObject]@{{ chain = $chain; children = @(Get-VhdChildren $dirs) }} | ConvertTo-Json -Depth 4",
+            chain::VHD_CHAIN, chain::VHD_CHILDREN, ps_quote(&disk_path)
         );
         
         let output = self.run_powershell(&script)?;
         
-        let disk_json: Result<Value, _> = serde_json::from_str(&output.trim());
+        let disks: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse disk chain: {}", e))?;
+        let links = chain::parse_links(&disks["chain"])?;
+        let children = chain::parse_children(&disks["children"]);
+        let plan = chain::plan_merge(&links, destination_path.as_deref(), &children)?;
         
-        match disk_json {
-            Ok(disk) => {
-                let path = disk["Path"].as_str().unwrap_or(&target_volume_path).to_string();
-                
-                Ok(json!({
-                    "success": true,
-                    "id": path.clone(),
-                    "path": path
-                }))
-            },
-            Err(_) => {
-                // Fallback if we can't parse the JSON
-                Ok(json!({
-                    "success": true,
-                    "id": target_volume_path.clone(),
-                    "path": target_volume_path
-                }))
-            }
-        }
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             Merge-VHD -Path {} -DestinationPath {}; \
+             Get-VHD -Path {} | Select-Object Path, @{{Name='Type';Expression={{$_.VhdType.ToString()}}}}, FileSize | ConvertTo-Json",
+            ps_quote(&links[0].path), ps_quote(&plan.destination), ps_quote(&plan.destination)
+        );
+        
+        let output = self.run_powershell(&script)?;
+        
+        let merged: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse merged disk: {}", e))?;
+        
+        Ok(json!({
+            "success": true,
+            "path": plan.destination,
+            "type": merged["Type"],
+            "file_size_bytes": merged["FileSize"],
+            "removed_paths": plan.removed
+        }))
+    }
+}
+
+impl Default for HyperVExtension {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -610,6 +2646,10 @@ impl CpiExtension for HyperVExtension {
         &self.provider_type
     }
     
+    fn default_settings(&self) -> HashMap<String, Value> {
+        self.default_settings.clone()
+    }
+    
     fn list_actions(&self) -> Vec<String> {
         vec![
             "test_install".to_string(),
@@ -631,7 +2671,30 @@ impl CpiExtension for HyperVExtension {
             "reboot_worker".to_string(),
             "configure_networks".to_string(),
             "set_worker_metadata".to_string(),
-            "snapshot_volume".to_string()
+            "snapshot_volume".to_string(),
+            "set_boot_order".to_string(),
+            "get_boot_order".to_string(),
+            "register_template".to_string(),
+            "list_templates".to_string(),
+            "clone_worker".to_string(),
+            "export_worker".to_string(),
+            "import_worker".to_string(),
+            "rename_worker".to_string(),
+            "set_automatic_actions".to_string(),
+            "get_integration_services".to_string(),
+            "set_integration_services".to_string(),
+            "run_in_guest".to_string(),
+            "copy_to_guest".to_string(),
+            "get_guest_kvp".to_string(),
+            "set_guest_kvp".to_string(),
+            "get_worker_screenshot".to_string(),
+            "configure_serial_port".to_string(),
+            "read_serial_console".to_string(),
+            "resize_volume".to_string(),
+            "compact_volume".to_string(),
+            "convert_volume".to_string(),
+            "get_volume_chain".to_string(),
+            "merge_volume".to_string()
         ]
     }
     
@@ -644,52 +2707,90 @@ impl CpiExtension for HyperVExtension {
             }),
             "list_workers" => Some(ActionDefinition {
                 name: "list_workers".to_string(),
-                description: "List all virtual machines".to_string(),
-                parameters: vec![],
+                description: "List virtual machines, optionally filtered, sorted and paginated".to_string(),
+                parameters: vec![
+                    param!("state", "Only list workers in these states (comma-separated, e.g. 'running,stopped')", ParamType::String, optional),
+                    param!("name", "Only list workers whose name matches this wildcard pattern", ParamType::String, optional),
+                    param!("metadata", "Only list workers whose metadata has these key/value pairs (null matches any value)", ParamType::Json, optional),
+                    param!("generation", "Only list workers of this generation (1 or 2)", ParamType::Integer, optional),
+                    param!("switch_name", "Only list workers connected to this virtual switch", ParamType::String, optional),
+                    param!("fields", "Fields to return: name, id, state, generation, memory_mb, cpu_count, uptime_seconds, switches, metadata", ParamType::Json, optional, json!(["name", "id", "state"])),
+                    param!("sort_by", "Field to sort by", ParamType::String, optional, json!("name")),
+                    param!("sort_order", "Sort order ('asc' or 'desc')", ParamType::String, optional, json!("asc")),
+                    param!("limit", "Maximum number of workers per page", ParamType::Integer, optional),
+                    param!("cursor", "The next_cursor of the previous page", ParamType::String, optional),
+                    param!("detail", "Return every get_worker field for each worker", ParamType::Boolean, optional, json!(false)),
+                ],
             }),
             "create_worker" => Some(ActionDefinition {
                 name: "create_worker".to_string(),
                 description: "Create a new virtual machine".to_string(),
                 parameters: vec![
                     param!("worker_name", "Name of the VM to create", ParamType::String, required),
-                    param!("memory_mb", "Memory in MB", ParamType::Integer, optional, json!(2048)),
-                    param!("cpu_count", "Number of CPUs", ParamType::Integer, optional, json!(2)),
-                    param!("generation", "VM generation (1 or 2)", ParamType::Integer, optional, json!(2)),
-                    param!("switch_name", "Network switch to connect to", ParamType::String, optional, json!("Default Switch")),
+                    param!("template", "Name of a worker template to start from", ParamType::String, optional),
+                    param!("templates", "Named templates from the host configuration, added to the built-in ones", ParamType::Json, optional),
+                    param!("memory_mb", "Memory in MB (from the template, or 2048)", ParamType::Integer, optional),
+                    param!("cpu_count", "Number of CPUs (from the template, or 2)", ParamType::Integer, optional),
+                    param!("generation", "VM generation, 1 or 2 (from the template, or 2)", ParamType::Integer, optional),
+                    param!("switch_name", "Network switch to connect to (from the template, or Default Switch)", ParamType::String, optional),
+                    param!("base_image", "Parent VHD/VHDX to create the OS disk from", ParamType::String, optional),
+                    param!("disk_size_mb", "Size of the OS disk in MB", ParamType::Integer, optional),
+                    param!("disk_mode", "How the OS disk is created from the base image: differencing (the default) or copy", ParamType::String, optional),
+                    param!("storage_root", "Directory under which the worker's disk folder is created", ParamType::String, optional),
+                    param!("vlan_id", "Access VLAN ID for the network adapter", ParamType::Integer, optional),
+                    param!("secure_boot", "Enable or disable secure boot (Generation 2 only)", ParamType::Boolean, optional),
+                    param!("secure_boot_template", "Secure boot template, e.g. MicrosoftUEFICertificateAuthority", ParamType::String, optional),
+                    param!("integration_services", "Integration services to enable or disable, e.g. {\"Guest Service Interface\": true}", ParamType::Json, optional),
+                    param!("user_data", "cloud-init user-data delivered on a NoCloud seed ISO", ParamType::String, optional),
+                    param!("meta_data", "cloud-init meta-data (generated from the VM ID and name if omitted)", ParamType::String, optional),
+                    param!("network_config", "cloud-init network-config", ParamType::String, optional),
+                    param!("unattend", "Windows answer file settings (computer_name, product_key, time_zone, locale, organization, owner, first_logon_commands, auto_logon, architecture)", ParamType::Json, optional),
+                    param!("unattend_delivery", "How unattend.xml is delivered (iso or inject)", ParamType::String, optional, json!("iso")),
+                    param!("automatic_start_action", "Action when the host starts (Nothing, StartIfRunning or Start)", ParamType::String, optional),
+                    param!("automatic_start_delay", "Seconds to wait after host start-up before starting the VM", ParamType::Integer, optional),
+                    param!("automatic_stop_action", "Action when the host shuts down (Save, TurnOff or ShutDown)", ParamType::String, optional),
+                    param!("automatic_critical_error_action", "Action on a critical storage error (Pause or None)", ParamType::String, optional),
                 ],
             }),
             "delete_worker" => Some(ActionDefinition {
                 name: "delete_worker".to_string(),
                 description: "Delete a virtual machine".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM to delete", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM to delete (used when worker_id is not given)", ParamType::String, optional),
                 ],
             }),
             "get_worker" => Some(ActionDefinition {
                 name: "get_worker".to_string(),
                 description: "Get information about a virtual machine".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
                 ],
             }),
             "has_worker" => Some(ActionDefinition {
                 name: "has_worker".to_string(),
                 description: "Check if a virtual machine exists".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
                 ],
             }),
             "start_worker" => Some(ActionDefinition {
                 name: "start_worker".to_string(),
                 description: "Start a virtual machine".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM to start", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM to start (used when worker_id is not given)", ParamType::String, optional),
                 ],
             }),
             "get_volumes" => Some(ActionDefinition {
                 name: "get_volumes".to_string(),
-                description: "List all virtual disk volumes".to_string(),
-                parameters: vec![],
+                description: "List the virtual disk volumes under a directory, optionally with those attached to VMs".to_string(),
+                parameters: vec![
+                    param!("directory", "Directory searched recursively for disks; defaults to the storage_root setting", ParamType::String, optional),
+                    param!("include_attached", "Also list the disks attached to any VM, wherever they are stored", ParamType::Boolean, optional),
+                ],
             }),
             "has_volume" => Some(ActionDefinition {
                 name: "has_volume".to_string(),
@@ -702,8 +2803,15 @@ impl CpiExtension for HyperVExtension {
                 name: "create_volume".to_string(),
                 description: "Create a new disk volume".to_string(),
                 parameters: vec![
-                    param!("disk_path", "Path for the new disk", ParamType::String, required),
-                    param!("size_mb", "Size in MB", ParamType::Integer, required),
+                    param!("disk_path", "Path for the new disk (.vhd, .vhdx or .vhds)", ParamType::String, required),
+                    param!("size_mb", "Size in MB; required unless the disk is differencing or copied from source_disk", ParamType::Integer, optional),
+                    param!("format", "Disk format (vhd, vhdx or vhds); must match the path's extension", ParamType::String, optional),
+                    param!("type", "Disk type (fixed, dynamic or differencing); differencing when parent_path is given, otherwise dynamic", ParamType::String, optional),
+                    param!("parent_path", "Parent disk of a differencing disk", ParamType::String, optional),
+                    param!("logical_sector_size", "Logical sector size in bytes (512 or 4096)", ParamType::Integer, optional),
+                    param!("physical_sector_size", "Physical sector size in bytes (512 or 4096)", ParamType::Integer, optional),
+                    param!("block_size_mb", "Block size in MB of a dynamic or differencing disk", ParamType::Integer, optional),
+                    param!("source_disk", "Number of a physical disk to copy into the new disk", ParamType::Integer, optional),
                 ],
             }),
             "delete_volume" => Some(ActionDefinition {
@@ -717,7 +2825,8 @@ impl CpiExtension for HyperVExtension {
                 name: "attach_volume".to_string(),
                 description: "Attach a disk to a VM".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
                     param!("controller_type", "Type of controller (IDE, SCSI, DVD)", ParamType::String, optional, json!("SCSI")),
                     param!("disk_path", "Path to the disk", ParamType::String, required),
                 ],
@@ -726,7 +2835,8 @@ impl CpiExtension for HyperVExtension {
                 name: "detach_volume".to_string(),
                 description: "Detach a disk from a VM".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
                     param!("controller_type", "Type of controller (IDE, SCSI, DVD)", ParamType::String, optional, json!("SCSI")),
                     param!("disk_path", "Path to the disk", ParamType::String, required),
                 ],
@@ -735,7 +2845,8 @@ impl CpiExtension for HyperVExtension {
                 name: "create_snapshot".to_string(),
                 description: "Create a snapshot of a VM".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
                     param!("snapshot_name", "Name of the snapshot", ParamType::String, required),
                 ],
             }),
@@ -743,7 +2854,8 @@ impl CpiExtension for HyperVExtension {
                 name: "delete_snapshot".to_string(),
                 description: "Delete a snapshot of a VM".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
                     param!("snapshot_name", "Name of the snapshot", ParamType::String, required),
                 ],
             }),
@@ -751,7 +2863,8 @@ impl CpiExtension for HyperVExtension {
                 name: "has_snapshot".to_string(),
                 description: "Check if a snapshot exists".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
                     param!("snapshot_name", "Name of the snapshot", ParamType::String, required),
                 ],
             }),
@@ -759,14 +2872,16 @@ impl CpiExtension for HyperVExtension {
                 name: "reboot_worker".to_string(),
                 description: "Reboot a VM".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
                 ],
             }),
             "configure_networks" => Some(ActionDefinition {
                 name: "configure_networks".to_string(),
                 description: "Configure network settings for a VM".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
                     param!("switch_name", "Name of the virtual switch", ParamType::String, required),
                 ],
             }),
@@ -774,17 +2889,242 @@ impl CpiExtension for HyperVExtension {
                 name: "set_worker_metadata".to_string(),
                 description: "Set metadata for a VM".to_string(),
                 parameters: vec![
-                    param!("worker_name", "Name of the VM", ParamType::String, required),
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
                     param!("key", "Metadata key", ParamType::String, required),
                     param!("value", "Metadata value", ParamType::String, required),
                 ],
             }),
             "snapshot_volume" => Some(ActionDefinition {
                 name: "snapshot_volume".to_string(),
-                description: "Clone a disk volume".to_string(),
+                description: "Snapshot a disk volume as a differencing child or a full copy".to_string(),
                 parameters: vec![
                     param!("source_volume_path", "Path to the source disk", ParamType::String, required),
-                    param!("target_volume_path", "Path for the cloned disk", ParamType::String, required),
+                    param!("target_volume_path", "Path for the new disk", ParamType::String, required),
+                    param!("mode", "differencing (a child disk backed by the source) or copy (an independent copy)", ParamType::String, optional, json!("differencing")),
+                ],
+            }),
+            "set_boot_order" => Some(ActionDefinition {
+                name: "set_boot_order".to_string(),
+                description: "Set the boot device order of a VM".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("devices", "Ordered list of boot devices, e.g. [{\"type\": \"disk\", \"path\": \"...\"}, {\"type\": \"dvd\"}, {\"type\": \"network\", \"name\": \"...\"}]", ParamType::Json, required),
+                ],
+            }),
+            "register_template" => Some(ActionDefinition {
+                name: "register_template".to_string(),
+                description: "Register a named worker template".to_string(),
+                parameters: vec![
+                    param!("name", "Name of the template", ParamType::String, required),
+                    param!("template", "Template settings (memory_mb, cpu_count, generation, switch_name, vlan_id, base_image, disk_size_mb, disk_mode, secure_boot, secure_boot_template, integration_services)", ParamType::Json, required),
+                ],
+            }),
+            "list_templates" => Some(ActionDefinition {
+                name: "list_templates".to_string(),
+                description: "List configured and registered worker templates".to_string(),
+                parameters: vec![
+                    param!("templates", "Named templates from the host configuration, added to the built-in ones", ParamType::Json, optional),
+                ],
+            }),
+            "get_boot_order" => Some(ActionDefinition {
+                name: "get_boot_order".to_string(),
+                description: "Get the boot device order of a VM".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                ],
+            }),
+            "clone_worker" => Some(ActionDefinition {
+                name: "clone_worker".to_string(),
+                description: "Clone a stopped VM or one of its checkpoints into a new VM".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM to clone (used when worker_id is not given)", ParamType::String, optional),
+                    param!("clone_name", "Name of the new VM", ParamType::String, required),
+                    param!("snapshot_name", "Checkpoint to clone instead of the current state", ParamType::String, optional),
+                    param!("copy_metadata", "Copy the source VM's metadata to the clone", ParamType::Boolean, optional, json!(false)),
+                    param!("storage_root", "Directory under which the clone's folder is created", ParamType::String, optional),
+                ],
+            }),
+            "export_worker" => Some(ActionDefinition {
+                name: "export_worker".to_string(),
+                description: "Export a VM, or one of its checkpoints, to a directory".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("export_path", "Directory to export into; a folder named after the VM is created", ParamType::String, required),
+                    param!("snapshot_name", "Checkpoint to export instead of the current state", ParamType::String, optional),
+                ],
+            }),
+            "import_worker" => Some(ActionDefinition {
+                name: "import_worker".to_string(),
+                description: "Import an exported VM".to_string(),
+                parameters: vec![
+                    param!("path", "Exported VM directory or .vmcx configuration file", ParamType::String, required),
+                    param!("mode", "Import mode: register (in place), restore (copy, keep ID) or copy (copy, new ID)", ParamType::String, optional, json!("copy")),
+                    param!("generate_new_id", "Give the imported VM a new ID (copy mode only)", ParamType::Boolean, optional, json!(true)),
+                    param!("new_name", "Rename the VM after import", ParamType::String, optional),
+                    param!("destination", "Directory for the VM's files in copy and restore modes", ParamType::String, optional),
+                    param!("storage_root", "Directory under which the VM's folder is created when no destination is given", ParamType::String, optional),
+                    param!("switch_name", "Switch to connect adapters whose switch is missing on this host", ParamType::String, optional),
+                    param!("check_only", "Only report incompatibilities without importing", ParamType::Boolean, optional, json!(false)),
+                ],
+            }),
+            "rename_worker" => Some(ActionDefinition {
+                name: "rename_worker".to_string(),
+                description: "Rename a VM".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Current name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("new_name", "New name of the VM", ParamType::String, required),
+                    param!("rename_files", "Also rename disk files and the folder named after the VM (VM must be stopped and have no checkpoints; refused when another disk uses a renamed file as its parent)", ParamType::Boolean, optional, json!(false)),
+                ],
+            }),
+            "set_automatic_actions" => Some(ActionDefinition {
+                name: "set_automatic_actions".to_string(),
+                description: "Set what a VM does when the host starts, shuts down or hits a critical error".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("automatic_start_action", "Action when the host starts (Nothing, StartIfRunning or Start)", ParamType::String, optional),
+                    param!("automatic_start_delay", "Seconds to wait after host start-up before starting the VM", ParamType::Integer, optional),
+                    param!("automatic_stop_action", "Action when the host shuts down (Save, TurnOff or ShutDown)", ParamType::String, optional),
+                    param!("automatic_critical_error_action", "Action on a critical storage error (Pause or None)", ParamType::String, optional),
+                ],
+            }),
+            "get_integration_services" => Some(ActionDefinition {
+                name: "get_integration_services".to_string(),
+                description: "Get the integration services of a VM with their status and version".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                ],
+            }),
+            "set_integration_services" => Some(ActionDefinition {
+                name: "set_integration_services".to_string(),
+                description: "Enable or disable integration services of a VM".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("integration_services", "Integration services to enable or disable, e.g. {\"Guest Service Interface\": true}", ParamType::Json, required),
+                ],
+            }),
+            "run_in_guest" => Some(ActionDefinition {
+                name: "run_in_guest".to_string(),
+                description: "Run a PowerShell script inside a running Windows VM over PowerShell Direct".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("script", "PowerShell script to run in the guest", ParamType::String, required),
+                    param!("timeout_seconds", "Seconds after which the script is stopped", ParamType::Integer, optional, json!(300)),
+                    param!("username", "Guest account to run the script as", ParamType::String, optional),
+                    param!("password", "Password of the guest account", ParamType::String, optional),
+                ],
+            }),
+            "copy_to_guest" => Some(ActionDefinition {
+                name: "copy_to_guest".to_string(),
+                description: "Copy a file from the host into a running VM through the Guest Service Interface".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("source_path", "Path of the file on the host", ParamType::String, required),
+                    param!("destination_path", "Path of the file in the guest", ParamType::String, required),
+                    param!("create_full_path", "Create missing directories of the destination path", ParamType::Boolean, optional, json!(true)),
+                    param!("overwrite", "Replace the destination file if it exists", ParamType::Boolean, optional, json!(false)),
+                ],
+            }),
+            "get_guest_kvp" => Some(ActionDefinition {
+                name: "get_guest_kvp".to_string(),
+                description: "Read the KVP exchange items of a VM (guest intrinsic, guest and host pools)".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                ],
+            }),
+            "set_guest_kvp" => Some(ActionDefinition {
+                name: "set_guest_kvp".to_string(),
+                description: "Add, modify or remove items in the host KVP pool of a VM".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("items", "Object of key to value; a null value removes the key", ParamType::Json, required),
+                ],
+            }),
+            "get_worker_screenshot" => Some(ActionDefinition {
+                name: "get_worker_screenshot".to_string(),
+                description: "Capture the console of a running VM as a PNG image".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("width", "Width of the image in pixels", ParamType::Integer, optional, json!(1024)),
+                    param!("height", "Height of the image in pixels", ParamType::Integer, optional, json!(768)),
+                    param!("output_path", "File to write the PNG to; the image is returned base64-encoded when omitted", ParamType::String, optional),
+                ],
+            }),
+            "configure_serial_port" => Some(ActionDefinition {
+                name: "configure_serial_port".to_string(),
+                description: "Connect a COM port of a VM to a named pipe".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("port", "COM port number (1 or 2)", ParamType::Integer, optional, json!(1)),
+                    param!("pipe_name", "Name of the pipe under \\\\.\\pipe\\; derived from the VM ID when omitted", ParamType::String, optional),
+                    param!("enabled", "Connect the port; false disconnects it", ParamType::Boolean, optional, json!(true)),
+                ],
+            }),
+            "read_serial_console" => Some(ActionDefinition {
+                name: "read_serial_console".to_string(),
+                description: "Read serial console output of a running VM into its console log and return the latest lines".to_string(),
+                parameters: vec![
+                    param!("worker_id", "ID (GUID) of the VM", ParamType::String, optional),
+                    param!("worker_name", "Name of the VM (used when worker_id is not given)", ParamType::String, optional),
+                    param!("port", "COM port number (1 or 2)", ParamType::Integer, optional, json!(1)),
+                    param!("duration_seconds", "How long to collect output from the pipe", ParamType::Integer, optional, json!(5)),
+                    param!("tail_lines", "Number of log lines to return", ParamType::Integer, optional, json!(100)),
+                ],
+            }),
+            "resize_volume" => Some(ActionDefinition {
+                name: "resize_volume".to_string(),
+                description: "Grow or shrink a virtual disk".to_string(),
+                parameters: vec![
+                    param!("disk_path", "Path to the disk", ParamType::String, required),
+                    param!("size_mb", "New virtual size in MB", ParamType::Integer, optional),
+                    param!("to_minimum", "Shrink the disk to its minimum size instead", ParamType::Boolean, optional, json!(false)),
+                ],
+            }),
+            "compact_volume" => Some(ActionDefinition {
+                name: "compact_volume".to_string(),
+                description: "Reclaim unused space in a dynamic or differencing disk".to_string(),
+                parameters: vec![
+                    param!("disk_path", "Path to the disk", ParamType::String, required),
+                    param!("mode", "Optimize-VHD mode (Full, Quick, Retrim, Pretrimmed or Prezeroed)", ParamType::String, optional, json!("Full")),
+                ],
+            }),
+            "convert_volume" => Some(ActionDefinition {
+                name: "convert_volume".to_string(),
+                description: "Convert a disk between VHD and VHDX, or between fixed and dynamic".to_string(),
+                parameters: vec![
+                    param!("source_path", "Path to the disk to convert", ParamType::String, required),
+                    param!("destination_path", "Path for the converted disk; its extension selects the format", ParamType::String, required),
+                    param!("type", "Type of the converted disk (fixed or dynamic); the source type is kept when omitted", ParamType::String, optional),
+                    param!("delete_source", "Delete the source disk after a successful conversion", ParamType::Boolean, optional, json!(false)),
+                ],
+            }),
+            "get_volume_chain" => Some(ActionDefinition {
+                name: "get_volume_chain".to_string(),
+                description: "List a disk and its differencing parents down to the root disk".to_string(),
+                parameters: vec![
+                    param!("disk_path", "Path to the disk", ParamType::String, required),
+                ],
+            }),
+            "merge_volume" => Some(ActionDefinition {
+                name: "merge_volume".to_string(),
+                description: "Merge a differencing disk into its parent or a chosen ancestor".to_string(),
+                parameters: vec![
+                    param!("disk_path", "Path to the differencing disk to merge", ParamType::String, required),
+                    param!("destination_path", "Ancestor to merge into; the direct parent when omitted", ParamType::String, optional),
                 ],
             }),
             _ => None,
@@ -794,97 +3134,320 @@ impl CpiExtension for HyperVExtension {
     fn execute_action(&self, action: &str, params: &HashMap<String, Value>) -> ActionResult {
         match action {
             "test_install" => self.test_install(),
-            "list_workers" => self.list_workers(),
+            "list_workers" => {
+                let query = WorkerQuery::from_params(params)?;
+                self.list_workers(query)
+            },
             "create_worker" => {
                 let worker_name = validation::extract_string(params, "worker_name")?;
-                let memory_mb = validation::extract_int_opt(params, "memory_mb")?.unwrap_or(2048);
-                let cpu_count = validation::extract_int_opt(params, "cpu_count")?.unwrap_or(2);
-                let generation = validation::extract_int_opt(params, "generation")?.unwrap_or(2);
-                let switch_name = validation::extract_string_opt(params, "switch_name")?.unwrap_or_else(|| "Default Switch".to_string());
+                let spec = self.resolve_worker_spec(params)?;
                 
-                self.create_worker(worker_name, memory_mb, cpu_count, generation, switch_name)
+                self.create_worker(worker_name, spec)
             },
             "delete_worker" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
-                self.delete_worker(worker_name)
+                let worker = WorkerRef::from_params(params)?;
+                self.delete_worker(worker)
             },
             "get_worker" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
-                self.get_worker(worker_name)
+                let worker = WorkerRef::from_params(params)?;
+                self.get_worker(worker)
             },
             "has_worker" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
-                self.has_worker(worker_name)
+                let worker = WorkerRef::from_params(params)?;
+                self.has_worker(worker)
             },
             "start_worker" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
-                self.start_worker(worker_name)
+                let worker = WorkerRef::from_params(params)?;
+                self.start_worker(worker)
+            },
+            "get_volumes" => {
+                let directory = validation::extract_string_opt(params, "directory")?
+                    .or_else(|| self.default_string("storage_root"))
+                    .ok_or_else(|| "No storage root configured for disks".to_string())?;
+                
+                let include_attached = extract_bool_opt(params, "include_attached")?.unwrap_or(false);
+                
+                self.get_volumes(directory, include_attached)
             },
-            "get_volumes" => self.get_volumes(),
             "has_volume" => {
                 let disk_path = validation::extract_string(params, "disk_path")?;
                 self.has_volume(disk_path)
             },
             "create_volume" => {
-                let disk_path = validation::extract_string(params, "disk_path")?;
-                let size_mb = validation::extract_int(params, "size_mb")?;
-                self.create_volume(disk_path, size_mb)
+                let spec = VolumeSpec::from_params(params)?;
+                self.create_volume(spec)
             },
             "delete_volume" => {
                 let disk_path = validation::extract_string(params, "disk_path")?;
                 self.delete_volume(disk_path)
             },
             "attach_volume" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
+                let worker = WorkerRef::from_params(params)?;
                 let controller_type = validation::extract_string_opt(params, "controller_type")?.unwrap_or_else(|| "SCSI".to_string());
                 let disk_path = validation::extract_string(params, "disk_path")?;
                 
-                self.attach_volume(worker_name, controller_type, disk_path)
+                self.attach_volume(worker, controller_type, disk_path)
             },
             "detach_volume" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
+                let worker = WorkerRef::from_params(params)?;
                 let controller_type = validation::extract_string_opt(params, "controller_type")?.unwrap_or_else(|| "SCSI".to_string());
                 let disk_path = validation::extract_string(params, "disk_path")?;
                 
-                self.detach_volume(worker_name, controller_type, disk_path)
+                self.detach_volume(worker, controller_type, disk_path)
             },
             "create_snapshot" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
+                let worker = WorkerRef::from_params(params)?;
                 let snapshot_name = validation::extract_string(params, "snapshot_name")?;
-                self.create_snapshot(worker_name, snapshot_name)
+                self.create_snapshot(worker, snapshot_name)
             },
             "delete_snapshot" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
+                let worker = WorkerRef::from_params(params)?;
                 let snapshot_name = validation::extract_string(params, "snapshot_name")?;
-                self.delete_snapshot(worker_name, snapshot_name)
+                self.delete_snapshot(worker, snapshot_name)
             },
             "has_snapshot" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
+                let worker = WorkerRef::from_params(params)?;
                 let snapshot_name = validation::extract_string(params, "snapshot_name")?;
-                self.has_snapshot(worker_name, snapshot_name)
+                self.has_snapshot(worker, snapshot_name)
             },
             "reboot_worker" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
-                self.reboot_worker(worker_name)
+                let worker = WorkerRef::from_params(params)?;
+                self.reboot_worker(worker)
             },
             "configure_networks" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
+                let worker = WorkerRef::from_params(params)?;
                 let switch_name = validation::extract_string(params, "switch_name")?;
                 
-                self.configure_networks(worker_name, switch_name)
+                self.configure_networks(worker, switch_name)
             },
             "set_worker_metadata" => {
-                let worker_name = validation::extract_string(params, "worker_name")?;
+                let worker = WorkerRef::from_params(params)?;
                 let key = validation::extract_string(params, "key")?;
                 let value = validation::extract_string(params, "value")?;
                 
-                self.set_worker_metadata(worker_name, key, value)
+                self.set_worker_metadata(worker, key, value)
             },
             "snapshot_volume" => {
                 let source_volume_path = validation::extract_string(params, "source_volume_path")?;
                 let target_volume_path = validation::extract_string(params, "target_volume_path")?;
+                let mode = validation::extract_string_opt(params, "mode")?
+                    .map(|m| DiskMode::parse(&m))
+                    .transpose()?
+                    .unwrap_or(DiskMode::Differencing);
+                let source_format = volume::VolumeFormat::from_path(&source_volume_path)?;
+                if volume::VolumeFormat::from_path(&target_volume_path)? != source_format {
+                    return Err(format!("The target disk must be a .{} file like the source", source_format.extension()));
+                }
+                
+                self.snapshot_volume(source_volume_path, target_volume_path, mode)
+            },
+            "set_boot_order" => {
+                let worker = WorkerRef::from_params(params)?;
+                let devices = validation::extract_json(params, "devices")?;
+                
+                self.set_boot_order(worker, devices)
+            },
+            "get_boot_order" => {
+                let worker = WorkerRef::from_params(params)?;
+                self.get_boot_order(worker)
+            },
+            "register_template" => {
+                let name = validation::extract_string(params, "name")?;
+                let template = validation::extract_json(params, "template")?;
+                
+                self.register_template(name, template)
+            },
+            "list_templates" => self.list_templates(params),
+            "clone_worker" => {
+                let worker = WorkerRef::from_params(params)?;
+                let clone_name = validation::extract_string(params, "clone_name")?;
+                let snapshot_name = validation::extract_string_opt(params, "snapshot_name")?;
+                let copy_metadata = extract_bool_opt(params, "copy_metadata")?.unwrap_or(false);
+                let storage_root = validation::extract_string_opt(params, "storage_root")?
+                    .or_else(|| self.default_string("storage_root"))
+                    .ok_or_else(|| "No storage root configured for worker disks".to_string())?;
+                
+                self.clone_worker(worker, clone_name, snapshot_name, copy_metadata, storage_root)
+            },
+            "export_worker" => {
+                let worker = WorkerRef::from_params(params)?;
+                let export_path = validation::extract_string(params, "export_path")?;
+                let snapshot_name = validation::extract_string_opt(params, "snapshot_name")?;
+                
+                self.export_worker(worker, export_path, snapshot_name)
+            },
+            "import_worker" => {
+                let mode = validation::extract_string_opt(params, "mode")?
+                    .map(|m| ImportMode::parse(&m))
+                    .transpose()?
+                    .unwrap_or(ImportMode::Copy);
+                let generate_new_id = match (mode, extract_bool_opt(params, "generate_new_id")?) {
+                    (ImportMode::Copy, requested) => requested.unwrap_or(true),
+                    (_, Some(true)) => return Err("generate_new_id is only supported in copy mode".to_string()),
+                    (_, _) => false,
+                };
+                
+                self.import_worker(ImportOptions {
+                    path: validation::extract_string(params, "path")?,
+                    mode,
+                    generate_new_id,
+                    new_name: validation::extract_string_opt(params, "new_name")?,
+                    destination: validation::extract_string_opt(params, "destination")?,
+                    storage_root: validation::extract_string_opt(params, "storage_root")?
+                        .or_else(|| self.default_string("storage_root"))
+                        .ok_or_else(|| "No storage root configured for worker disks".to_string())?,
+                    switch_name: validation::extract_string_opt(params, "switch_name")?,
+                    check_only: extract_bool_opt(params, "check_only")?.unwrap_or(false),
+                })
+            },
+            "rename_worker" => {
+                let worker = WorkerRef::from_params(params)?;
+                let new_name = validation::extract_string(params, "new_name")?;
+                let rename_files = extract_bool_opt(params, "rename_files")?.unwrap_or(false);
+                
+                self.rename_worker(worker, new_name, rename_files)
+            },
+            "set_automatic_actions" => {
+                let worker = WorkerRef::from_params(params)?;
+                let actions = AutomaticActions::from_params(params)?;
+                
+                self.set_automatic_actions(worker, actions)
+            },
+            "get_integration_services" => {
+                let worker = WorkerRef::from_params(params)?;
+                
+                self.get_integration_services(worker)
+            },
+            "set_integration_services" => {
+                let worker = WorkerRef::from_params(params)?;
+                let services = validation::extract_json(params, "integration_services")?;
+                let services: BTreeMap<String, bool> = serde_json::from_value(services)
+                    .map_err(|_| "Parameter 'integration_services' must be an object of service name to boolean".to_string())?;
+                
+                self.set_integration_services(worker, services.into_iter().collect())
+            },
+            "run_in_guest" => {
+                let worker = WorkerRef::from_params(params)?;
+                let script = validation::extract_string(params, "script")?;
+                let timeout_seconds = validation::extract_int_opt(params, "timeout_seconds")?.unwrap_or(300);
+                if timeout_seconds < 1 {
+                    return Err(format!("timeout_seconds must be at least 1, got {}", timeout_seconds));
+                }
+                let credentials = GuestCredentials::resolve(params, &self.default_settings)?;
+                
+                self.run_in_guest(worker, script, timeout_seconds, credentials)
+            },
+            "copy_to_guest" => {
+                let worker = WorkerRef::from_params(params)?;
+                let source_path = validation::extract_string(params, "source_path")?;
+                let destination_path = validation::extract_string(params, "destination_path")?;
+                let create_full_path = extract_bool_opt(params, "create_full_path")?.unwrap_or(true);
+                let overwrite = extract_bool_opt(params, "overwrite")?.unwrap_or(false);
+                
+                self.copy_to_guest(worker, source_path, destination_path, create_full_path, overwrite)
+            },
+            "get_guest_kvp" => {
+                let worker = WorkerRef::from_params(params)?;
+                
+                self.get_guest_kvp(worker)
+            },
+            "set_guest_kvp" => {
+                let worker = WorkerRef::from_params(params)?;
+                let items = match validation::extract_json(params, "items")? {
+                    Value::Object(entries) => entries.into_iter()
+                        .map(|(name, value)| {
+                            let data = match value {
+                                Value::String(data) => Some(data),
+                                Value::Null => None,
+                                _ => return Err(format!("KVP value for '{}' must be a string or null", name)),
+                            };
+                            kvp::validate(&name, data.as_deref().unwrap_or_default())?;
+                            Ok((name, data))
+                        })
+                        .collect::<Result<Vec<_>, String>>()?,
+                    _ => return Err("Parameter 'items' must be an object of key to value".to_string()),
+                };
+                
+                self.set_guest_kvp(worker, items)
+            },
+            "get_worker_screenshot" => {
+                let worker = WorkerRef::from_params(params)?;
+                let width = validation::extract_int_opt(params, "width")?.unwrap_or(1024);
+                let height = validation::extract_int_opt(params, "height")?.unwrap_or(768);
+                for (name, value) in [("width", width), ("height", height)] {
+                    if !(16..=4096).contains(&value) {
+                        return Err(format!("{} must be between 16 and 4096 pixels, got {}", name, value));
+                    }
+                }
+                let output_path = validation::extract_string_opt(params, "output_path")?;
+                
+                self.get_worker_screenshot(worker, width as u32, height as u32, output_path)
+            },
+            "configure_serial_port" => {
+                let worker = WorkerRef::from_params(params)?;
+                let port = extract_com_port(params)?;
+                let pipe_name = validation::extract_string_opt(params, "pipe_name")?;
+                if let Some(name) = &pipe_name
+                    && (name.is_empty() || name.contains('\\')) {
+                    return Err(format!("Invalid pipe name '{}'", name));
+                }
+                let enabled = extract_bool_opt(params, "enabled")?.unwrap_or(true);
+                
+                self.configure_serial_port(worker, port, pipe_name, enabled)
+            },
+            "read_serial_console" => {
+                let worker = WorkerRef::from_params(params)?;
+                let port = extract_com_port(params)?;
+                let duration_seconds = validation::extract_int_opt(params, "duration_seconds")?.unwrap_or(5);
+                if !(0..=300).contains(&duration_seconds) {
+                    return Err(format!("duration_seconds must be between 0 and 300, got {}", duration_seconds));
+                }
+                let tail_lines = validation::extract_int_opt(params, "tail_lines")?.unwrap_or(100);
+                if tail_lines < 0 {
+                    return Err(format!("tail_lines must not be negative, got {}", tail_lines));
+                }
+                
+                self.read_serial_console(worker, port, duration_seconds, tail_lines as usize)
+            },
+            "resize_volume" => {
+                let disk_path = validation::extract_string(params, "disk_path")?;
+                let size_mb = validation::extract_int_opt(params, "size_mb")?;
+                let to_minimum = extract_bool_opt(params, "to_minimum")?.unwrap_or(false);
+                let size_mb = match (size_mb, to_minimum) {
+                    (Some(size_mb), false) if size_mb > 0 => Some(size_mb),
+                    (Some(size_mb), false) => return Err(format!("size_mb must be positive, got {}", size_mb)),
+                    (None, true) => None,
+                    _ => return Err("Exactly one of 'size_mb' and 'to_minimum' must be given".to_string()),
+                };
+                
+                self.resize_volume(disk_path, size_mb)
+            },
+            "compact_volume" => {
+                let disk_path = validation::extract_string(params, "disk_path")?;
+                let mode = extract_choice(params, "mode", &["Full", "Quick", "Retrim", "Pretrimmed", "Prezeroed"])?
+                    .unwrap_or("Full");
+                
+                self.compact_volume(disk_path, mode)
+            },
+            "convert_volume" => {
+                let source_path = validation::extract_string(params, "source_path")?;
+                let destination_path = validation::extract_string(params, "destination_path")?;
+                let volume_type = validation::extract_string_opt(params, "type")?;
+                let delete_source = extract_bool_opt(params, "delete_source")?.unwrap_or(false);
+                let conversion = VolumeConversion::new(source_path, destination_path, volume_type.as_deref(), delete_source)?;
+                
+                self.convert_volume(conversion)
+            },
+            "get_volume_chain" => {
+                let disk_path = validation::extract_string(params, "disk_path")?;
+                
+                self.get_volume_chain(disk_path)
+            },
+            "merge_volume" => {
+                let disk_path = validation::extract_string(params, "disk_path")?;
+                let destination_path = validation::extract_string_opt(params, "destination_path")?;
                 
-                self.snapshot_volume(source_volume_path, target_volume_path)
+                self.merge_volume(disk_path, destination_path)
             },
             _ => Err(format!("Action '{}' not found", action)),
         }
@@ -0,0 +1,192 @@
+// File: cpi_hyperv/src/png.rs
+//! PNG encoding of Hyper-V console thumbnails.
+//!
+//! `GetVirtualSystemThumbnailImage` returns the framebuffer as raw RGB565
+//! pixels (16-bit little-endian, rows top to bottom). They are expanded to
+//! 8-bit RGB and written as a PNG whose zlib stream uses stored (uncompressed)
+//! deflate blocks, which needs no compression library.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Largest payload of a stored deflate block
+const MAX_STORED_BLOCK: usize = 65535;
+
+/// Encode a `width` x `height` RGB565 framebuffer as a PNG image
+pub fn encode_rgb565(width: u32, height: u32, pixels: &[u8]) -> Result<Vec<u8>, String> {
+    if width == 0 || height == 0 {
+        return Err("Image dimensions must be positive".to_string());
+    }
+    let expected = width as usize * height as usize * 2;
+    if pixels.len() != expected {
+        return Err(format!(
+            "Expected {} bytes of RGB565 data for {}x{}, got {}",
+            expected, width, height, pixels.len()
+        ));
+    }
+
+    // Each scanline starts with filter type 0 (None)
+    let mut raw = Vec::with_capacity(height as usize * (1 + width as usize * 3));
+    for row in pixels.chunks(width as usize * 2) {
+        raw.push(0);
+        for pixel in row.chunks(2) {
+            raw.extend_from_slice(&rgb565_to_rgb(u16::from_le_bytes([pixel[0], pixel[1]])));
+        }
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, colour type 2 (RGB), deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+/// Expand a 5-6-5 pixel to 8 bits per channel, replicating high bits into the low bits
+fn rgb565_to_rgb(pixel: u16) -> [u8; 3] {
+    let r = ((pixel >> 11) & 0x1f) as u8;
+    let g = ((pixel >> 5) & 0x3f) as u8;
+    let b = (pixel & 0x1f) as u8;
+    [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// zlib stream made of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+    // CM 8 (deflate), 32K window, no preset dictionary; FCHECK makes the header a multiple of 31
+    out.extend_from_slice(&[0x78, 0x01]);
+
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = chunks.next() {
+        let last = chunks.peek().is_none();
+        out.push(last as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal reader for the stored-block images this module writes
+    fn decode(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        assert_eq!(&png[..8], &SIGNATURE);
+        let mut pos = 8;
+        let (mut width, mut height, mut idat) = (0, 0, Vec::new());
+        while pos < png.len() {
+            let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+            let kind = &png[pos + 4..pos + 8];
+            let data = &png[pos + 8..pos + 8 + len];
+            let crc = u32::from_be_bytes(png[pos + 8 + len..pos + 12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(&png[pos + 4..pos + 8 + len]), "bad CRC");
+            match kind {
+                b"IHDR" => {
+                    width = u32::from_be_bytes(data[0..4].try_into().unwrap());
+                    height = u32::from_be_bytes(data[4..8].try_into().unwrap());
+                    assert_eq!(&data[8..], &[8, 2, 0, 0, 0]);
+                },
+                b"IDAT" => idat.extend_from_slice(data),
+                _ => {},
+            }
+            pos += 12 + len;
+        }
+
+        assert_eq!((u16::from_be_bytes([idat[0], idat[1]])) % 31, 0);
+        let mut raw = Vec::new();
+        let mut p = 2;
+        loop {
+            let last = idat[p] & 1;
+            let len = u16::from_le_bytes([idat[p + 1], idat[p + 2]]) as usize;
+            assert_eq!(!u16::from_le_bytes([idat[p + 1], idat[p + 2]]), u16::from_le_bytes([idat[p + 3], idat[p + 4]]));
+            raw.extend_from_slice(&idat[p + 5..p + 5 + len]);
+            p += 5 + len;
+            if last == 1 {
+                break;
+            }
+        }
+        assert_eq!(u32::from_be_bytes(idat[p..p + 4].try_into().unwrap()), adler32(&raw));
+        (width, height, raw)
+    }
+
+    #[test]
+    fn expands_rgb565_channels() {
+        assert_eq!(rgb565_to_rgb(0xffff), [255, 255, 255]);
+        assert_eq!(rgb565_to_rgb(0x0000), [0, 0, 0]);
+        assert_eq!(rgb565_to_rgb(0xf800), [255, 0, 0]);
+        assert_eq!(rgb565_to_rgb(0x07e0), [0, 255, 0]);
+        assert_eq!(rgb565_to_rgb(0x001f), [0, 0, 255]);
+        assert_eq!(rgb565_to_rgb(0x8410), [132, 130, 132]);
+    }
+
+    #[test]
+    fn encodes_scanlines_with_valid_checksums() {
+        // 2x2: red, green / blue, white
+        let pixels = [0x00, 0xf8, 0xe0, 0x07, 0x1f, 0x00, 0xff, 0xff];
+        let (width, height, raw) = decode(&encode_rgb565(2, 2, &pixels).unwrap());
+
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(raw, vec![
+            0, 255, 0, 0, 0, 255, 0,
+            0, 0, 0, 255, 255, 255, 255,
+        ]);
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    }
+
+    #[test]
+    fn splits_large_images_into_stored_blocks() {
+        let (width, height) = (640, 480);
+        let pixels: Vec<u8> = (0..width * height * 2).map(|i| (i % 251) as u8).collect();
+        let (_, _, raw) = decode(&encode_rgb565(width, height, &pixels).unwrap());
+
+        assert_eq!(raw.len(), (height * (1 + width * 3)) as usize);
+        assert!(encode_rgb565(2, 2, &[0; 6]).is_err());
+        assert!(encode_rgb565(0, 2, &[]).is_err());
+    }
+}
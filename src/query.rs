@@ -0,0 +1,424 @@
+// File: cpi_hyperv/src/query.rs
+//! Filtering, sorting and pagination for `list_workers`.
+//!
+//! A query is compiled into a single PowerShell pipeline so that filtering,
+//! sorting and paging happen on the host and only the requested fields of
+//! one page are serialized. Pages are addressed with keyset cursors: the
+//! cursor records the sort key and VM ID of the last worker returned, so
+//! workers created or deleted between calls do not shift later pages.
+
+use crate::base64;
+use crate::inventory::{self, NOTES_PARSER};
+use crate::ps_quote;
+use lib_cpi::validation;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// Fields a listing can return, with the PowerShell expression computing each from a VM in `$_`
+const FIELDS: &[(&str, &str)] = &[
+    ("name", "$_.Name"),
+    ("id", "$_.Id.ToString()"),
+    ("state", "$(if ($_.State.ToString() -eq 'Off') { 'Stopped' } else { $_.State.ToString() })"),
+    ("generation", "$_.Generation"),
+    ("memory_mb", "[int64]($_.MemoryStartup / 1MB)"),
+    ("cpu_count", "$_.ProcessorCount"),
+    ("uptime_seconds", "[int64]$_.Uptime.TotalSeconds"),
+    ("switches", "@($_.NetworkAdapters | ForEach-Object { $_.SwitchName } | Where-Object { $_ })"),
+    ("metadata", "(ConvertFrom-Notes $_.Notes)"),
+];
+
+/// Fields returned when the caller does not select any
+const DEFAULT_FIELDS: &[&str] = &["name", "id", "state"];
+
+/// Fields with a scalar value that a listing can be sorted by
+const SORT_FIELDS: &[&str] = &["name", "id", "state", "generation", "memory_mb", "cpu_count", "uptime_seconds"];
+
+/// Hyper-V VM states accepted by the `state` filter, keyed by their lowercase name
+const STATES: &[(&str, &str)] = &[
+    ("running", "Running"),
+    ("stopped", "Off"),
+    ("off", "Off"),
+    ("saved", "Saved"),
+    ("paused", "Paused"),
+    ("starting", "Starting"),
+    ("stopping", "Stopping"),
+    ("saving", "Saving"),
+    ("pausing", "Pausing"),
+    ("resuming", "Resuming"),
+];
+
+/// Position after the last worker of a page
+#[derive(Debug, Clone, PartialEq)]
+struct Cursor {
+    key: Value,
+    id: String,
+}
+
+/// A parsed `list_workers` request
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerQuery {
+    /// Hyper-V state names; a worker matches any of them
+    states: Vec<String>,
+    /// Wildcard pattern matched against the VM name
+    name: Option<String>,
+    /// Metadata keys to match; `None` only requires the key to be present
+    metadata: Vec<(String, Option<String>)>,
+    generation: Option<i64>,
+    switch_name: Option<String>,
+    fields: Vec<String>,
+    /// Return every `get_worker` field instead of `fields`
+    detail: bool,
+    sort_by: String,
+    descending: bool,
+    limit: Option<i64>,
+    cursor: Option<Cursor>,
+}
+
+impl WorkerQuery {
+    pub fn from_params(params: &HashMap<String, Value>) -> Result<Self, String> {
+        let states = match validation::extract_string_opt(params, "state")? {
+            Some(states) => states
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    STATES.iter()
+                        .find(|(name, _)| *name == s)
+                        .map(|(_, state)| state.to_string())
+                        .ok_or_else(|| format!("Unknown worker state '{}'", s))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+
+        let metadata = match params.get("metadata") {
+            Some(Value::Object(entries)) => entries
+                .iter()
+                .map(|(key, value)| match value {
+                    Value::String(v) => Ok((key.clone(), Some(v.clone()))),
+                    Value::Null => Ok((key.clone(), None)),
+                    _ => Err(format!("Metadata filter for '{}' must be a string or null", key)),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(_) => return Err("Parameter 'metadata' must be an object of key to value".to_string()),
+            None => Vec::new(),
+        };
+
+        let generation = validation::extract_int_opt(params, "generation")?;
+        if let Some(generation) = generation
+            && generation != 1 && generation != 2 {
+            return Err(format!("generation must be 1 or 2, got {}", generation));
+        }
+
+        let fields = match params.get("fields") {
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| item.as_str().map(|s| s.to_string())
+                    .ok_or_else(|| "Parameter 'fields' must be a list of field names".to_string()))
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(Value::String(list)) => list.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect(),
+            Some(_) => return Err("Parameter 'fields' must be a list of field names".to_string()),
+            None => Vec::new(),
+        };
+        for field in &fields {
+            if !FIELDS.iter().any(|(name, _)| name == field) {
+                return Err(format!(
+                    "Unknown field '{}' (expected one of: {})",
+                    field,
+                    FIELDS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+                ));
+            }
+        }
+        let detail = crate::extract_bool_opt(params, "detail")?.unwrap_or(false);
+        if detail && !fields.is_empty() {
+            return Err("Parameters 'fields' and 'detail' cannot be combined".to_string());
+        }
+        let fields = if fields.is_empty() {
+            DEFAULT_FIELDS.iter().map(|s| s.to_string()).collect()
+        } else {
+            fields
+        };
+
+        let sort_by = validation::extract_string_opt(params, "sort_by")?.unwrap_or_else(|| "name".to_string());
+        if !SORT_FIELDS.contains(&sort_by.as_str()) {
+            return Err(format!("Cannot sort by '{}' (expected one of: {})", sort_by, SORT_FIELDS.join(", ")));
+        }
+        let descending = match validation::extract_string_opt(params, "sort_order")?.as_deref() {
+            None | Some("asc") => false,
+            Some("desc") => true,
+            Some(other) => return Err(format!("Unknown sort order '{}' (expected 'asc' or 'desc')", other)),
+        };
+
+        let limit = validation::extract_int_opt(params, "limit")?;
+        if let Some(limit) = limit
+            && limit < 1 {
+            return Err(format!("limit must be at least 1, got {}", limit));
+        }
+
+        let cursor = match validation::extract_string_opt(params, "cursor")? {
+            Some(token) => Some(Cursor::decode(&token, &sort_by, descending)?),
+            None => None,
+        };
+
+        Ok(WorkerQuery {
+            states,
+            name: validation::extract_string_opt(params, "name")?,
+            metadata,
+            generation,
+            switch_name: validation::extract_string_opt(params, "switch_name")?,
+            fields,
+            detail,
+            sort_by,
+            descending,
+            limit,
+            cursor,
+        })
+    }
+
+    /// PowerShell script writing the matching page as a JSON array. Each row carries
+    /// the sort key and ID in `__key` and `__id` for building the next cursor.
+    pub fn script(&self) -> String {
+        let mut filters = Vec::new();
+        if !self.states.is_empty() {
+            let states: Vec<String> = self.states.iter().map(|s| ps_quote(s)).collect();
+            filters.push(format!("($_.State.ToString() -in @({}))", states.join(", ")));
+        }
+        if let Some(name) = &self.name {
+            filters.push(format!("($_.Name -like {})", ps_quote(name)));
+        }
+        if let Some(generation) = self.generation {
+            filters.push(format!("($_.Generation -eq {})", generation));
+        }
+        if let Some(switch_name) = &self.switch_name {
+            filters.push(format!(
+                "(@($_.NetworkAdapters | Where-Object {{ $_.SwitchName -eq {} }}).Count -gt 0)",
+                ps_quote(switch_name)
+            ));
+        }
+        for (key, value) in &self.metadata {
+            let key = ps_quote(key);
+            match value {
+                Some(value) => filters.push(format!("((ConvertFrom-Notes $_.Notes)[{}] -ceq {})", key, ps_quote(value))),
+                None => filters.push(format!("((ConvertFrom-Notes $_.Notes).Contains({}))", key)),
+            }
+        }
+
+        let key = field_expression(&self.sort_by);
+        let descending = if self.descending { "$true" } else { "$false" };
+        let mut pipeline = vec!["Get-VM".to_string()];
+        if !filters.is_empty() {
+            pipeline.push(format!("Where-Object {{ {} }}", filters.join(" -and ")));
+        }
+        pipeline.push(format!(
+            "Sort-Object -Property @{{Expression={{ {} }}; Descending={}}}, @{{Expression={{ $_.Id.ToString() }}; Descending={}}}",
+            key, descending, descending
+        ));
+        if let Some(cursor) = &self.cursor {
+            let op = if self.descending { "-lt" } else { "-gt" };
+            pipeline.push(format!(
+                "Where-Object {{ $k = {}; ($k {op} {v}) -or (($k -eq {v}) -and ($_.Id.ToString() {op} {id})) }}",
+                key,
+                op = op,
+                v = ps_value(&cursor.key),
+                id = ps_quote(&cursor.id)
+            ));
+        }
+        if let Some(limit) = self.limit {
+            // One extra row tells whether another page follows
+            pipeline.push(format!("Select-Object -First {}", limit + 1));
+        }
+
+        if self.detail {
+            pipeline.push(format!(
+                "ForEach-Object {{ $row = ConvertTo-WorkerDetail $_; \
+                 $row | Add-Member -NotePropertyName '__key' -NotePropertyValue ({}); \
+                 $row | Add-Member -NotePropertyName '__id' -NotePropertyValue $_.Id.ToString(); \
+                 $row }}",
+                key
+            ));
+        } else {
+            let mut row = vec![
+                format!("'__key' = {}", key),
+                "'__id' = $_.Id.ToString()".to_string(),
+            ];
+            row.extend(self.fields.iter().map(|f| format!("{} = {}", ps_quote(f), field_expression(f))));
+            pipeline.push(format!("ForEach-Object {{ [PSCustomObject]@{{ {} }} }}", row.join("; ")));
+        }
+
+        format!(
+            "{} {} $page = @({}); ConvertTo-Json -InputObject $page -Depth 5 -Compress",
+            NOTES_PARSER,
+            if self.detail { inventory::worker_detail() } else { String::new() },
+            pipeline.join(" | ")
+        )
+    }
+
+    /// Turn the script output into the `list_workers` result
+    pub fn paginate(&self, output: &str) -> Result<Value, String> {
+        let rows: Value = serde_json::from_str(output.trim())
+            .map_err(|e| format!("Failed to parse worker list: {}", e))?;
+        let mut rows = match rows {
+            Value::Array(rows) => rows,
+            other => vec![other],
+        };
+
+        let mut next_cursor = None;
+        if let Some(limit) = self.limit
+            && rows.len() as i64 > limit {
+            rows.truncate(limit as usize);
+            if let Some(last) = rows.last() {
+                let cursor = Cursor {
+                    key: last["__key"].clone(),
+                    id: last["__id"].as_str().unwrap_or_default().to_string(),
+                };
+                next_cursor = Some(cursor.encode(&self.sort_by, self.descending));
+            }
+        }
+
+        let workers: Vec<Value> = rows
+            .into_iter()
+            .map(|row| {
+                let mut row = match row {
+                    Value::Object(row) => row,
+                    _ => Map::new(),
+                };
+                row.remove("__key");
+                row.remove("__id");
+                if self.detail {
+                    inventory::finish_detail(&mut row);
+                }
+                Value::Object(row)
+            })
+            .collect();
+
+        Ok(json!({
+            "workers": workers,
+            "next_cursor": next_cursor
+        }))
+    }
+}
+
+impl Cursor {
+    // The cursor also records the ordering it was issued for, so it cannot be
+    // replayed against a differently sorted listing
+    fn encode(&self, sort_by: &str, descending: bool) -> String {
+        let token = json!({
+            "sort_by": sort_by,
+            "desc": descending,
+            "key": self.key,
+            "id": self.id
+        });
+        base64::encode(token.to_string().as_bytes())
+    }
+
+    fn decode(token: &str, sort_by: &str, descending: bool) -> Result<Self, String> {
+        let invalid = || "Invalid cursor".to_string();
+        let bytes = base64::decode(token).map_err(|_| invalid())?;
+        let token: Value = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+
+        if token["sort_by"].as_str() != Some(sort_by) || token["desc"].as_bool() != Some(descending) {
+            return Err("Cursor was issued for a different sort order".to_string());
+        }
+        let key = token["key"].clone();
+        if !(key.is_string() || key.is_number()) {
+            return Err(invalid());
+        }
+        let id = token["id"].as_str().filter(|id| crate::is_guid(id)).ok_or_else(invalid)?;
+
+        Ok(Cursor { key, id: id.to_string() })
+    }
+}
+
+fn field_expression(field: &str) -> &'static str {
+    FIELDS.iter().find(|(name, _)| *name == field).map(|(_, expr)| *expr).unwrap_or("$null")
+}
+
+// Render a cursor key as a PowerShell literal
+fn ps_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => ps_quote(s),
+        Value::Number(n) => n.to_string(),
+        _ => "$null".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(params: Value) -> Result<WorkerQuery, String> {
+        let params: HashMap<String, Value> = serde_json::from_value(params).unwrap();
+        WorkerQuery::from_params(&params)
+    }
+
+    #[test]
+    fn builds_filters_and_quotes_values() {
+        let q = query(json!({
+            "state": "running, stopped",
+            "name": "web-*",
+            "metadata": { "role": "o'brien", "pool": null },
+            "generation": 2,
+            "fields": ["name", "memory_mb"],
+            "sort_by": "memory_mb",
+            "sort_order": "desc",
+            "limit": 10
+        })).unwrap();
+        let script = q.script();
+
+        assert!(script.contains("$_.State.ToString() -in @('Running', 'Off')"));
+        assert!(script.contains("$_.Name -like 'web-*'"));
+        assert!(script.contains("['role'] -ceq 'o''brien'"));
+        assert!(script.contains(".Contains('pool')"));
+        assert!(script.contains("$_.Generation -eq 2"));
+        assert!(script.contains("Descending=$true"));
+        assert!(script.contains("Select-Object -First 11"));
+        assert!(script.contains("'memory_mb' = [int64]($_.MemoryStartup / 1MB)"));
+        assert!(!script.contains("'state' ="));
+        assert!(!script.contains("ConvertTo-WorkerDetail"));
+    }
+
+    #[test]
+    fn detail_rows_use_worker_detail() {
+        let q = query(json!({ "detail": true, "state": "running" })).unwrap();
+        let script = q.script();
+
+        assert!(script.contains("function ConvertTo-WorkerDetail"));
+        assert!(script.contains("$row = ConvertTo-WorkerDetail $_"));
+        assert!(!script.contains("'name' = $_.Name"));
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        assert!(query(json!({ "state": "hibernating" })).is_err());
+        assert!(query(json!({ "fields": ["name", "password"] })).is_err());
+        assert!(query(json!({ "sort_by": "switches" })).is_err());
+        assert!(query(json!({ "sort_order": "up" })).is_err());
+        assert!(query(json!({ "limit": 0 })).is_err());
+        assert!(query(json!({ "cursor": "garbage" })).is_err());
+        assert!(query(json!({ "detail": true, "fields": ["name"] })).is_err());
+    }
+
+    #[test]
+    fn pages_with_cursor() {
+        let q = query(json!({ "limit": 2 })).unwrap();
+        let output = r#"[
+            {"__key":"a","__id":"00000000-0000-0000-0000-000000000001","name":"a","id":"00000000-0000-0000-0000-000000000001","state":"Running"},
+            {"__key":"b","__id":"00000000-0000-0000-0000-000000000002","name":"b","id":"00000000-0000-0000-0000-000000000002","state":"Stopped"},
+            {"__key":"c","__id":"00000000-0000-0000-0000-000000000003","name":"c","id":"00000000-0000-0000-0000-000000000003","state":"Saved"}
+        ]"#;
+        let page = q.paginate(output).unwrap();
+
+        assert_eq!(page["workers"].as_array().unwrap().len(), 2);
+        assert!(page["workers"][1].get("__key").is_none());
+        let cursor = page["next_cursor"].as_str().unwrap();
+
+        let next = query(json!({ "limit": 2, "cursor": cursor })).unwrap();
+        assert!(next.script().contains("($k -gt 'b') -or (($k -eq 'b') -and ($_.Id.ToString() -gt '00000000-0000-0000-0000-000000000002'))"));
+        // A cursor only continues the ordering it came from
+        assert!(query(json!({ "limit": 2, "cursor": cursor, "sort_order": "desc" })).is_err());
+
+        let last = next.paginate(r#"{"__key":"c","__id":"x","name":"c"}"#).unwrap();
+        assert_eq!(last["workers"].as_array().unwrap().len(), 1);
+        assert!(last["next_cursor"].is_null());
+    }
+}
@@ -0,0 +1,150 @@
+// File: cpi_hyperv/src/templates.rs
+//! Named worker templates (instance types) for `create_worker`.
+//!
+//! A template is a partial worker description. `create_worker` resolves the
+//! final settings by layering the extension defaults, the named template and
+//! finally the parameters of the call itself.
+
+use lib_cpi::validation;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// Hardware and provisioning settings that a template may provide
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerTemplate {
+    pub memory_mb: Option<i64>,
+    pub cpu_count: Option<i64>,
+    pub generation: Option<i64>,
+    pub switch_name: Option<String>,
+    pub vlan_id: Option<i64>,
+    pub base_image: Option<String>,
+    pub disk_size_mb: Option<i64>,
+    pub disk_mode: Option<String>,
+    pub secure_boot: Option<bool>,
+    pub secure_boot_template: Option<String>,
+    /// Integration service name (e.g. "Guest Service Interface") to enabled state
+    pub integration_services: Option<BTreeMap<String, bool>>,
+}
+
+impl WorkerTemplate {
+    /// Parse a template from its JSON representation
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        let template: WorkerTemplate = serde_json::from_value(value.clone())
+            .map_err(|e| format!("Invalid template: {}", e))?;
+        template.validate()?;
+        Ok(template)
+    }
+
+    /// Collect the template fields passed directly as action parameters
+    pub fn from_params(params: &HashMap<String, Value>) -> Result<Self, String> {
+        let integration_services = match params.get("integration_services") {
+            Some(value) => Some(
+                serde_json::from_value(value.clone())
+                    .map_err(|_| "Parameter 'integration_services' must be an object of service name to boolean".to_string())?
+            ),
+            None => None,
+        };
+
+        let template = WorkerTemplate {
+            memory_mb: validation::extract_int_opt(params, "memory_mb")?,
+            cpu_count: validation::extract_int_opt(params, "cpu_count")?,
+            generation: validation::extract_int_opt(params, "generation")?,
+            switch_name: validation::extract_string_opt(params, "switch_name")?,
+            vlan_id: validation::extract_int_opt(params, "vlan_id")?,
+            base_image: validation::extract_string_opt(params, "base_image")?,
+            disk_size_mb: validation::extract_int_opt(params, "disk_size_mb")?,
+            disk_mode: validation::extract_string_opt(params, "disk_mode")?,
+            secure_boot: crate::extract_bool_opt(params, "secure_boot")?,
+            secure_boot_template: validation::extract_string_opt(params, "secure_boot_template")?,
+            integration_services,
+        };
+        template.validate()?;
+        Ok(template)
+    }
+
+    /// Return a copy of this template with every field set in `overrides` replaced.
+    /// Integration service settings are merged per service.
+    pub fn overlay(&self, overrides: &WorkerTemplate) -> WorkerTemplate {
+        let integration_services = match (&self.integration_services, &overrides.integration_services) {
+            (Some(base), Some(over)) => {
+                let mut merged = base.clone();
+                merged.extend(over.iter().map(|(k, v)| (k.clone(), *v)));
+                Some(merged)
+            },
+            (base, over) => over.clone().or_else(|| base.clone()),
+        };
+
+        WorkerTemplate {
+            memory_mb: overrides.memory_mb.or(self.memory_mb),
+            cpu_count: overrides.cpu_count.or(self.cpu_count),
+            generation: overrides.generation.or(self.generation),
+            switch_name: overrides.switch_name.clone().or_else(|| self.switch_name.clone()),
+            vlan_id: overrides.vlan_id.or(self.vlan_id),
+            base_image: overrides.base_image.clone().or_else(|| self.base_image.clone()),
+            disk_size_mb: overrides.disk_size_mb.or(self.disk_size_mb),
+            disk_mode: overrides.disk_mode.clone().or_else(|| self.disk_mode.clone()),
+            secure_boot: overrides.secure_boot.or(self.secure_boot),
+            secure_boot_template: overrides.secure_boot_template.clone().or_else(|| self.secure_boot_template.clone()),
+            integration_services,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(memory_mb) = self.memory_mb
+            && memory_mb < 32 {
+            return Err(format!("memory_mb must be at least 32, got {}", memory_mb));
+        }
+        if let Some(cpu_count) = self.cpu_count
+            && cpu_count < 1 {
+            return Err(format!("cpu_count must be at least 1, got {}", cpu_count));
+        }
+        if let Some(generation) = self.generation {
+            if generation != 1 && generation != 2 {
+                return Err(format!("generation must be 1 or 2, got {}", generation));
+            }
+            if generation == 1 && (self.secure_boot == Some(true) || self.secure_boot_template.is_some()) {
+                return Err("Secure boot is only available on Generation 2 VMs".to_string());
+            }
+        }
+        if let Some(vlan_id) = self.vlan_id
+            && !(1..=4094).contains(&vlan_id) {
+            return Err(format!("vlan_id must be between 1 and 4094, got {}", vlan_id));
+        }
+        if let Some(disk_size_mb) = self.disk_size_mb
+            && disk_size_mb < 1 {
+            return Err(format!("disk_size_mb must be positive, got {}", disk_size_mb));
+        }
+        Ok(())
+    }
+}
+
+/// Check that a template name is usable as an identifier
+pub fn validate_template_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Template name must not be empty".to_string());
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        return Err(format!(
+            "Template name '{}' may only contain letters, digits, '-', '_' and '.'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Templates shipped with the extension, exposed through the `templates` default setting
+pub fn builtin_templates() -> Value {
+    serde_json::json!({
+        "small": { "memory_mb": 2048, "cpu_count": 2 },
+        "medium": { "memory_mb": 4096, "cpu_count": 4 },
+        "large": { "memory_mb": 8192, "cpu_count": 8 },
+        "build-large": {
+            "memory_mb": 16384,
+            "cpu_count": 8,
+            "disk_size_mb": 131072,
+            "integration_services": { "Guest Service Interface": true }
+        }
+    })
+}
@@ -0,0 +1,288 @@
+// File: cpi_hyperv/src/unattend.rs
+//! Windows answer file (unattend.xml) rendering.
+//!
+//! The answer file targets sysprepped images: the `specialize` pass sets the
+//! computer name and product key and the `oobeSystem` pass skips OOBE, sets
+//! the local Administrator password and runs the first-logon commands.
+//!
+//! Passwords are written in Setup's encoded form (base64 of the UTF-16 text
+//! with a fixed suffix, `PlainText` false). That only keeps them from being
+//! read at a glance; the answer file must still be removed after first boot.
+
+use crate::base64;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// How the rendered answer file reaches the guest
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnattendDelivery {
+    /// Attach an ISO with `unattend.xml` at its root; Setup searches removable media
+    Iso,
+    /// Mount the OS disk offline and write `Windows\Panther\unattend.xml`
+    Inject,
+}
+
+impl UnattendDelivery {
+    pub fn parse(mode: &str) -> Result<Self, String> {
+        match mode.to_lowercase().as_str() {
+            "iso" => Ok(UnattendDelivery::Iso),
+            "inject" | "vhd" => Ok(UnattendDelivery::Inject),
+            other => Err(format!("Unknown unattend delivery '{}' (expected 'iso' or 'inject')", other)),
+        }
+    }
+}
+
+/// Settings rendered into unattend.xml
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnattendSpec {
+    pub computer_name: Option<String>,
+    pub product_key: Option<String>,
+    pub time_zone: Option<String>,
+    /// Locale used for input, system, UI and user locale, e.g. "en-US"
+    pub locale: Option<String>,
+    pub organization: Option<String>,
+    pub owner: Option<String>,
+    /// Commands run in order the first time the Administrator logs on
+    pub first_logon_commands: Vec<String>,
+    /// Log on automatically once so the first-logon commands run unattended
+    pub auto_logon: bool,
+    /// Processor architecture of the image, "amd64" unless specified
+    pub architecture: Option<String>,
+    /// Account the guest credentials name; created as a local administrator unless it is
+    /// the built-in Administrator, and used for AutoLogon. Not part of the request
+    #[serde(skip)]
+    pub username: Option<String>,
+}
+
+impl UnattendSpec {
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        let spec: UnattendSpec = serde_json::from_value(value.clone())
+            .map_err(|e| format!("Invalid unattend settings: {}", e))?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(name) = &self.computer_name {
+            validate_computer_name(name)?;
+        }
+        if let Some(key) = &self.product_key {
+            let groups: Vec<&str> = key.split('-').collect();
+            if groups.len() != 5 || groups.iter().any(|g| g.len() != 5 || !g.chars().all(|c| c.is_ascii_alphanumeric())) {
+                return Err("Product key must have the form XXXXX-XXXXX-XXXXX-XXXXX-XXXXX".to_string());
+            }
+        }
+        if let Some(architecture) = &self.architecture
+            && !["amd64", "x86", "arm64"].contains(&architecture.as_str()) {
+            return Err(format!("Unsupported architecture '{}'", architecture));
+        }
+        Ok(())
+    }
+
+    /// Render the answer file with the given password for the Administrator and `username`
+    pub fn render(&self, admin_password: &str) -> String {
+        let username = self.username.as_deref().unwrap_or("Administrator");
+        let architecture = self.architecture.as_deref().unwrap_or("amd64");
+        let component = |name: &str| format!(
+            "<component name=\"{}\" processorArchitecture=\"{}\" publicKeyToken=\"31bf3856ad364e35\" \
+             language=\"neutral\" versionScope=\"nonSxS\" \
+             xmlns:wcm=\"http://schemas.microsoft.com/WMIConfig/2002/State\" \
+             xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">",
+            name, architecture
+        );
+
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        xml.push_str("<unattend xmlns=\"urn:schemas-microsoft-com:unattend\">\n");
+
+        // specialize
+        xml.push_str("  <settings pass=\"specialize\">\n");
+        xml.push_str(&format!("    {}\n", component("Microsoft-Windows-Shell-Setup")));
+        element(&mut xml, 6, "ComputerName", self.computer_name.as_deref());
+        element(&mut xml, 6, "ProductKey", self.product_key.as_deref());
+        element(&mut xml, 6, "TimeZone", self.time_zone.as_deref());
+        element(&mut xml, 6, "RegisteredOrganization", self.organization.as_deref());
+        element(&mut xml, 6, "RegisteredOwner", self.owner.as_deref());
+        xml.push_str("    </component>\n");
+        xml.push_str("  </settings>\n");
+
+        // oobeSystem
+        xml.push_str("  <settings pass=\"oobeSystem\">\n");
+        if let Some(locale) = &self.locale {
+            xml.push_str(&format!("    {}\n", component("Microsoft-Windows-International-Core")));
+            for name in ["InputLocale", "SystemLocale", "UILanguage", "UserLocale"] {
+                element(&mut xml, 6, name, Some(locale));
+            }
+            xml.push_str("    </component>\n");
+        }
+        xml.push_str(&format!("    {}\n", component("Microsoft-Windows-Shell-Setup")));
+        xml.push_str("      <OOBE>\n");
+        element(&mut xml, 8, "HideEULAPage", Some("true"));
+        element(&mut xml, 8, "HideOnlineAccountScreens", Some("true"));
+        element(&mut xml, 8, "HideWirelessSetupInOOBE", Some("true"));
+        element(&mut xml, 8, "ProtectYourPC", Some("3"));
+        element(&mut xml, 8, "SkipMachineOOBE", Some("true"));
+        element(&mut xml, 8, "SkipUserOOBE", Some("true"));
+        xml.push_str("      </OOBE>\n");
+        xml.push_str("      <UserAccounts>\n");
+        xml.push_str("        <AdministratorPassword>\n");
+        element(&mut xml, 10, "Value", Some(&encode_password(admin_password, "AdministratorPassword")));
+        element(&mut xml, 10, "PlainText", Some("false"));
+        xml.push_str("        </AdministratorPassword>\n");
+        if !username.eq_ignore_ascii_case("Administrator") {
+            xml.push_str("        <LocalAccounts>\n");
+            xml.push_str("          <LocalAccount wcm:action=\"add\">\n");
+            xml.push_str("            <Password>\n");
+            element(&mut xml, 14, "Value", Some(&encode_password(admin_password, "Password")));
+            element(&mut xml, 14, "PlainText", Some("false"));
+            xml.push_str("            </Password>\n");
+            element(&mut xml, 12, "Group", Some("Administrators"));
+            element(&mut xml, 12, "Name", Some(username));
+            xml.push_str("          </LocalAccount>\n");
+            xml.push_str("        </LocalAccounts>\n");
+        }
+        xml.push_str("      </UserAccounts>\n");
+        if self.auto_logon {
+            xml.push_str("      <AutoLogon>\n");
+            element(&mut xml, 8, "Enabled", Some("true"));
+            element(&mut xml, 8, "LogonCount", Some("1"));
+            element(&mut xml, 8, "Username", Some(username));
+            xml.push_str("        <Password>\n");
+            element(&mut xml, 10, "Value", Some(&encode_password(admin_password, "Password")));
+            element(&mut xml, 10, "PlainText", Some("false"));
+            xml.push_str("        </Password>\n");
+            xml.push_str("      </AutoLogon>\n");
+        }
+        if !self.first_logon_commands.is_empty() {
+            xml.push_str("      <FirstLogonCommands>\n");
+            for (index, command) in self.first_logon_commands.iter().enumerate() {
+                xml.push_str("        <SynchronousCommand wcm:action=\"add\">\n");
+                element(&mut xml, 10, "Order", Some(&(index + 1).to_string()));
+                element(&mut xml, 10, "CommandLine", Some(command));
+                element(&mut xml, 10, "Description", Some(&format!("First logon command {}", index + 1)));
+                xml.push_str("        </SynchronousCommand>\n");
+            }
+            xml.push_str("      </FirstLogonCommands>\n");
+        }
+        xml.push_str("    </component>\n");
+        xml.push_str("  </settings>\n");
+        xml.push_str("</unattend>\n");
+        xml
+    }
+}
+
+/// Setup's encoded password form: base64 of the UTF-16LE password followed by the
+/// name of the element it belongs to (`AdministratorPassword` or `Password`)
+fn encode_password(password: &str, suffix: &str) -> String {
+    let bytes: Vec<u8> = password.encode_utf16()
+        .chain(suffix.encode_utf16())
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect();
+    base64::encode(&bytes)
+}
+
+/// Check the NetBIOS computer name rules Windows Setup enforces
+pub fn validate_computer_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 15 {
+        return Err(format!("Computer name '{}' must be 1 to 15 characters long", name));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(format!("Computer name '{}' may only contain letters, digits and '-'", name));
+    }
+    if name.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Computer name '{}' must not be entirely numeric", name));
+    }
+    Ok(())
+}
+
+/// Escape text for use in XML element content and attribute values
+pub fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn element(xml: &mut String, indent: usize, name: &str, value: Option<&str>) {
+    if let Some(value) = value {
+        xml.push_str(&format!("{:indent$}<{}>{}</{}>\n", "", name, xml_escape(value), name, indent = indent));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_markup_characters() {
+        assert_eq!(xml_escape("a<b>&\"c'"), "a&lt;b&gt;&amp;&quot;c&apos;");
+        assert_eq!(xml_escape("plain"), "plain");
+    }
+
+    #[test]
+    fn renders_settings_and_escaped_values() {
+        let spec = UnattendSpec {
+            computer_name: Some("WEB-01".to_string()),
+            product_key: Some("AAAAA-BBBBB-CCCCC-DDDDD-EEEEE".to_string()),
+            first_logon_commands: vec![
+                "cmd /c echo ok > C:\\done.txt".to_string(),
+                "powershell -Command \"Enable-PSRemoting -Force\"".to_string(),
+            ],
+            ..Default::default()
+        };
+        let xml = spec.render("p<ss&word");
+
+        assert!(xml.contains("<ComputerName>WEB-01</ComputerName>"));
+        assert!(xml.contains("<ProductKey>AAAAA-BBBBB-CCCCC-DDDDD-EEEEE</ProductKey>"));
+        assert!(!xml.contains("p&lt;ss&amp;word") && !xml.contains("<PlainText>true</PlainText>"));
+        assert!(xml.contains(&format!("<Value>{}</Value>", encode_password("p<ss&word", "AdministratorPassword"))));
+        assert!(!xml.contains("<LocalAccounts>"));
+        assert!(xml.contains("<CommandLine>cmd /c echo ok &gt; C:\\done.txt</CommandLine>"));
+        assert!(xml.contains("<Order>2</Order>"));
+        assert!(xml.contains("&quot;Enable-PSRemoting -Force&quot;"));
+        assert!(!xml.contains("<TimeZone>"));
+        assert!(!xml.contains("<AutoLogon>"));
+    }
+
+    #[test]
+    fn encodes_passwords_and_logs_on_as_the_guest_user() {
+        // Base64 of UTF-16LE "Pa55AdministratorPassword"
+        assert_eq!(
+            encode_password("Pa55", "AdministratorPassword"),
+            "UABhADUANQBBAGQAbQBpAG4AaQBzAHQAcgBhAHQAbwByAFAAYQBzAHMAdwBvAHIAZAA="
+        );
+
+        let spec = UnattendSpec {
+            auto_logon: true,
+            username: Some("deploy".to_string()),
+            ..Default::default()
+        };
+        let xml = spec.render("Pa55");
+        assert!(xml.contains("<Name>deploy</Name>"));
+        assert!(xml.contains("<Username>deploy</Username>"));
+        assert_eq!(xml.matches(&encode_password("Pa55", "Password")).count(), 2);
+        assert!(!xml.contains("Pa55<"));
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(validate_computer_name("this-name-is-far-too-long").is_err());
+        assert!(validate_computer_name("bad_name").is_err());
+        assert!(validate_computer_name("12345").is_err());
+        assert!(validate_computer_name("worker-7").is_ok());
+
+        let spec = UnattendSpec::from_value(&serde_json::json!({ "product_key": "ABC" }));
+        assert!(spec.is_err());
+        let spec = UnattendSpec::from_value(&serde_json::json!({ "password": "x" }));
+        assert!(spec.is_err());
+    }
+}
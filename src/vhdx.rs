@@ -0,0 +1,1422 @@
+// File: cpi_hyperv/src/vhdx.rs
+//! Native reader for VHDX and legacy VHD files, and writer for new VHDX files.
+//!
+//! Reads the facts `Get-VHD` reports straight from the file, so volumes can
+//! be described without starting PowerShell, and writes empty dynamic and
+//! differencing VHDX files so they can be created without `New-VHD`. A VHDX starts with a file
+//! identifier followed by two copies each of the header and the region
+//! table; the current header is the valid copy with the highest sequence
+//! number. The region table locates the metadata region (block size, virtual
+//! size, sector sizes, disk id and parent locator) and the block allocation
+//! table (BAT). A VHD keeps a 512-byte footer at the end of the file, and
+//! dynamic and differencing VHDs add a header and BAT it points to.
+//!
+//! A VHDX whose log is active may hold metadata updates that were never
+//! applied; such files are rejected rather than replayed. Every structure a
+//! size field points at must lie within the file before it is read, and the
+//! BAT, which can run to hundreds of megabytes, is only read on request.
+
+pub use crate::volume::{VolumeFormat, VolumeType};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KB: u64 = 1024;
+const MB: u64 = 1024 * KB;
+
+const FILE_SIGNATURE: &[u8; 8] = b"vhdxfile";
+const HEADER_OFFSETS: [u64; 2] = [64 * KB, 128 * KB];
+const HEADER_SIZE: usize = 4 * KB as usize;
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * KB, 256 * KB];
+const REGION_TABLE_SIZE: usize = 64 * KB as usize;
+const METADATA_TABLE_SIZE: usize = 64 * KB as usize;
+/// Most entries a region or metadata table can hold
+const MAX_TABLE_ENTRIES: usize = 2047;
+/// Largest VHDX, 64 TB
+const MAX_VHDX_SIZE: u64 = 64 * 1024 * 1024 * MB;
+
+/// Where new disks place their structures, as New-VHD does
+const LOG_OFFSET: u64 = MB;
+const LOG_LENGTH: u64 = MB;
+const METADATA_OFFSET: u64 = 2 * MB;
+const METADATA_LENGTH: u64 = MB;
+const BAT_OFFSET: u64 = 3 * MB;
+const CREATOR: &str = "cpi_hyperv";
+
+const VHD_FOOTER_SIZE: usize = 512;
+const VHD_DYNAMIC_HEADER_SIZE: usize = 1024;
+const VHD_SECTOR_SIZE: u32 = 512;
+const VHD_UNALLOCATED: u32 = 0xffff_ffff;
+
+pub const BAT_REGION: Guid = Guid::new(0x2dc2_7766, 0xf623, 0x4200, [0x9d, 0x64, 0x11, 0x5e, 0x9b, 0xfd, 0x4a, 0x08]);
+pub const METADATA_REGION: Guid = Guid::new(0x8b7c_a206, 0x4790, 0x4b9a, [0xb8, 0xfe, 0x57, 0x5f, 0x05, 0x0f, 0x88, 0x6e]);
+
+pub const FILE_PARAMETERS: Guid = Guid::new(0xcaa1_6737, 0xfa36, 0x4d43, [0xb3, 0xb6, 0x33, 0xf0, 0xaa, 0x44, 0xe7, 0x6b]);
+pub const VIRTUAL_DISK_SIZE: Guid = Guid::new(0x2fa5_4224, 0xcd1b, 0x4876, [0xb2, 0x11, 0x5d, 0xbe, 0xd8, 0x3b, 0xf4, 0xb8]);
+pub const VIRTUAL_DISK_ID: Guid = Guid::new(0xbeca_12ab, 0xb2e6, 0x4523, [0x93, 0xef, 0xc3, 0x09, 0xe0, 0x00, 0xc7, 0x46]);
+pub const LOGICAL_SECTOR_SIZE: Guid = Guid::new(0x8141_bf1d, 0xa96f, 0x4709, [0xba, 0x47, 0xf2, 0x33, 0xa8, 0xfa, 0xab, 0x5f]);
+pub const PHYSICAL_SECTOR_SIZE: Guid = Guid::new(0xcda3_48c7, 0x445d, 0x4471, [0x9c, 0xc9, 0xe9, 0x88, 0x52, 0x51, 0xc5, 0x56]);
+pub const PARENT_LOCATOR: Guid = Guid::new(0xa8d3_5f2d, 0xb30b, 0x454d, [0xab, 0xf7, 0xd3, 0xd8, 0x48, 0x34, 0xab, 0x0c]);
+/// Locator type of a VHDX parent locator
+pub const VHDX_PARENT_LOCATOR: Guid = Guid::new(0xb04a_efb7, 0xd19e, 0x4a81, [0xb7, 0x89, 0x25, 0xb8, 0xe9, 0x44, 0x59, 0x13]);
+
+/// A GUID, held in its on-disk (mixed-endian) byte order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let a = data1.to_le_bytes();
+        let b = data2.to_le_bytes();
+        let c = data3.to_le_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1],
+            data4[0], data4[1], data4[2], data4[3], data4[4], data4[5], data4[6], data4[7],
+        ])
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        let mut guid = [0; 16];
+        guid.copy_from_slice(&bytes[..16]);
+        Guid(guid)
+    }
+
+    /// Parse the registry form, with or without braces
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid GUID '{}'", text);
+        let trimmed = text.trim().trim_start_matches('{').trim_end_matches('}');
+        let parts: Vec<&str> = trimmed.split('-').collect();
+        if parts.iter().map(|p| p.len()).collect::<Vec<_>>() != [8, 4, 4, 4, 12] {
+            return Err(invalid());
+        }
+        let hex = |part: &str| u64::from_str_radix(part, 16).map_err(|_| invalid());
+
+        let tail = hex(parts[3])? << 48 | hex(parts[4])?;
+        Ok(Guid::new(hex(parts[0])? as u32, hex(parts[1])? as u16, hex(parts[2])? as u16, tail.to_be_bytes()))
+    }
+
+    /// A random (version 4) GUID
+    pub fn new_random() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+
+        // RandomState is seeded from the OS once per thread and varied per instance
+        let mut guid = [0u8; 16];
+        for half in guid.chunks_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+            hasher.write_u128(nanos);
+            half.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+        guid[7] = (guid[7] & 0x0f) | 0x40;
+        guid[8] = (guid[8] & 0x3f) | 0x80;
+        Guid(guid)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            g[8], g[9], g[10], g[11], g[12], g[13], g[14], g[15]
+        )
+    }
+}
+
+/// Where a differencing disk expects to find its parent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParentLocator {
+    /// `linkage_id` the parent must have
+    pub parent_id: Option<Guid>,
+    pub absolute_path: Option<String>,
+    /// Path relative to the child's directory
+    pub relative_path: Option<String>,
+    /// Path through the parent's volume GUID (`\\?\Volume{...}\...`)
+    pub volume_path: Option<String>,
+}
+
+impl ParentLocator {
+    /// The path Hyper-V tries first
+    pub fn path(&self) -> Option<&str> {
+        self.absolute_path.as_deref()
+            .or(self.volume_path.as_deref())
+            .or(self.relative_path.as_deref())
+    }
+}
+
+/// State of one payload block in the BAT, as returned by `read_blocks`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockState {
+    /// Never written; reads as zeros, or from the parent of a differencing disk
+    NotPresent,
+    Undefined,
+    Zero,
+    Unmapped,
+    /// Stored at the given file offset
+    FullyPresent(u64),
+    /// Stored at the given file offset, with a sector bitmap saying which sectors
+    /// come from the parent instead
+    PartiallyPresent(u64),
+}
+
+impl BlockState {
+    /// Whether the block has data in this file
+    pub fn is_allocated(&self) -> bool {
+        matches!(self, BlockState::FullyPresent(_) | BlockState::PartiallyPresent(_))
+    }
+}
+
+/// What a VHDX or VHD file says about itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskInfo {
+    pub format: VolumeFormat,
+    pub volume_type: VolumeType,
+    pub virtual_size: u64,
+    /// Payload block size; 0 for a fixed VHD
+    pub block_size: u32,
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+    pub disk_id: Guid,
+    /// Identifier children record for this disk: the VHDX DataWriteGuid or the VHD unique id
+    pub linkage_id: Guid,
+    pub parent: Option<ParentLocator>,
+    /// File offset of the BAT; 0 for a fixed VHD, which has none
+    pub bat_offset: u64,
+}
+
+impl DiskInfo {
+    /// Payload blocks covering the virtual size
+    pub fn block_count(&self) -> usize {
+        if self.block_size == 0 { 0 } else { self.virtual_size.div_ceil(self.block_size as u64) as usize }
+    }
+}
+
+/// Read a VHDX or VHD file, without its BAT
+pub fn inspect(path: impl AsRef<Path>) -> Result<DiskInfo, String> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("Failed to open '{}': {}", path.display(), e))?;
+    read_disk(&mut BufReader::new(file))
+}
+
+/// Read a VHDX or VHD image, without its BAT
+pub fn read_disk<R: Read + Seek>(reader: &mut R) -> Result<DiskInfo, String> {
+    let len = file_length(reader)?;
+    if len >= 8 && read_at(reader, 0, 8)? == FILE_SIGNATURE {
+        read_vhdx(reader, len)
+    } else if len >= VHD_FOOTER_SIZE as u64 {
+        read_vhd(reader, len)
+    } else {
+        Err("Not a VHD or VHDX file".to_string())
+    }
+}
+
+/// Read the state of every payload block of an image `read_disk` described, in disk order;
+/// empty for a fixed VHD
+pub fn read_blocks<R: Read + Seek>(reader: &mut R, info: &DiskInfo) -> Result<Vec<BlockState>, String> {
+    let len = file_length(reader)?;
+    let block_count = info.block_count();
+    if block_count == 0 {
+        return Ok(Vec::new());
+    }
+
+    if info.format == VolumeFormat::Vhdx {
+        // Every chunk of payload blocks is followed by the BAT entry of its sector bitmap
+        let chunk_ratio = vhdx_chunk_ratio(info.block_size, info.logical_sector_size);
+        let bat_entries = block_count + (block_count - 1) / chunk_ratio;
+        if !within(len, info.bat_offset, bat_entries as u64 * 8) {
+            return Err("VHDX BAT lies outside the file".to_string());
+        }
+        let bat = read_at(reader, info.bat_offset, bat_entries * 8)?;
+        (0..block_count)
+            .map(|block| block_state(u64_le(&bat, (block + block / chunk_ratio) * 8), block))
+            .collect()
+    } else {
+        if !within(len, info.bat_offset, block_count as u64 * 4) {
+            return Err("VHD block table lies outside the file".to_string());
+        }
+        // Each block starts with a bitmap of its sectors, padded to whole sectors
+        let bitmap_size = (info.block_size / VHD_SECTOR_SIZE).div_ceil(8).next_multiple_of(VHD_SECTOR_SIZE) as u64;
+        let bat = read_at(reader, info.bat_offset, block_count * 4)?;
+        Ok((0..block_count).map(|block| match u32_be(&bat, block * 4) {
+            VHD_UNALLOCATED => BlockState::NotPresent,
+            sector if info.volume_type == VolumeType::Differencing => {
+                BlockState::PartiallyPresent(sector as u64 * VHD_SECTOR_SIZE as u64 + bitmap_size)
+            },
+            sector => BlockState::FullyPresent(sector as u64 * VHD_SECTOR_SIZE as u64 + bitmap_size),
+        }).collect())
+    }
+}
+
+/// Disk files (`.vhd`, `.vhdx` and checkpoint `.avhd`/`.avhdx`) under `dir`, sorted
+pub fn find_disks(dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let mut disks = Vec::new();
+    let mut pending = vec![dir.as_ref().to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            // Symbolic links and junctions are not followed, so a loop cannot recur
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+            } else if path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| ["vhd", "vhdx", "avhd", "avhdx"].contains(&ext.to_lowercase().as_str()))
+            {
+                disks.push(path);
+            }
+        }
+    }
+    disks.sort();
+    Ok(disks)
+}
+
+// The header of a VHDX
+#[derive(Debug, Clone)]
+struct Header {
+    sequence_number: u64,
+    data_write_guid: Guid,
+    log_guid: Guid,
+    version: u16,
+}
+
+#[derive(Debug, Clone)]
+struct Region {
+    guid: Guid,
+    file_offset: u64,
+    length: u32,
+    required: bool,
+}
+
+fn read_vhdx<R: Read + Seek>(reader: &mut R, len: u64) -> Result<DiskInfo, String> {
+    let header = current_header(reader)?;
+    if header.version != 1 {
+        return Err(format!("Unsupported VHDX version {}", header.version));
+    }
+    if !header.log_guid.is_nil() {
+        return Err("VHDX log is active; the disk is in use or was not closed cleanly".to_string());
+    }
+
+    let regions = region_table(reader)?;
+    if let Some(region) = regions.iter().find(|r| r.required && r.guid != BAT_REGION && r.guid != METADATA_REGION) {
+        return Err(format!("VHDX requires unknown region {}", region.guid));
+    }
+    let region = |guid: Guid, name: &str| regions.iter()
+        .find(|r| r.guid == guid)
+        .ok_or_else(|| format!("VHDX has no {} region", name));
+    let metadata_region = region(METADATA_REGION, "metadata")?;
+    let bat_region = region(BAT_REGION, "BAT")?;
+    for (found, name) in [(metadata_region, "metadata"), (bat_region, "BAT")] {
+        if !within(len, found.file_offset, found.length as u64) {
+            return Err(format!("VHDX {} region lies outside the file", name));
+        }
+    }
+    if (metadata_region.length as usize) < METADATA_TABLE_SIZE {
+        return Err("VHDX metadata region is too small".to_string());
+    }
+
+    let items = metadata_items(reader, metadata_region)?;
+    let item = |guid: Guid, name: &str, len: usize| items.iter()
+        .find(|(id, _)| *id == guid)
+        .map(|(_, data)| data.as_slice())
+        .filter(|data| data.len() >= len)
+        .ok_or_else(|| format!("VHDX metadata has no valid {} item", name));
+
+    let parameters = item(FILE_PARAMETERS, "file parameters", 8)?;
+    let block_size = u32_le(parameters, 0);
+    let leave_blocks_allocated = u32_le(parameters, 4) & 1 != 0;
+    let has_parent = u32_le(parameters, 4) & 2 != 0;
+    let virtual_size = u64_le(item(VIRTUAL_DISK_SIZE, "virtual disk size", 8)?, 0);
+    let disk_id = Guid::from_slice(item(VIRTUAL_DISK_ID, "virtual disk id", 16)?);
+    let logical_sector_size = u32_le(item(LOGICAL_SECTOR_SIZE, "logical sector size", 4)?, 0);
+    let physical_sector_size = u32_le(item(PHYSICAL_SECTOR_SIZE, "physical sector size", 4)?, 0);
+
+    if !block_size.is_power_of_two() || !(MB..=256 * MB).contains(&(block_size as u64)) {
+        return Err(format!("Invalid VHDX block size {}", block_size));
+    }
+    if ![512, 4096].contains(&logical_sector_size) || ![512, 4096].contains(&physical_sector_size) {
+        return Err(format!("Invalid VHDX sector sizes {}/{}", logical_sector_size, physical_sector_size));
+    }
+    if virtual_size == 0 || virtual_size > MAX_VHDX_SIZE || !virtual_size.is_multiple_of(logical_sector_size as u64) {
+        return Err(format!("Invalid VHDX virtual size {}", virtual_size));
+    }
+
+    let parent = if has_parent {
+        Some(parse_parent_locator(item(PARENT_LOCATOR, "parent locator", 20)?)?)
+    } else {
+        None
+    };
+
+    let chunk_ratio = vhdx_chunk_ratio(block_size, logical_sector_size);
+    let block_count = virtual_size.div_ceil(block_size as u64) as usize;
+    let bat_entries = block_count + (block_count - 1) / chunk_ratio;
+    if (bat_region.length as usize) < bat_entries * 8 {
+        return Err("VHDX BAT region is too small for the virtual size".to_string());
+    }
+
+    Ok(DiskInfo {
+        format: VolumeFormat::Vhdx,
+        volume_type: if has_parent {
+            VolumeType::Differencing
+        } else if leave_blocks_allocated {
+            VolumeType::Fixed
+        } else {
+            VolumeType::Dynamic
+        },
+        virtual_size,
+        block_size,
+        logical_sector_size,
+        physical_sector_size,
+        disk_id,
+        linkage_id: header.data_write_guid,
+        parent,
+        bat_offset: bat_region.file_offset,
+    })
+}
+
+// Payload blocks per chunk: the blocks one sector bitmap block covers
+fn vhdx_chunk_ratio(block_size: u32, logical_sector_size: u32) -> usize {
+    ((1u64 << 23) * logical_sector_size as u64 / block_size as u64) as usize
+}
+
+// The valid header copy with the highest sequence number
+fn current_header<R: Read + Seek>(reader: &mut R) -> Result<Header, String> {
+    let mut current: Option<Header> = None;
+    for offset in HEADER_OFFSETS {
+        let data = read_at(reader, offset, HEADER_SIZE)?;
+        if &data[0..4] != b"head" || !crc32c_matches(&data, 4) {
+            continue;
+        }
+        let header = Header {
+            sequence_number: u64_le(&data, 8),
+            data_write_guid: Guid::from_slice(&data[32..48]),
+            log_guid: Guid::from_slice(&data[48..64]),
+            version: u16_le(&data, 66),
+        };
+        if current.as_ref().is_none_or(|c| header.sequence_number > c.sequence_number) {
+            current = Some(header);
+        }
+    }
+    current.ok_or_else(|| "VHDX has no valid header".to_string())
+}
+
+// The first valid copy of the region table
+fn region_table<R: Read + Seek>(reader: &mut R) -> Result<Vec<Region>, String> {
+    for offset in REGION_TABLE_OFFSETS {
+        let data = read_at(reader, offset, REGION_TABLE_SIZE)?;
+        let count = u32_le(&data, 8) as usize;
+        if &data[0..4] != b"regi" || !crc32c_matches(&data, 4) || count > MAX_TABLE_ENTRIES {
+            continue;
+        }
+        return Ok((0..count).map(|i| {
+            let entry = &data[16 + i * 32..48 + i * 32];
+            Region {
+                guid: Guid::from_slice(&entry[0..16]),
+                file_offset: u64_le(entry, 16),
+                length: u32_le(entry, 24),
+                required: u32_le(entry, 28) & 1 != 0,
+            }
+        }).collect());
+    }
+    Err("VHDX has no valid region table".to_string())
+}
+
+// Known metadata items by id
+fn metadata_items<R: Read + Seek>(reader: &mut R, region: &Region) -> Result<Vec<(Guid, Vec<u8>)>, String> {
+    const KNOWN: [Guid; 6] = [FILE_PARAMETERS, VIRTUAL_DISK_SIZE, VIRTUAL_DISK_ID, LOGICAL_SECTOR_SIZE, PHYSICAL_SECTOR_SIZE, PARENT_LOCATOR];
+
+    let table = read_at(reader, region.file_offset, METADATA_TABLE_SIZE)?;
+    if &table[0..8] != b"metadata" {
+        return Err("VHDX metadata table has a bad signature".to_string());
+    }
+    let count = u16_le(&table, 10) as usize;
+    if count > MAX_TABLE_ENTRIES {
+        return Err("VHDX metadata table has too many entries".to_string());
+    }
+
+    let mut items = Vec::new();
+    for i in 0..count {
+        let entry = &table[32 + i * 32..64 + i * 32];
+        let id = Guid::from_slice(&entry[0..16]);
+        let (offset, length) = (u32_le(entry, 16), u32_le(entry, 20));
+        let required = u32_le(entry, 24) & 4 != 0;
+
+        if !KNOWN.contains(&id) {
+            if required {
+                return Err(format!("VHDX requires unknown metadata item {}", id));
+            }
+            continue;
+        }
+        if (offset as usize) < METADATA_TABLE_SIZE || offset as u64 + length as u64 > region.length as u64 {
+            return Err(format!("VHDX metadata item {} lies outside the metadata region", id));
+        }
+        items.push((id, read_at(reader, region.file_offset + offset as u64, length as usize)?));
+    }
+    Ok(items)
+}
+
+fn parse_parent_locator(data: &[u8]) -> Result<ParentLocator, String> {
+    if Guid::from_slice(&data[0..16]) != VHDX_PARENT_LOCATOR {
+        return Err("VHDX parent locator has an unknown type".to_string());
+    }
+    let count = u16_le(data, 18) as usize;
+    if 20 + count * 12 > data.len() {
+        return Err("VHDX parent locator is truncated".to_string());
+    }
+
+    let text = |offset: u32, len: u16| {
+        let (start, end) = (offset as usize, offset as usize + len as usize);
+        data.get(start..end)
+            .filter(|_| len.is_multiple_of(2))
+            .map(utf16_le)
+            .ok_or_else(|| "VHDX parent locator entry lies outside the locator".to_string())
+    };
+
+    let mut locator = ParentLocator::default();
+    for i in 0..count {
+        let entry = &data[20 + i * 12..32 + i * 12];
+        let key = text(u32_le(entry, 0), u16_le(entry, 8))?;
+        let value = text(u32_le(entry, 4), u16_le(entry, 10))?;
+        match key.as_str() {
+            "parent_linkage" => locator.parent_id = Some(Guid::parse(&value)?),
+            "absolute_win32_path" => locator.absolute_path = Some(value),
+            "relative_path" => locator.relative_path = Some(value),
+            "volume_path" => locator.volume_path = Some(value),
+            _ => {},
+        }
+    }
+    Ok(locator)
+}
+
+fn block_state(entry: u64, block: usize) -> Result<BlockState, String> {
+    let offset = (entry >> 20) * MB;
+    match entry & 7 {
+        0 => Ok(BlockState::NotPresent),
+        1 => Ok(BlockState::Undefined),
+        2 => Ok(BlockState::Zero),
+        3 => Ok(BlockState::Unmapped),
+        6 => Ok(BlockState::FullyPresent(offset)),
+        7 => Ok(BlockState::PartiallyPresent(offset)),
+        state => Err(format!("VHDX block {} has invalid state {}", block, state)),
+    }
+}
+
+fn read_vhd<R: Read + Seek>(reader: &mut R, len: u64) -> Result<DiskInfo, String> {
+    // Dynamic disks keep a copy of the footer at the start of the file
+    let mut footer = read_at(reader, len - VHD_FOOTER_SIZE as u64, VHD_FOOTER_SIZE)?;
+    if !vhd_footer_valid(&footer) {
+        footer = read_at(reader, 0, VHD_FOOTER_SIZE)?;
+        if !vhd_footer_valid(&footer) {
+            return Err("Not a VHD or VHDX file".to_string());
+        }
+    }
+
+    let virtual_size = u64_be(&footer, 48);
+    let volume_type = match u32_be(&footer, 60) {
+        2 => VolumeType::Fixed,
+        3 => VolumeType::Dynamic,
+        4 => VolumeType::Differencing,
+        other => return Err(format!("Unsupported VHD disk type {}", other)),
+    };
+    let disk_id = Guid::from_slice(&footer[68..84]);
+    let mut info = DiskInfo {
+        format: VolumeFormat::Vhd,
+        volume_type,
+        virtual_size,
+        block_size: 0,
+        logical_sector_size: VHD_SECTOR_SIZE,
+        physical_sector_size: VHD_SECTOR_SIZE,
+        disk_id,
+        linkage_id: disk_id,
+        parent: None,
+        bat_offset: 0,
+    };
+    if volume_type == VolumeType::Fixed {
+        return Ok(info);
+    }
+
+    let header_offset = u64_be(&footer, 16);
+    if !within(len, header_offset, VHD_DYNAMIC_HEADER_SIZE as u64) {
+        return Err("VHD dynamic header lies outside the file".to_string());
+    }
+    let header = read_at(reader, header_offset, VHD_DYNAMIC_HEADER_SIZE)?;
+    if &header[0..8] != b"cxsparse" || vhd_checksum(&header, 36) != u32_be(&header, 36) {
+        return Err("VHD dynamic header is invalid".to_string());
+    }
+    let table_offset = u64_be(&header, 16);
+    let max_entries = u32_be(&header, 28) as usize;
+    let block_size = u32_be(&header, 32);
+    if !block_size.is_power_of_two() || block_size < VHD_SECTOR_SIZE {
+        return Err(format!("Invalid VHD block size {}", block_size));
+    }
+    let block_count = virtual_size.div_ceil(block_size as u64);
+    if block_count > max_entries as u64 {
+        return Err("VHD block table is too small for the virtual size".to_string());
+    }
+    if !within(len, table_offset, block_count * 4) {
+        return Err("VHD block table lies outside the file".to_string());
+    }
+    info.block_size = block_size;
+    info.bat_offset = table_offset;
+
+    if volume_type == VolumeType::Differencing {
+        let mut locator = ParentLocator {
+            parent_id: Some(Guid::from_slice(&header[40..56])),
+            ..ParentLocator::default()
+        };
+        for i in 0..8 {
+            let entry = &header[576 + i * 24..600 + i * 24];
+            let (code, length, offset) = (&entry[0..4], u32_be(entry, 8), u64_be(entry, 16));
+            if length == 0 || !length.is_multiple_of(2) {
+                continue;
+            }
+            if !within(len, offset, length as u64) {
+                return Err("VHD parent locator lies outside the file".to_string());
+            }
+            let path = utf16_le(&read_at(reader, offset, length as usize)?);
+            match code {
+                b"W2ku" => locator.absolute_path = Some(path),
+                b"W2ru" => locator.relative_path = Some(path),
+                _ => {},
+            }
+        }
+        info.parent = Some(locator);
+    }
+    Ok(info)
+}
+
+fn vhd_footer_valid(footer: &[u8]) -> bool {
+    &footer[0..8] == b"conectix" && vhd_checksum(footer, 64) == u32_be(footer, 64)
+}
+
+// One's complement of the byte sum, skipping the checksum field
+fn vhd_checksum(data: &[u8], field: usize) -> u32 {
+    let sum = data.iter().enumerate()
+        .filter(|(i, _)| !(field..field + 4).contains(i))
+        .fold(0u32, |sum, (_, &byte)| sum.wrapping_add(byte as u32));
+    !sum
+}
+
+/// Settings of a new, empty dynamic or differencing VHDX
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewVhdx {
+    pub virtual_size: u64,
+    pub block_size: u32,
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+    pub disk_id: Guid,
+    /// Set for a differencing disk; needs `parent_id` and `absolute_path`
+    pub parent: Option<ParentLocator>,
+}
+
+impl NewVhdx {
+    /// A dynamic disk with the New-VHD defaults: 32 MB blocks, 512-byte logical
+    /// and 4096-byte physical sectors
+    pub fn dynamic(virtual_size: u64) -> Self {
+        NewVhdx {
+            virtual_size,
+            block_size: 32 * MB as u32,
+            logical_sector_size: 512,
+            physical_sector_size: 4096,
+            disk_id: Guid::new_random(),
+            parent: None,
+        }
+    }
+
+    /// A differencing disk at `path` on top of the VHDX at `parent_path`, which
+    /// supplies the size, sector sizes and disk id; blocks are 2 MB as with New-VHD
+    pub fn differencing(path: &str, parent_path: &str, parent: &DiskInfo) -> Result<Self, String> {
+        if parent.format != VolumeFormat::Vhdx {
+            return Err("The parent of a VHDX differencing disk must be a VHDX".to_string());
+        }
+        let absolute_path = if is_win32_absolute(parent_path) {
+            parent_path.to_string()
+        } else {
+            std::path::absolute(parent_path)
+                .map_err(|e| format!("Failed to resolve parent path '{}': {}", parent_path, e))?
+                .to_string_lossy()
+                .to_string()
+        };
+
+        Ok(NewVhdx {
+            virtual_size: parent.virtual_size,
+            block_size: 2 * MB as u32,
+            logical_sector_size: parent.logical_sector_size,
+            physical_sector_size: parent.physical_sector_size,
+            disk_id: parent.disk_id,
+            parent: Some(ParentLocator {
+                parent_id: Some(parent.linkage_id),
+                relative_path: relative_win32_path(path, &absolute_path),
+                absolute_path: Some(absolute_path),
+                volume_path: None,
+            }),
+        })
+    }
+
+    /// Write the disk to a new file, which must not exist yet
+    pub fn create(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        self.validate()?;
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).open(path)
+            .map_err(|e| format!("Failed to create '{}': {}", path.display(), e))?;
+
+        let written = self.write(&mut file).and_then(|_| file.sync_all().map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            drop(file);
+            let _ = fs::remove_file(path);
+            return Err(format!("Failed to write '{}': {}", path.display(), e));
+        }
+        Ok(())
+    }
+
+    /// Write the disk: file identifier, both headers and region tables, an
+    /// empty 1 MB log at 1 MB, the metadata region at 2 MB and a BAT of
+    /// unallocated blocks at 3 MB, the layout New-VHD uses
+    pub fn write<W: Write + Seek>(&self, writer: &mut W) -> Result<(), String> {
+        self.validate()?;
+        let bat_length = (self.bat_entries() * 8).next_multiple_of(MB);
+        let file_write_guid = Guid::new_random();
+        let data_write_guid = Guid::new_random();
+
+        let mut identifier = FILE_SIGNATURE.to_vec();
+        identifier.extend(CREATOR.encode_utf16().flat_map(|u| u.to_le_bytes()));
+        write_at(writer, 0, &identifier)?;
+
+        // Both headers are current; readers take the higher sequence number
+        for (sequence, offset) in HEADER_OFFSETS.into_iter().enumerate() {
+            let mut header = vec![0u8; HEADER_SIZE];
+            header[0..4].copy_from_slice(b"head");
+            header[8..16].copy_from_slice(&(sequence as u64).to_le_bytes());
+            header[16..32].copy_from_slice(&file_write_guid.0);
+            header[32..48].copy_from_slice(&data_write_guid.0);
+            header[66..68].copy_from_slice(&1u16.to_le_bytes());
+            header[68..72].copy_from_slice(&(LOG_LENGTH as u32).to_le_bytes());
+            header[72..80].copy_from_slice(&LOG_OFFSET.to_le_bytes());
+            seal_crc32c(&mut header, 4);
+            write_at(writer, offset, &header)?;
+        }
+
+        let mut regions = vec![0u8; REGION_TABLE_SIZE];
+        regions[0..4].copy_from_slice(b"regi");
+        regions[8..12].copy_from_slice(&2u32.to_le_bytes());
+        let entries = [(BAT_REGION, BAT_OFFSET, bat_length), (METADATA_REGION, METADATA_OFFSET, METADATA_LENGTH)];
+        for (i, (guid, offset, length)) in entries.into_iter().enumerate() {
+            let entry = &mut regions[16 + i * 32..48 + i * 32];
+            entry[0..16].copy_from_slice(&guid.0);
+            entry[16..24].copy_from_slice(&offset.to_le_bytes());
+            entry[24..28].copy_from_slice(&(length as u32).to_le_bytes());
+            entry[28..32].copy_from_slice(&1u32.to_le_bytes());
+        }
+        seal_crc32c(&mut regions, 4);
+        for offset in REGION_TABLE_OFFSETS {
+            write_at(writer, offset, &regions)?;
+        }
+
+        write_at(writer, METADATA_OFFSET, &self.metadata())?;
+
+        // The log and the BAT are all zeros: no log entries and no blocks present
+        let end = BAT_OFFSET + bat_length;
+        write_at(writer, end - 1, &[0])?;
+        writer.flush().map_err(|e| format!("Failed to write disk: {}", e))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !self.block_size.is_power_of_two() || !(MB..=256 * MB).contains(&(self.block_size as u64)) {
+            return Err(format!("Invalid VHDX block size {}", self.block_size));
+        }
+        if ![512, 4096].contains(&self.logical_sector_size) || ![512, 4096].contains(&self.physical_sector_size) {
+            return Err(format!("Invalid VHDX sector sizes {}/{}", self.logical_sector_size, self.physical_sector_size));
+        }
+        if self.virtual_size == 0 || self.virtual_size > MAX_VHDX_SIZE
+            || !self.virtual_size.is_multiple_of(self.logical_sector_size as u64) {
+            return Err(format!("Invalid VHDX virtual size {}", self.virtual_size));
+        }
+        if let Some(parent) = &self.parent
+            && (parent.parent_id.is_none() || parent.absolute_path.is_none()) {
+            return Err("A differencing VHDX needs the parent's id and absolute path".to_string());
+        }
+        Ok(())
+    }
+
+    fn bat_entries(&self) -> u64 {
+        let chunk_ratio = (1u64 << 23) * self.logical_sector_size as u64 / self.block_size as u64;
+        let blocks = self.virtual_size.div_ceil(self.block_size as u64);
+        if self.parent.is_some() {
+            // Differencing disks carry a sector bitmap entry for every chunk
+            blocks.div_ceil(chunk_ratio) * (chunk_ratio + 1)
+        } else {
+            blocks + (blocks - 1) / chunk_ratio
+        }
+    }
+
+    // The metadata table followed by its items, in the order Windows writes them
+    fn metadata(&self) -> Vec<u8> {
+        // Item flags: IsVirtualDisk is bit 1 and IsRequired bit 2
+        const REQUIRED: u32 = 4;
+        const VIRTUAL_DISK_REQUIRED: u32 = 6;
+
+        let flags = if self.parent.is_some() { 2u32 } else { 0 };
+        let mut parameters = self.block_size.to_le_bytes().to_vec();
+        parameters.extend_from_slice(&flags.to_le_bytes());
+
+        let mut items = vec![
+            (FILE_PARAMETERS, REQUIRED, parameters),
+            (VIRTUAL_DISK_SIZE, VIRTUAL_DISK_REQUIRED, self.virtual_size.to_le_bytes().to_vec()),
+            (LOGICAL_SECTOR_SIZE, VIRTUAL_DISK_REQUIRED, self.logical_sector_size.to_le_bytes().to_vec()),
+            (PHYSICAL_SECTOR_SIZE, VIRTUAL_DISK_REQUIRED, self.physical_sector_size.to_le_bytes().to_vec()),
+            (VIRTUAL_DISK_ID, VIRTUAL_DISK_REQUIRED, self.disk_id.0.to_vec()),
+        ];
+        if let Some(parent) = &self.parent {
+            items.push((PARENT_LOCATOR, REQUIRED, parent_locator(parent)));
+        }
+
+        let mut metadata = vec![0u8; METADATA_TABLE_SIZE];
+        metadata[0..8].copy_from_slice(b"metadata");
+        metadata[10..12].copy_from_slice(&(items.len() as u16).to_le_bytes());
+        for (i, (id, flags, data)) in items.into_iter().enumerate() {
+            let offset = metadata.len();
+            let entry = &mut metadata[32 + i * 32..64 + i * 32];
+            entry[0..16].copy_from_slice(&id.0);
+            entry[16..20].copy_from_slice(&(offset as u32).to_le_bytes());
+            entry[20..24].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            entry[24..28].copy_from_slice(&flags.to_le_bytes());
+            metadata.extend_from_slice(&data);
+        }
+        metadata
+    }
+}
+
+// Key/value entries of a VHDX parent locator, strings in UTF-16LE, keyed and
+// ordered as Windows writes them, down to the nil parent_linkage2
+fn parent_locator(parent: &ParentLocator) -> Vec<u8> {
+    let linkage = parent.parent_id.map(|id| format!("{{{}}}", id).to_lowercase());
+    let linkage2 = format!("{{{}}}", Guid::default());
+    let pairs: Vec<(&str, &str)> = [
+        ("parent_linkage", linkage.as_deref()),
+        ("absolute_win32_path", parent.absolute_path.as_deref()),
+        ("relative_path", parent.relative_path.as_deref()),
+        ("volume_path", parent.volume_path.as_deref()),
+        ("parent_linkage2", Some(linkage2.as_str())),
+    ].into_iter().filter_map(|(key, value)| Some((key, value?))).collect();
+
+    let mut locator = VHDX_PARENT_LOCATOR.0.to_vec();
+    locator.extend_from_slice(&[0, 0]);
+    locator.extend_from_slice(&(pairs.len() as u16).to_le_bytes());
+
+    let utf16 = |text: &str| text.encode_utf16().flat_map(|u| u.to_le_bytes()).collect::<Vec<u8>>();
+    let mut strings = Vec::new();
+    let base = 20 + pairs.len() * 12;
+    for (key, value) in pairs {
+        let (key, value) = (utf16(key), utf16(value));
+        locator.extend_from_slice(&((base + strings.len()) as u32).to_le_bytes());
+        locator.extend_from_slice(&((base + strings.len() + key.len()) as u32).to_le_bytes());
+        locator.extend_from_slice(&(key.len() as u16).to_le_bytes());
+        locator.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        strings.extend_from_slice(&key);
+        strings.extend_from_slice(&value);
+    }
+    locator.extend_from_slice(&strings);
+    locator
+}
+
+// A drive-letter or UNC path
+fn is_win32_absolute(path: &str) -> bool {
+    path.starts_with("\\\\") || path.as_bytes().get(1..3).is_some_and(|s| s[0] == b':' && (s[1] == b'\\' || s[1] == b'/'))
+}
+
+// Path of `target` relative to the directory of `file`, as Hyper-V records it;
+// None when they are on different volumes
+fn relative_win32_path(file: &str, target: &str) -> Option<String> {
+    if !is_win32_absolute(file) {
+        return None;
+    }
+    let split = |path: &str| path.split(['\\', '/']).filter(|p| !p.is_empty()).map(|p| p.to_string()).collect::<Vec<_>>();
+    let (file, target) = (split(file), split(target));
+    let directory = &file[..file.len() - 1];
+    if !directory.first()?.eq_ignore_ascii_case(target.first()?) {
+        return None;
+    }
+
+    let common = directory.iter().zip(&target)
+        .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+        .count();
+    let mut parts = if common == directory.len() { vec![".".to_string()] } else { vec!["..".to_string(); directory.len() - common] };
+    parts.extend_from_slice(&target[common..]);
+    Some(parts.join("\\"))
+}
+
+fn write_at<W: Write + Seek>(writer: &mut W, offset: u64, data: &[u8]) -> Result<(), String> {
+    writer.seek(SeekFrom::Start(offset))
+        .and_then(|_| writer.write_all(data))
+        .map_err(|e| format!("Failed to write {} bytes at offset {}: {}", data.len(), offset, e))
+}
+
+fn seal_crc32c(data: &mut [u8], field: usize) {
+    data[field..field + 4].fill(0);
+    let crc = crc32c(data);
+    data[field..field + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+// VHDX checksums are CRC-32C over the structure with the checksum field zeroed
+fn crc32c_matches(data: &[u8], field: usize) -> bool {
+    let mut copy = data.to_vec();
+    copy[field..field + 4].fill(0);
+    crc32c(&copy) == u32_le(data, field)
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn file_length<R: Seek>(reader: &mut R) -> Result<u64, String> {
+    reader.seek(SeekFrom::End(0)).map_err(|e| format!("Failed to read disk size: {}", e))
+}
+
+// Whether `size` bytes at `offset` lie within a file of `len` bytes
+fn within(len: u64, offset: u64, size: u64) -> bool {
+    offset.checked_add(size).is_some_and(|end| end <= len)
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> Result<Vec<u8>, String> {
+    let mut data = vec![0; len];
+    reader.seek(SeekFrom::Start(offset))
+        .and_then(|_| reader.read_exact(&mut data))
+        .map_err(|e| format!("Failed to read {} bytes at offset {}: {}", len, offset, e))?;
+    Ok(data)
+}
+
+fn utf16_le(data: &[u8]) -> String {
+    let units: Vec<u16> = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    String::from_utf16_lossy(&units).trim_end_matches('\0').to_string()
+}
+
+fn u16_le(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_le(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_le(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn u32_be(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_be(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const DISK_ID: Guid = Guid::new(0x1234_5678, 0x9abc, 0xdef0, [1, 2, 3, 4, 5, 6, 7, 8]);
+    const DATA_WRITE: Guid = Guid::new(0x0bad_f00d, 1, 2, [3, 4, 5, 6, 7, 8, 9, 10]);
+
+    fn put(image: &mut [u8], offset: usize, data: &[u8]) {
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    fn seal(image: &mut [u8], start: usize, len: usize) {
+        image[start + 4..start + 8].fill(0);
+        let crc = crc32c(&image[start..start + len]);
+        put(image, start + 4, &crc.to_le_bytes());
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(|u| u.to_le_bytes()).collect()
+    }
+
+    // A VHDX laid out as Hyper-V does: headers and region tables, a 1 MB log
+    // at 1 MB, metadata at 2 MB and the BAT at 3 MB
+    fn vhdx_fixture(virtual_size: u64, block_size: u32, flags: u32, parent: &[(&str, &str)], bat: &[(usize, u64)]) -> Vec<u8> {
+        let mut image = vec![0u8; 4 * MB as usize];
+        put(&mut image, 0, FILE_SIGNATURE);
+        put(&mut image, 8, &utf16("test fixture"));
+
+        for (i, offset) in HEADER_OFFSETS.iter().enumerate() {
+            let at = *offset as usize;
+            put(&mut image, at, b"head");
+            put(&mut image, at + 8, &(10 + i as u64).to_le_bytes());
+            put(&mut image, at + 32, &DATA_WRITE.0);
+            put(&mut image, at + 66, &1u16.to_le_bytes());
+            put(&mut image, at + 68, &(MB as u32).to_le_bytes());
+            put(&mut image, at + 72, &MB.to_le_bytes());
+            seal(&mut image, at, HEADER_SIZE);
+        }
+
+        for offset in REGION_TABLE_OFFSETS {
+            let at = offset as usize;
+            put(&mut image, at, b"regi");
+            put(&mut image, at + 8, &2u32.to_le_bytes());
+            for (i, (guid, file_offset)) in [(BAT_REGION, 3 * MB), (METADATA_REGION, 2 * MB)].iter().enumerate() {
+                let entry = at + 16 + i * 32;
+                put(&mut image, entry, &guid.0);
+                put(&mut image, entry + 16, &file_offset.to_le_bytes());
+                put(&mut image, entry + 24, &(MB as u32).to_le_bytes());
+                put(&mut image, entry + 28, &1u32.to_le_bytes());
+            }
+            seal(&mut image, at, REGION_TABLE_SIZE);
+        }
+
+        let mut locator = VHDX_PARENT_LOCATOR.0.to_vec();
+        locator.extend_from_slice(&[0, 0]);
+        locator.extend_from_slice(&(parent.len() as u16).to_le_bytes());
+        let mut strings = Vec::new();
+        let base = 20 + parent.len() * 12;
+        for (key, value) in parent {
+            let (key, value) = (utf16(key), utf16(value));
+            locator.extend_from_slice(&((base + strings.len()) as u32).to_le_bytes());
+            locator.extend_from_slice(&((base + strings.len() + key.len()) as u32).to_le_bytes());
+            locator.extend_from_slice(&(key.len() as u16).to_le_bytes());
+            locator.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            strings.extend_from_slice(&key);
+            strings.extend_from_slice(&value);
+        }
+        locator.extend_from_slice(&strings);
+
+        let mut parameters = block_size.to_le_bytes().to_vec();
+        parameters.extend_from_slice(&flags.to_le_bytes());
+        let mut items = vec![
+            (FILE_PARAMETERS, parameters),
+            (VIRTUAL_DISK_SIZE, virtual_size.to_le_bytes().to_vec()),
+            (VIRTUAL_DISK_ID, DISK_ID.0.to_vec()),
+            (LOGICAL_SECTOR_SIZE, 512u32.to_le_bytes().to_vec()),
+            (PHYSICAL_SECTOR_SIZE, 4096u32.to_le_bytes().to_vec()),
+        ];
+        if !parent.is_empty() {
+            items.push((PARENT_LOCATOR, locator));
+        }
+
+        let metadata = 2 * MB as usize;
+        put(&mut image, metadata, b"metadata");
+        put(&mut image, metadata + 10, &(items.len() as u16).to_le_bytes());
+        let mut offset = METADATA_TABLE_SIZE;
+        for (i, (id, data)) in items.iter().enumerate() {
+            let entry = metadata + 32 + i * 32;
+            put(&mut image, entry, &id.0);
+            put(&mut image, entry + 16, &(offset as u32).to_le_bytes());
+            put(&mut image, entry + 20, &(data.len() as u32).to_le_bytes());
+            put(&mut image, entry + 24, &4u32.to_le_bytes());
+            put(&mut image, metadata + offset, data);
+            offset += data.len().next_multiple_of(8);
+        }
+
+        for (index, entry) in bat {
+            put(&mut image, 3 * MB as usize + index * 8, &entry.to_le_bytes());
+        }
+        image
+    }
+
+    fn vhd_footer(disk_type: u32, size: u64, data_offset: u64) -> Vec<u8> {
+        let mut footer = vec![0u8; VHD_FOOTER_SIZE];
+        put(&mut footer, 0, b"conectix");
+        put(&mut footer, 12, &0x0001_0000u32.to_be_bytes());
+        put(&mut footer, 16, &data_offset.to_be_bytes());
+        put(&mut footer, 40, &size.to_be_bytes());
+        put(&mut footer, 48, &size.to_be_bytes());
+        put(&mut footer, 60, &disk_type.to_be_bytes());
+        put(&mut footer, 68, &DISK_ID.0);
+        let checksum = vhd_checksum(&footer, 64);
+        put(&mut footer, 64, &checksum.to_be_bytes());
+        footer
+    }
+
+    #[test]
+    fn formats_and_parses_guids() {
+        assert_eq!(BAT_REGION.to_string(), "2DC27766-F623-4200-9D64-115E9BFD4A08");
+        assert_eq!(&BAT_REGION.0[..4], &[0x66, 0x77, 0xc2, 0x2d]);
+        assert_eq!(Guid::parse("{2dc27766-f623-4200-9d64-115e9bfd4a08}").unwrap(), BAT_REGION);
+        assert!(Guid::parse("2DC27766-F623-4200-9D64").is_err());
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn reads_dynamic_vhdx() {
+        // 4 GB in 256 MB blocks: 16 payload blocks per chunk, so the first
+        // sector bitmap entry sits at index 16 and block 16 at index 17
+        let bat = [(0, (4 << 20) | 6), (16, (9 << 20) | 6), (17, (5 << 20) | 6), (3, 2)];
+        let mut image = Cursor::new(vhdx_fixture(4 * 1024 * MB, 256 * MB as u32, 0, &[], &bat));
+        let info = read_disk(&mut image).unwrap();
+
+        assert_eq!(info.format, VolumeFormat::Vhdx);
+        assert_eq!(info.volume_type, VolumeType::Dynamic);
+        assert_eq!(info.virtual_size, 4 * 1024 * MB);
+        assert_eq!((info.block_size, info.logical_sector_size, info.physical_sector_size), (256 * MB as u32, 512, 4096));
+        assert_eq!(info.disk_id, DISK_ID);
+        // The second header has the higher sequence number
+        assert_eq!(info.linkage_id, DATA_WRITE);
+        assert_eq!(info.parent, None);
+
+        assert_eq!(info.bat_offset, 3 * MB);
+
+        let blocks = read_blocks(&mut image, &info).unwrap();
+        assert_eq!(blocks.len(), 16);
+        assert_eq!(blocks[0], BlockState::FullyPresent(4 * MB));
+        assert_eq!(blocks[3], BlockState::Zero);
+        assert_eq!(blocks[15], BlockState::NotPresent);
+        assert_eq!(blocks.iter().filter(|block| block.is_allocated()).count(), 1);
+
+        let mut image = Cursor::new(vhdx_fixture(4 * 1024 * MB + 256 * MB, 256 * MB as u32, 0, &[], &bat));
+        let info = read_disk(&mut image).unwrap();
+        let blocks = read_blocks(&mut image, &info).unwrap();
+        assert_eq!(blocks[16], BlockState::FullyPresent(5 * MB));
+        assert_eq!(blocks.iter().filter(|block| block.is_allocated()).count(), 2);
+
+        let image = vhdx_fixture(MB * 64, MB as u32 * 32, 1, &[], &[(0, (4 << 20) | 6), (1, (36 << 20) | 6)]);
+        assert_eq!(read_disk(&mut Cursor::new(image)).unwrap().volume_type, VolumeType::Fixed);
+    }
+
+    #[test]
+    fn reads_vhdx_parent_locator() {
+        let linkage = "{0BADF00D-0001-0002-0304-05060708090A}";
+        let parent = [
+            ("parent_linkage", linkage),
+            ("relative_path", "..\\base\\ubuntu.vhdx"),
+            ("absolute_win32_path", "D:\\base\\ubuntu.vhdx"),
+        ];
+        let image = vhdx_fixture(10 * 1024 * MB, 2 * MB as u32, 2, &parent, &[(1, (8 << 20) | 7)]);
+        let mut image = Cursor::new(image);
+        let info = read_disk(&mut image).unwrap();
+
+        assert_eq!(info.volume_type, VolumeType::Differencing);
+        assert_eq!(read_blocks(&mut image, &info).unwrap()[1], BlockState::PartiallyPresent(8 * MB));
+        let locator = info.parent.unwrap();
+        assert_eq!(locator.parent_id, Some(DATA_WRITE));
+        assert_eq!(locator.path(), Some("D:\\base\\ubuntu.vhdx"));
+        assert_eq!(locator.relative_path.as_deref(), Some("..\\base\\ubuntu.vhdx"));
+        assert_eq!(locator.volume_path, None);
+    }
+
+    #[test]
+    fn falls_back_to_second_copies_and_rejects_damage() {
+        let good = vhdx_fixture(1024 * MB, 32 * MB as u32, 0, &[], &[]);
+
+        // A torn write of the newest header falls back to the older one
+        let mut image = good.clone();
+        image[HEADER_OFFSETS[1] as usize + 100] ^= 1;
+        assert!(read_disk(&mut Cursor::new(image.clone())).is_ok());
+        image[REGION_TABLE_OFFSETS[0] as usize + 100] ^= 1;
+        assert!(read_disk(&mut Cursor::new(image.clone())).is_ok());
+        image[REGION_TABLE_OFFSETS[1] as usize + 100] ^= 1;
+        assert!(read_disk(&mut Cursor::new(image)).unwrap_err().contains("region table"));
+
+        // An active log means the metadata may be stale
+        let mut image = good.clone();
+        for offset in HEADER_OFFSETS {
+            put(&mut image, offset as usize + 48, &DISK_ID.0);
+            seal(&mut image, offset as usize, HEADER_SIZE);
+        }
+        assert!(read_disk(&mut Cursor::new(image)).unwrap_err().contains("log"));
+
+        // Block states are only checked when the BAT is read
+        let mut image = good.clone();
+        put(&mut image, 3 * MB as usize, &5u64.to_le_bytes());
+        let mut image = Cursor::new(image);
+        let info = read_disk(&mut image).unwrap();
+        assert!(read_blocks(&mut image, &info).unwrap_err().contains("invalid state"));
+
+        assert!(read_disk(&mut Cursor::new(vec![0u8; 4096])).is_err());
+    }
+
+    #[test]
+    fn rejects_structures_beyond_the_end_of_the_file() {
+        // A region length from the file is checked before anything is allocated for it
+        let mut image = vhdx_fixture(1024 * MB, 32 * MB as u32, 0, &[], &[]);
+        for offset in REGION_TABLE_OFFSETS {
+            put(&mut image, offset as usize + 16 + 24, &u32::MAX.to_le_bytes());
+            seal(&mut image, offset as usize, REGION_TABLE_SIZE);
+        }
+        assert!(read_disk(&mut Cursor::new(image)).unwrap_err().contains("outside the file"));
+
+        let mut image = vhdx_fixture(1024 * MB, 32 * MB as u32, 0, &[], &[]);
+        image.truncate(3 * MB as usize);
+        assert!(read_disk(&mut Cursor::new(image)).unwrap_err().contains("BAT region lies outside"));
+
+        // A VHD block table or parent path past the end of the file
+        let footer = vhd_footer(3, 64 * 1024 * 1024 * MB, 512);
+        let mut header = vec![0u8; VHD_DYNAMIC_HEADER_SIZE];
+        put(&mut header, 0, b"cxsparse");
+        put(&mut header, 16, &1536u64.to_be_bytes());
+        put(&mut header, 28, &u32::MAX.to_be_bytes());
+        put(&mut header, 32, &(2 * MB as u32).to_be_bytes());
+        let checksum = vhd_checksum(&header, 36);
+        put(&mut header, 36, &checksum.to_be_bytes());
+        let mut image = footer.clone();
+        image.extend_from_slice(&header);
+        image.extend_from_slice(&footer);
+        assert!(read_disk(&mut Cursor::new(image)).unwrap_err().contains("block table lies outside"));
+
+        let mut fixed = vec![0u8; 512];
+        fixed.extend_from_slice(&vhd_footer(3, MB, u64::MAX - 100));
+        assert!(read_disk(&mut Cursor::new(fixed)).unwrap_err().contains("header lies outside"));
+    }
+
+    #[test]
+    fn reads_vhd_footers_and_block_tables() {
+        let mut fixed = vec![0u8; MB as usize];
+        fixed.extend_from_slice(&vhd_footer(2, MB, u64::MAX));
+        let info = read_disk(&mut Cursor::new(fixed)).unwrap();
+        assert_eq!((info.format, info.volume_type, info.virtual_size), (VolumeFormat::Vhd, VolumeType::Fixed, MB));
+        assert_eq!(info.disk_id, DISK_ID);
+        assert!(read_blocks(&mut Cursor::new(Vec::new()), &info).unwrap().is_empty());
+
+        // Differencing VHD: footer copy, dynamic header at 512, BAT at 1536,
+        // parent path at 2048 and one 2 MB block at sector 8
+        let footer = vhd_footer(4, 5 * MB, 512);
+        let mut image = footer.clone();
+        let mut header = vec![0u8; VHD_DYNAMIC_HEADER_SIZE];
+        put(&mut header, 0, b"cxsparse");
+        put(&mut header, 8, &u64::MAX.to_be_bytes());
+        put(&mut header, 16, &1536u64.to_be_bytes());
+        put(&mut header, 28, &3u32.to_be_bytes());
+        put(&mut header, 32, &(2 * MB as u32).to_be_bytes());
+        put(&mut header, 40, &DATA_WRITE.0);
+        let parent = utf16("D:\\base\\win.vhd");
+        put(&mut header, 576, b"W2ku");
+        put(&mut header, 576 + 8, &(parent.len() as u32).to_be_bytes());
+        put(&mut header, 576 + 16, &2048u64.to_be_bytes());
+        let checksum = vhd_checksum(&header, 36);
+        put(&mut header, 36, &checksum.to_be_bytes());
+        image.extend_from_slice(&header);
+        image.extend_from_slice(&[0, 0, 0, 8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        image.resize(2048, 0xff);
+        image.extend_from_slice(&parent);
+        image.resize(8 * 512 + 512 + 2 * MB as usize, 0);
+        image.extend_from_slice(&footer);
+
+        let mut image = Cursor::new(image);
+        let info = read_disk(&mut image).unwrap();
+        assert_eq!(info.volume_type, VolumeType::Differencing);
+        assert_eq!((info.block_size, info.bat_offset), (2 * MB as u32, 1536));
+        assert_eq!(
+            read_blocks(&mut image, &info).unwrap(),
+            vec![BlockState::PartiallyPresent(8 * 512 + 512), BlockState::NotPresent, BlockState::NotPresent]
+        );
+        let locator = info.parent.unwrap();
+        assert_eq!(locator.parent_id, Some(DATA_WRITE));
+        assert_eq!(locator.path(), Some("D:\\base\\win.vhd"));
+    }
+
+    #[test]
+    fn written_disks_read_back() {
+        let mut image = Cursor::new(Vec::new());
+        NewVhdx::dynamic(127 * 1024 * MB).write(&mut image).unwrap();
+        let parent = read_disk(&mut image).unwrap();
+
+        assert_eq!((parent.format, parent.volume_type), (VolumeFormat::Vhdx, VolumeType::Dynamic));
+        assert_eq!(parent.virtual_size, 127 * 1024 * MB);
+        assert_eq!((parent.block_size, parent.logical_sector_size, parent.physical_sector_size), (32 * MB as u32, 512, 4096));
+        let blocks = read_blocks(&mut image, &parent).unwrap();
+        assert_eq!(blocks.len(), 4064);
+        assert!(!blocks.iter().any(|block| block.is_allocated()));
+        assert!(!parent.disk_id.is_nil() && !parent.linkage_id.is_nil());
+
+        let child = NewVhdx::differencing("D:\\vms\\web-1\\os.vhdx", "D:\\base\\ubuntu.vhdx", &parent).unwrap();
+        let mut image = Cursor::new(Vec::new());
+        child.write(&mut image).unwrap();
+        let info = read_disk(&mut image).unwrap();
+
+        assert_eq!(info.volume_type, VolumeType::Differencing);
+        assert_eq!((info.virtual_size, info.block_size), (parent.virtual_size, 2 * MB as u32));
+        assert_eq!(info.disk_id, parent.disk_id);
+        assert!(read_blocks(&mut image, &info).unwrap().iter().all(|block| *block == BlockState::NotPresent));
+        let locator = info.parent.unwrap();
+        assert_eq!(locator.parent_id, Some(parent.linkage_id));
+        assert_eq!(locator.path(), Some("D:\\base\\ubuntu.vhdx"));
+        assert_eq!(locator.relative_path.as_deref(), Some("..\\..\\base\\ubuntu.vhdx"));
+
+        // 1 MB blocks over 1 TB need a BAT of just over 8 MB, rounded up to 9 MB
+        let mut disk = NewVhdx::dynamic(1024 * 1024 * MB);
+        disk.block_size = MB as u32;
+        disk.logical_sector_size = 4096;
+        let mut image = Cursor::new(Vec::new());
+        disk.write(&mut image).unwrap();
+        assert_eq!(image.get_ref().len() as u64, 12 * MB);
+        let info = read_disk(&mut image).unwrap();
+        assert_eq!((read_blocks(&mut image, &info).unwrap().len(), info.logical_sector_size), (1024 * 1024, 4096));
+    }
+
+    // An image from the sparse hex dump format of testdata/windows-differencing-512.hex
+    fn hex_fixture(text: &str) -> Vec<u8> {
+        let mut image = Vec::new();
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let (offset, hex) = line.split_once(": ").unwrap();
+            if offset == "length" {
+                image.resize(usize::from_str_radix(hex, 16).unwrap(), 0);
+                continue;
+            }
+            let bytes: Vec<u8> = (0..hex.len()).step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                .collect();
+            put(&mut image, usize::from_str_radix(offset, 16).unwrap(), &bytes);
+        }
+        image
+    }
+
+    const WINDOWS_CHILD: &str = "C:\\virtdisk-acceptance\\20261007T172747Z\\sector-512\\child.vhdx";
+
+    #[test]
+    fn reads_a_disk_written_by_windows() {
+        let mut reference = Cursor::new(hex_fixture(include_str!("../testdata/windows-differencing-512.hex")));
+        let info = read_disk(&mut reference).unwrap();
+
+        assert_eq!((info.format, info.volume_type), (VolumeFormat::Vhdx, VolumeType::Differencing));
+        assert_eq!(info.virtual_size, 4 * MB);
+        assert_eq!((info.block_size, info.logical_sector_size, info.physical_sector_size), (2 * MB as u32, 512, 4096));
+        assert_eq!(info.disk_id, Guid::parse("6B6D02AA-8947-490F-AA2D-2E81BB5C6B28").unwrap());
+        assert_eq!(info.linkage_id, Guid::parse("926A3799-C792-4F9D-836F-938C81F70272").unwrap());
+        assert_eq!(info.bat_offset, 3 * MB);
+        assert_eq!(read_blocks(&mut reference, &info).unwrap(), [BlockState::PartiallyPresent(5 * MB), BlockState::PartiallyPresent(7 * MB)]);
+
+        let locator = info.parent.unwrap();
+        assert_eq!(locator.parent_id, Some(Guid::parse("0E382594-BDB1-4949-B894-DA2D82312C32").unwrap()));
+        assert_eq!(locator.path(), Some("C:\\virtdisk-acceptance\\20261007T172747Z\\sector-512\\base.vhdx"));
+        assert_eq!(locator.relative_path.as_deref(), Some(".\\base.vhdx"));
+        assert_eq!(
+            locator.volume_path.as_deref(),
+            Some("\\\\?\\Volume{f8ca6cb5-12a2-470c-bfdf-fe35d8c84a63}\\virtdisk-acceptance\\20261007T172747Z\\sector-512\\base.vhdx")
+        );
+    }
+
+    #[test]
+    fn writes_the_windows_layout() {
+        let reference = hex_fixture(include_str!("../testdata/windows-differencing-512.hex"));
+        let native = read_disk(&mut Cursor::new(&reference)).unwrap();
+        let locator = native.parent.clone().unwrap();
+
+        // The same child on top of the same parent, which lends it its disk id
+        let parent = DiskInfo {
+            volume_type: VolumeType::Dynamic,
+            linkage_id: locator.parent_id.unwrap(),
+            parent: None,
+            ..native.clone()
+        };
+        let mut child = NewVhdx::differencing(WINDOWS_CHILD, locator.absolute_path.as_deref().unwrap(), &parent).unwrap();
+        child.parent.as_mut().unwrap().volume_path = locator.volume_path.clone();
+        let mut image = Cursor::new(Vec::new());
+        child.write(&mut image).unwrap();
+        let image = image.into_inner();
+
+        assert_eq!(image.len() as u64, 4 * MB);
+        assert_eq!(&image[0..8], FILE_SIGNATURE);
+        assert_eq!(utf16_le(&image[8..8 + 2 * CREATOR.len()]), CREATOR);
+
+        // Headers match apart from their sequence numbers, checksums and write GUIDs
+        for offset in HEADER_OFFSETS {
+            let (ours, theirs) = (&image[offset as usize..][..HEADER_SIZE], &reference[offset as usize..][..HEADER_SIZE]);
+            assert!(&ours[0..4] == b"head" && crc32c_matches(ours, 4));
+            assert_eq!(&ours[48..], &theirs[48..]);
+        }
+
+        // Region tables are byte for byte the same, and so is the metadata region
+        // but for a stale copy of the last entry Windows leaves past the six in use
+        for offset in REGION_TABLE_OFFSETS {
+            assert_eq!(&image[offset as usize..][..REGION_TABLE_SIZE], &reference[offset as usize..][..REGION_TABLE_SIZE]);
+        }
+        let (metadata, items) = (METADATA_OFFSET as usize, METADATA_OFFSET as usize + METADATA_TABLE_SIZE);
+        assert_eq!(&image[metadata..][..32 + 6 * 32], &reference[metadata..][..32 + 6 * 32]);
+        assert_eq!(&image[items..BAT_OFFSET as usize], &reference[items..BAT_OFFSET as usize]);
+
+        // Nothing in the log or the BAT
+        assert!(image[MB as usize..2 * MB as usize].iter().all(|&b| b == 0));
+        assert!(image[3 * MB as usize..].iter().all(|&b| b == 0));
+
+        // A dynamic disk of the same size has the same regions
+        let mut dynamic = NewVhdx { parent: None, ..child };
+        let mut image = Cursor::new(Vec::new());
+        dynamic.write(&mut image).unwrap();
+        let image = image.into_inner();
+        for offset in REGION_TABLE_OFFSETS {
+            assert_eq!(&image[offset as usize..][..REGION_TABLE_SIZE], &reference[offset as usize..][..REGION_TABLE_SIZE]);
+        }
+
+        // Differencing BATs hold a sector bitmap entry for every chunk, even a partial one
+        dynamic.virtual_size = 5 * 1024 * MB;
+        dynamic.block_size = 32 * MB as u32;
+        assert_eq!(dynamic.bat_entries(), 161);
+        dynamic.parent = Some(ParentLocator {
+            parent_id: Some(DISK_ID),
+            absolute_path: Some("D:\\base.vhdx".to_string()),
+            ..ParentLocator::default()
+        });
+        assert_eq!(dynamic.bat_entries(), 2 * 129);
+        dynamic.parent = Some(ParentLocator::default());
+        assert!(dynamic.write(&mut Cursor::new(Vec::new())).is_err());
+    }
+
+    #[test]
+    fn records_relative_parent_paths() {
+        assert_eq!(relative_win32_path("D:\\vms\\os.vhdx", "D:\\vms\\base.vhdx").as_deref(), Some(".\\base.vhdx"));
+        assert_eq!(relative_win32_path("D:\\vms\\a\\os.vhdx", "d:\\VMS\\base\\b.vhdx").as_deref(), Some("..\\base\\b.vhdx"));
+        assert_eq!(relative_win32_path("D:\\vms\\os.vhdx", "E:\\base.vhdx"), None);
+        assert_eq!(relative_win32_path("os.vhdx", "D:\\base.vhdx"), None);
+        assert!(is_win32_absolute("\\\\server\\share\\base.vhdx"));
+        assert!(!is_win32_absolute("base.vhdx"));
+    }
+
+    #[test]
+    fn creates_new_files_only() {
+        let dir = std::env::temp_dir().join(format!("cpi-vhdx-new-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.vhdx");
+
+        NewVhdx::dynamic(10 * 1024 * MB).create(&path).unwrap();
+        assert_eq!(inspect(&path).unwrap().virtual_size, 10 * 1024 * MB);
+        assert!(NewVhdx::dynamic(MB).create(&path).unwrap_err().contains("Failed to create"));
+        assert!(NewVhdx::dynamic(1000).create(dir.join("odd.vhdx")).is_err());
+        assert!(!dir.join("odd.vhdx").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finds_disk_files() {
+        let dir = std::env::temp_dir().join(format!("cpi-vhdx-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("web-1")).unwrap();
+        for name in ["base.VHDX", "notes.txt", "web-1/os.vhdx", "web-1/os_1F2E.avhdx"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let names: Vec<String> = find_disks(&dir).unwrap().iter()
+            .map(|p| p.strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect();
+        assert_eq!(names, vec!["base.VHDX", "web-1/os.vhdx", "web-1/os_1F2E.avhdx"]);
+
+        // A link back to the top is listed by neither its own name nor its contents
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&dir, dir.join("web-1/loop")).unwrap();
+            assert_eq!(find_disks(&dir).unwrap().len(), 3);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
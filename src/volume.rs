@@ -0,0 +1,380 @@
+// File: cpi_hyperv/src/volume.rs
+//! Virtual disk formats and types, and the `create_volume` settings.
+//!
+//! Hyper-V has few hard limits that `New-VHD` reports clearly, so settings
+//! are checked here against the VHD, VHDX and VHD Set rules before any
+//! PowerShell runs.
+
+use crate::ps_quote;
+use lib_cpi::validation;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Largest VHD, 2040 GB
+const MAX_VHD_MB: i64 = 2040 * 1024;
+/// Largest VHDX, 64 TB
+const MAX_VHDX_MB: i64 = 64 * 1024 * 1024;
+
+/// On-disk format of a virtual disk; New-VHD and Convert-VHD pick it from the file extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VolumeFormat {
+    Vhd,
+    Vhdx,
+    /// VHD Set, a VHDX shared between guest cluster nodes
+    Vhds,
+}
+
+impl VolumeFormat {
+    pub fn parse(format: &str) -> Result<Self, String> {
+        match format.to_lowercase().as_str() {
+            "vhd" => Ok(VolumeFormat::Vhd),
+            "vhdx" => Ok(VolumeFormat::Vhdx),
+            "vhds" => Ok(VolumeFormat::Vhds),
+            other => Err(format!("Unknown disk format '{}' (expected 'vhd', 'vhdx' or 'vhds')", other)),
+        }
+    }
+
+    /// The format implied by a disk path's extension
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        let extension = path.rsplit_once('.')
+            .map(|(_, ext)| ext)
+            .filter(|ext| !ext.contains(['\\', '/']))
+            .ok_or_else(|| format!("Disk path '{}' has no .vhd, .vhdx or .vhds extension", path))?;
+        Self::parse(extension)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            VolumeFormat::Vhd => "vhd",
+            VolumeFormat::Vhdx => "vhdx",
+            VolumeFormat::Vhds => "vhds",
+        }
+    }
+
+    pub fn max_size_mb(&self) -> i64 {
+        match self {
+            VolumeFormat::Vhd => MAX_VHD_MB,
+            VolumeFormat::Vhdx | VolumeFormat::Vhds => MAX_VHDX_MB,
+        }
+    }
+}
+
+/// How a virtual disk allocates its storage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VolumeType {
+    Fixed,
+    Dynamic,
+    /// Changes relative to a parent disk
+    Differencing,
+}
+
+impl VolumeType {
+    pub fn parse(kind: &str) -> Result<Self, String> {
+        match kind.to_lowercase().as_str() {
+            "fixed" => Ok(VolumeType::Fixed),
+            "dynamic" => Ok(VolumeType::Dynamic),
+            "differencing" => Ok(VolumeType::Differencing),
+            other => Err(format!("Unknown disk type '{}' (expected 'fixed', 'dynamic' or 'differencing')", other)),
+        }
+    }
+
+    /// The name Hyper-V uses for the type, as in `Convert-VHD -VHDType`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VolumeType::Fixed => "Fixed",
+            VolumeType::Dynamic => "Dynamic",
+            VolumeType::Differencing => "Differencing",
+        }
+    }
+}
+
+/// Validated settings for `create_volume`
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeSpec {
+    pub path: String,
+    pub format: VolumeFormat,
+    pub volume_type: VolumeType,
+    /// Virtual size; taken from the parent or source disk when absent
+    pub size_mb: Option<i64>,
+    pub parent_path: Option<String>,
+    pub logical_sector_size: Option<i64>,
+    pub physical_sector_size: Option<i64>,
+    pub block_size_mb: Option<i64>,
+    /// Number of a physical disk whose contents are copied into the new disk
+    pub source_disk: Option<i64>,
+}
+
+impl VolumeSpec {
+    pub fn from_params(params: &HashMap<String, Value>) -> Result<Self, String> {
+        let path = validation::extract_string(params, "disk_path")?;
+        let parent_path = validation::extract_string_opt(params, "parent_path")?;
+
+        let path_format = VolumeFormat::from_path(&path)?;
+        let format = match validation::extract_string_opt(params, "format")? {
+            Some(format) => {
+                let format = VolumeFormat::parse(&format)?;
+                if format != path_format {
+                    return Err(format!(
+                        "Disk path '{}' does not match format '{}'; use the .{} extension",
+                        path, format.extension(), format.extension()
+                    ));
+                }
+                format
+            },
+            None => path_format,
+        };
+
+        // A parent implies a differencing disk; otherwise disks are dynamic unless told otherwise
+        let volume_type = match validation::extract_string_opt(params, "type")? {
+            Some(kind) => VolumeType::parse(&kind)?,
+            None if parent_path.is_some() => VolumeType::Differencing,
+            None => VolumeType::Dynamic,
+        };
+
+        let spec = VolumeSpec {
+            path,
+            format,
+            volume_type,
+            size_mb: validation::extract_int_opt(params, "size_mb")?,
+            parent_path,
+            logical_sector_size: validation::extract_int_opt(params, "logical_sector_size")?,
+            physical_sector_size: validation::extract_int_opt(params, "physical_sector_size")?,
+            block_size_mb: validation::extract_int_opt(params, "block_size_mb")?,
+            source_disk: validation::extract_int_opt(params, "source_disk")?,
+        };
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let format = self.format.extension();
+
+        match self.volume_type {
+            VolumeType::Differencing => {
+                let parent = self.parent_path.as_deref()
+                    .ok_or_else(|| "Differencing disks require 'parent_path'".to_string())?;
+                if self.format == VolumeFormat::Vhds {
+                    return Err("VHD Sets cannot be differencing disks".to_string());
+                }
+                if VolumeFormat::from_path(parent)? != self.format {
+                    return Err("A differencing disk must have the same format as its parent".to_string());
+                }
+                if self.source_disk.is_some() {
+                    return Err("'source_disk' cannot be used with differencing disks".to_string());
+                }
+                if self.logical_sector_size.is_some() || self.physical_sector_size.is_some() {
+                    return Err("Differencing disks inherit their sector sizes from the parent".to_string());
+                }
+            },
+            VolumeType::Fixed | VolumeType::Dynamic => {
+                if self.parent_path.is_some() {
+                    return Err("'parent_path' is only valid for differencing disks".to_string());
+                }
+                match (self.size_mb, self.source_disk) {
+                    (Some(_), Some(_)) => return Err("'size_mb' cannot be combined with 'source_disk'; the size comes from the source disk".to_string()),
+                    (None, None) => return Err("'size_mb' is required unless 'source_disk' is given".to_string()),
+                    _ => {},
+                }
+            },
+        }
+
+        if let Some(size_mb) = self.size_mb
+            && (size_mb < 3 || size_mb > self.format.max_size_mb()) {
+            return Err(format!(
+                "size_mb must be between 3 and {} for {} disks, got {}",
+                self.format.max_size_mb(), format, size_mb
+            ));
+        }
+        if let Some(disk) = self.source_disk
+            && disk < 0 {
+            return Err(format!("source_disk must be a disk number, got {}", disk));
+        }
+
+        if let Some(size) = self.logical_sector_size {
+            let allowed: &[i64] = if self.format == VolumeFormat::Vhd { &[512] } else { &[512, 4096] };
+            if !allowed.contains(&size) {
+                return Err(format!("Logical sector size {} is not supported by {} disks", size, format));
+            }
+        }
+        if let Some(size) = self.physical_sector_size
+            && size != 512 && size != 4096 {
+            return Err(format!("Physical sector size must be 512 or 4096, got {}", size));
+        }
+        if let (Some(logical), Some(physical)) = (self.logical_sector_size, self.physical_sector_size)
+            && physical < logical {
+            return Err("The physical sector size cannot be smaller than the logical sector size".to_string());
+        }
+
+        if let Some(block_size_mb) = self.block_size_mb {
+            if self.volume_type == VolumeType::Fixed {
+                return Err("Block size does not apply to fixed size disks".to_string());
+            }
+            // VHD blocks are 2 MB (512 KB is not expressible in MB); VHDX blocks are 1 to 256 MB powers of two
+            let valid = match self.format {
+                VolumeFormat::Vhd => block_size_mb == 2,
+                VolumeFormat::Vhdx | VolumeFormat::Vhds => (1..=256).contains(&block_size_mb) && (block_size_mb as u64).is_power_of_two(),
+            };
+            if !valid {
+                return Err(format!("Block size of {} MB is not supported by {} disks", block_size_mb, format));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The New-VHD invocation creating this disk
+    pub fn new_vhd_command(&self) -> String {
+        let mut command = format!("New-VHD -Path {}", ps_quote(&self.path));
+        match self.volume_type {
+            VolumeType::Fixed => command.push_str(" -Fixed"),
+            VolumeType::Dynamic => command.push_str(" -Dynamic"),
+            VolumeType::Differencing => {
+                command.push_str(" -Differencing");
+                if let Some(parent) = &self.parent_path {
+                    command.push_str(&format!(" -ParentPath {}", ps_quote(parent)));
+                }
+            },
+        }
+        if let Some(size_mb) = self.size_mb {
+            command.push_str(&format!(" -SizeBytes {}MB", size_mb));
+        }
+        if let Some(disk) = self.source_disk {
+            command.push_str(&format!(" -SourceDisk {}", disk));
+        }
+        if let Some(size) = self.logical_sector_size {
+            command.push_str(&format!(" -LogicalSectorSizeBytes {}", size));
+        }
+        if let Some(size) = self.physical_sector_size {
+            command.push_str(&format!(" -PhysicalSectorSizeBytes {}", size));
+        }
+        if let Some(block_size_mb) = self.block_size_mb {
+            command.push_str(&format!(" -BlockSizeBytes {}MB", block_size_mb));
+        }
+        command
+    }
+}
+
+/// Validated settings for `convert_volume`
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeConversion {
+    pub source_path: String,
+    pub destination_path: String,
+    /// Format of the new disk, from the destination extension
+    pub format: VolumeFormat,
+    /// Type of the new disk; the source type is kept when absent
+    pub volume_type: Option<VolumeType>,
+    pub delete_source: bool,
+}
+
+impl VolumeConversion {
+    pub fn new(source_path: String, destination_path: String, volume_type: Option<&str>, delete_source: bool) -> Result<Self, String> {
+        VolumeFormat::from_path(&source_path)?;
+        let format = VolumeFormat::from_path(&destination_path)?;
+        if format == VolumeFormat::Vhds {
+            return Err("Disks cannot be converted to VHD Sets".to_string());
+        }
+        if source_path.eq_ignore_ascii_case(&destination_path) {
+            return Err("The destination must differ from the source".to_string());
+        }
+
+        let volume_type = volume_type.map(VolumeType::parse).transpose()?;
+        if volume_type == Some(VolumeType::Differencing) {
+            return Err("Disks can only be converted to fixed or dynamic; use snapshot_volume for differencing disks".to_string());
+        }
+
+        Ok(VolumeConversion { source_path, destination_path, format, volume_type, delete_source })
+    }
+
+    /// The Convert-VHD invocation performing the conversion
+    pub fn convert_vhd_command(&self) -> String {
+        let mut command = format!(
+            "Convert-VHD -Path {} -DestinationPath {}",
+            ps_quote(&self.source_path), ps_quote(&self.destination_path)
+        );
+        if let Some(volume_type) = self.volume_type {
+            command.push_str(&format!(" -VHDType {}", volume_type.as_str()));
+        }
+        if self.delete_source {
+            command.push_str(" -DeleteSource");
+        }
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn spec(params: Value) -> Result<VolumeSpec, String> {
+        let params: HashMap<String, Value> = serde_json::from_value(params).unwrap();
+        VolumeSpec::from_params(&params)
+    }
+
+    #[test]
+    fn builds_new_vhd_commands() {
+        let dynamic = spec(json!({ "disk_path": "D:\\disks\\data.vhdx", "size_mb": 10240 })).unwrap();
+        assert_eq!(dynamic.new_vhd_command(), "New-VHD -Path 'D:\\disks\\data.vhdx' -Dynamic -SizeBytes 10240MB");
+
+        let fixed = spec(json!({
+            "disk_path": "D:\\disks\\log.vhdx", "type": "Fixed", "size_mb": 4096,
+            "logical_sector_size": 4096, "physical_sector_size": 4096
+        })).unwrap();
+        assert_eq!(
+            fixed.new_vhd_command(),
+            "New-VHD -Path 'D:\\disks\\log.vhdx' -Fixed -SizeBytes 4096MB -LogicalSectorSizeBytes 4096 -PhysicalSectorSizeBytes 4096"
+        );
+
+        let child = spec(json!({ "disk_path": "D:\\w\\child.vhdx", "parent_path": "D:\\base\\o'neil.vhdx", "block_size_mb": 2 })).unwrap();
+        assert_eq!(child.volume_type, VolumeType::Differencing);
+        assert_eq!(
+            child.new_vhd_command(),
+            "New-VHD -Path 'D:\\w\\child.vhdx' -Differencing -ParentPath 'D:\\base\\o''neil.vhdx' -BlockSizeBytes 2MB"
+        );
+
+        let copy = spec(json!({ "disk_path": "D:\\p2v.vhdx", "source_disk": 2 })).unwrap();
+        assert_eq!(copy.new_vhd_command(), "New-VHD -Path 'D:\\p2v.vhdx' -Dynamic -SourceDisk 2");
+    }
+
+    #[test]
+    fn rejects_invalid_combinations() {
+        // Format and extension disagree
+        assert!(spec(json!({ "disk_path": "a.vhd", "format": "vhdx", "size_mb": 10 })).is_err());
+        assert!(spec(json!({ "disk_path": "a.img", "size_mb": 10 })).is_err());
+        // Size limits
+        assert!(spec(json!({ "disk_path": "a.vhd", "size_mb": 3 * 1024 * 1024 })).is_err());
+        assert!(spec(json!({ "disk_path": "a.vhdx", "size_mb": 3 * 1024 * 1024 })).is_ok());
+        assert!(spec(json!({ "disk_path": "a.vhdx" })).is_err());
+        // Sector and block sizes
+        assert!(spec(json!({ "disk_path": "a.vhd", "size_mb": 10, "logical_sector_size": 4096 })).is_err());
+        assert!(spec(json!({ "disk_path": "a.vhdx", "size_mb": 10, "logical_sector_size": 4096, "physical_sector_size": 512 })).is_err());
+        assert!(spec(json!({ "disk_path": "a.vhdx", "size_mb": 10, "block_size_mb": 3 })).is_err());
+        assert!(spec(json!({ "disk_path": "a.vhdx", "type": "fixed", "size_mb": 10, "block_size_mb": 32 })).is_err());
+        // Differencing disks
+        assert!(spec(json!({ "disk_path": "a.vhdx", "type": "differencing" })).is_err());
+        assert!(spec(json!({ "disk_path": "a.vhdx", "parent_path": "b.vhd" })).is_err());
+        assert!(spec(json!({ "disk_path": "a.vhds", "parent_path": "b.vhds" })).is_err());
+        assert!(spec(json!({ "disk_path": "a.vhdx", "type": "dynamic", "size_mb": 10, "parent_path": "b.vhdx" })).is_err());
+        // Source disks
+        assert!(spec(json!({ "disk_path": "a.vhdx", "size_mb": 10, "source_disk": 1 })).is_err());
+    }
+
+    #[test]
+    fn validates_conversions() {
+        let convert = |source: &str, destination: &str, kind: Option<&str>| {
+            VolumeConversion::new(source.to_string(), destination.to_string(), kind, false)
+        };
+
+        let to_vhdx = convert("D:\\old.vhd", "D:\\new.vhdx", Some("dynamic")).unwrap();
+        assert_eq!(to_vhdx.format, VolumeFormat::Vhdx);
+        assert_eq!(to_vhdx.convert_vhd_command(), "Convert-VHD -Path 'D:\\old.vhd' -DestinationPath 'D:\\new.vhdx' -VHDType Dynamic");
+
+        let keep_type = convert("a.vhdx", "b.vhdx", None).unwrap();
+        assert_eq!(keep_type.convert_vhd_command(), "Convert-VHD -Path 'a.vhdx' -DestinationPath 'b.vhdx'");
+
+        assert!(convert("a.vhdx", "A.VHDX", Some("fixed")).is_err());
+        assert!(convert("a.vhdx", "b.vhds", None).is_err());
+        assert!(convert("a.vhdx", "b.vhdx", Some("differencing")).is_err());
+        assert!(convert("a.vhdx", "b.img", None).is_err());
+    }
+}